@@ -1,43 +1,251 @@
 use std::sync::mpsc;
+use std::error::Error;
+use std::time::{Duration, Instant};
 
-use super::midi_message::MidiMessage;
+use super::midi_message::{MidiMessage, MidiEvent, MidiProgramChangeEvent, MidiReaderStatus};
 use super::midi_reader::{MidiReaderCommand, MidiReaderConfigAcceptedPorts};
-use super::synth::SynthKeyboard;
+use super::midi_output::MidiOut;
+use super::synth::{SynthKeyboard, FilterRouting, RepeatedNote, DisconnectBehavior, VelocityTrim};
 use super::synth_voice::SynthInstrument;
-use super::audio_writer::AudioWriter;
+use super::audio_writer::{AudioWriter, RequestedConfig};
+use super::keyboard::{KeyboardState, KeyboardLayout, KeyboardTheme, KeyLabels, KeyColorScheme};
+use super::qwerty::QwertyKeyboard;
+use super::spectrum::SpectrumAnalyzer;
+use super::chorus::ChorusSettings;
+use super::bitcrusher::BitcrusherSettings;
+use super::tuning;
+use super::midi_file;
+
+const SCOPE_REPAINT_TIME: Duration = Duration::from_millis(30);
+const VOICE_MONITOR_REPAINT_TIME: Duration = Duration::from_millis(100);
+const CLIP_HOLD_TIME: Duration = Duration::from_secs(1);
+const CLIP_CHECK_TIME: Duration = Duration::from_millis(250);
+const SONG_REPAINT_TIME: Duration = Duration::from_millis(50);
+// Correlation between the left and right channels below which the mono
+// sum loses a lot of level.
+const PHASE_WARNING_CORRELATION: f32 = -0.3;
+const CLIP_COLOR: egui::Color32 = egui::Color32::from_rgb(255, 32, 32);
+
+const KEYBOARD_MAX_HEIGHT_KEY: &str = "keyboard_max_height";
+const DEMO_SONG: &[u8] = include_bytes!("../assets/demo.mid");
+
+// Instruments in the "Synth" menu, with their shortcut keys.
+const INSTRUMENTS: [(&str, SynthInstrument, egui::Key); 4] = [
+    ("Piano", SynthInstrument::PIANO, egui::Key::Num1),
+    ("Vibraphone", SynthInstrument::VIBRAPHONE, egui::Key::Num2),
+    ("Bell", SynthInstrument::BELL, egui::Key::Num3),
+    ("Organ", SynthInstrument::ORGAN, egui::Key::Num4),
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VolumeControl {
+    Slider,     // full height slider next to the keyboard
+    Compact,    // small numeric control, leaves more room for the keyboard
+    Hidden,
+}
+
+impl VolumeControl {
+    pub const ALL: [VolumeControl; 3] = [VolumeControl::Slider, VolumeControl::Compact, VolumeControl::Hidden];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            VolumeControl::Slider => "Slider",
+            VolumeControl::Compact => "Compact",
+            VolumeControl::Hidden => "Hidden",
+        }
+    }
+}
 
 pub struct KeySynthApp {
-    _audio_writer: AudioWriter, // never used, but must be kept alive
-    midi_write: mpsc::Sender<MidiMessage>,
+    audio_writer: Option<AudioWriter>,  // None when no audio device could be opened
+    audio_config: RequestedConfig,
+    midi_write: mpsc::Sender<MidiEvent>,
     reader_command: Option<mpsc::Sender<MidiReaderCommand>>,
     midi_ports: Option<super::midi_ports::MidiPorts>,
+    midi_out: Option<MidiOut>,
+    out_channel: u8,
+    out_program: u8,        // 1 to 128, as shown on most instruments
     synth: SynthKeyboard,
-    keyboard_state: super::keyboard::KeyboardState,
+    keyboard_state: KeyboardState,
+    qwerty: QwertyKeyboard,
+    release_on_focus_loss: bool,
+    preview_instruments: bool,
+    previewed_instrument: Option<usize>,    // instrument menu entry under the mouse
+    had_focus: bool,
     volume: f32,
+    volume_control: VolumeControl,
+    show_volume_value: bool,
+    muted: bool,
+    velocity_floor: u8,
+    velocity_trim: VelocityTrim,
+    click_smoothing_ms: f32,
+    repeated_note: RepeatedNote,
+    key_range: (u8, u8),
+    disconnect_behavior: DisconnectBehavior,
+    repaint_rate: u32,      // in Hz
+    high_res_controllers: bool,
+    half_pedaling: bool,
+    pitch_bend_range: f32,  // in semitones
+    auto_economy: bool,
+    max_note_duration: Option<f32>,
+    max_duration_sustained: bool,
+    filter_routing: FilterRouting,
+    width: f32,
+    key_spread: f32,
+    force_mono: bool,
+    loudness: bool,
+    chorus: ChorusSettings,
+    bitcrusher: BitcrusherSettings,
+    instrument: SynthInstrument,    // settings of the instrument played by the keys
+    show_instrument_editor: bool,
+    max_voices: usize,
+    show_pitch_bend: bool,
+    show_voice_monitor: bool,
+    show_scope: bool,
+    scope_samples: Vec<i16>,
+    spectrum: Option<SpectrumAnalyzer>,
+    show_tuning_file: bool,
+    tuning_file: String,
+    tuning_error: Option<String>,
+    reference_key: u8,
+    show_recording: bool,
+    recording_file: String,
+    normalize_recording: bool,
+    normalize_peak: f32,    // in dBFS
+    recording_status: Option<String>,
+    show_note_log: bool,
+    note_log_file: String,
+    note_log_status: Option<String>,
+    device_info: Option<String>,    // shown in a window when set
+    device_status: Option<String>,  // result of the last device rescan
+    audio_lost: bool,       // the audio device disappeared, shows a window to restart the audio
+    audio_error: Option<String>,    // the audio output couldn't be started, shows a window to rescan the devices
+    clip_time: Option<Instant>,
 }
 
 impl KeySynthApp {
     pub fn new(cc: &eframe::CreationContext,
-               mut audio_writer: AudioWriter,
-               midi_read: mpsc::Receiver<MidiMessage>,
-               midi_write: mpsc::Sender<MidiMessage>,
+               audio_writer: Result<AudioWriter, Box<dyn Error>>,
+               audio_config: RequestedConfig,
+               block_size: usize,
+               midi_read: mpsc::Receiver<MidiEvent>,
+               midi_write: mpsc::Sender<MidiEvent>,
                reader_command: Option<mpsc::Sender<MidiReaderCommand>>) -> Self {
 
-        let synth = SynthKeyboard::start(midi_read, cc.egui_ctx.clone(), audio_writer.num_channels, audio_writer.sample_rate);
+        // without an audio device the synth runs in the requested format
+        // until a device is found by a rescan
+        let (num_channels, sample_rate) = match &audio_writer {
+            Ok(audio_writer) => (audio_writer.num_channels, audio_writer.sample_rate),
+            Err(_) => (audio_config.num_channels as usize, audio_config.pref_sample_rate as f32),
+        };
+        let synth = SynthKeyboard::start(midi_read, cc.egui_ctx.clone(), num_channels, sample_rate, block_size);
         let volume = synth.get_volume();
-        audio_writer.start(synth.get_player()).unwrap_or(());
+        let muted = synth.is_muted();
+        let velocity_floor = synth.get_velocity_floor();
+        let velocity_trim = synth.get_velocity_trim();
+        let click_smoothing_ms = synth.get_click_smoothing_ms();
+        let repeated_note = synth.get_repeated_note();
+        let key_range = synth.get_key_range();
+        let disconnect_behavior = synth.get_disconnect_behavior();
+        let repaint_rate = (1.0 / synth.get_repaint_interval().as_secs_f32()).round() as u32;
+        let high_res_controllers = synth.is_high_res_controllers();
+        let half_pedaling = synth.is_half_pedaling();
+        let pitch_bend_range = synth.get_pitch_bend_range();
+        let auto_economy = synth.is_auto_economy();
+        let max_note_duration = synth.get_max_note_duration();
+        let max_duration_sustained = synth.is_max_duration_sustained();
+        let filter_routing = synth.get_filter_routing();
+        let instrument = synth.get_instrument();
+        let max_voices = synth.get_max_voices();
+        let width = synth.get_width();
+        let key_spread = synth.get_key_spread();
+        let force_mono = synth.is_force_mono();
+        let loudness = synth.is_loudness();
+        let chorus = synth.get_chorus();
+        let bitcrusher = synth.get_bitcrusher();
+        let (audio_writer, audio_error) = match audio_writer {
+            Ok(mut audio_writer) => {
+                let error = audio_writer.start(synth.get_player(), cc.egui_ctx.clone()).err().map(|e| format!("Audio not available: {}", e));
+                (Some(audio_writer), error)
+            }
+            Err(e) => (None, Some(format!("Error initializing sound: {}", e))),
+        };
+        let device_status = audio_error.clone();
+
+        let mut keyboard_state = KeyboardState::new();
+        keyboard_state.transpose = synth.get_transpose();
+        if let Some(storage) = cc.storage && let Some(max_height) = storage.get_string(KEYBOARD_MAX_HEIGHT_KEY) {
+            keyboard_state.max_height = max_height.parse().unwrap_or(keyboard_state.max_height);
+        }
 
         egui_extras::install_image_loaders(&cc.egui_ctx);
         //cc.egui_ctx.set_theme(egui::ThemePreference::Light);
         cc.egui_ctx.set_zoom_factor(1.5);
         KeySynthApp {
-            _audio_writer: audio_writer,
+            audio_writer,
+            audio_config,
             synth,
             midi_write,
             reader_command,
             midi_ports: super::midi_ports::MidiPorts::open(),
-            keyboard_state: super::keyboard::KeyboardState::new(),
+            midi_out: None,
+            out_channel: 1,
+            out_program: 1,
+            keyboard_state,
+            release_on_focus_loss: true,
+            qwerty: QwertyKeyboard::new(),
+            preview_instruments: true,
+            previewed_instrument: None,
+            had_focus: true,
             volume,
+            volume_control: VolumeControl::Slider,
+            show_volume_value: false,
+            muted,
+            velocity_floor,
+            velocity_trim,
+            click_smoothing_ms,
+            repeated_note,
+            key_range,
+            disconnect_behavior,
+            repaint_rate,
+            high_res_controllers,
+            half_pedaling,
+            pitch_bend_range,
+            auto_economy,
+            max_note_duration,
+            max_duration_sustained,
+            filter_routing,
+            width,
+            key_spread,
+            force_mono,
+            loudness,
+            chorus,
+            bitcrusher,
+            instrument,
+            show_instrument_editor: false,
+            max_voices,
+            show_pitch_bend: true,
+            show_voice_monitor: false,
+            show_scope: false,
+            scope_samples: Vec::new(),
+            spectrum: None,
+            show_tuning_file: false,
+            tuning_file: String::new(),
+            tuning_error: None,
+            reference_key: 69,      // A4
+            show_recording: false,
+            recording_file: String::from("recording.wav"),
+            normalize_recording: false,
+            normalize_peak: -1.0,
+            recording_status: None,
+            show_note_log: false,
+            note_log_file: String::from("notes.csv"),
+            note_log_status: None,
+            device_info: None,
+            device_status,
+            audio_lost: false,
+            audio_error,
+            clip_time: None,
         }
     }
 
@@ -47,6 +255,79 @@ impl KeySynthApp {
         }
     }
 
+    // Stops the MIDI reader, the synth thread and the audio output.
+    pub fn shutdown(&mut self) {
+        self.synth.stop_song();
+        self.close_midi_reader();
+        self.midi_write.send(MidiMessage::Shutdown.into()).unwrap_or(());
+        self.synth.join();
+        if let Some(audio_writer) = &mut self.audio_writer {
+            audio_writer.stop();
+        }
+    }
+
+    // Re-enumerates the MIDI ports and the audio devices, restarting the
+    // audio output on the current default device.
+    pub fn rescan_devices(&mut self, ctx: &egui::Context) {
+        match &mut self.midi_ports {
+            Some(midi_ports) => { midi_ports.refresh(); }
+            None => { self.midi_ports = super::midi_ports::MidiPorts::open(); }
+        }
+
+        if let Some(audio_writer) = &mut self.audio_writer {
+            audio_writer.stop();
+        }
+        self.synth.flush_scheduled();
+        let init_error = match AudioWriter::init(self.audio_config) {
+            Ok(audio_writer) => { self.audio_writer = Some(audio_writer); None }
+            Err(e) => Some(e.to_string()),
+        };
+        let (status, started) = match &mut self.audio_writer {
+            None => (format!("Audio not available: {}", init_error.unwrap_or_default()), false),
+            Some(audio_writer) => {
+                self.synth.set_output_format(audio_writer.num_channels, audio_writer.sample_rate);
+                match (audio_writer.start(self.synth.get_player(), ctx.clone()), init_error) {
+                    (Err(e), _) => (format!("Audio not available: {}", e), false),
+                    (Ok(()), Some(e)) => (format!("Audio rescan failed ({}), still using {}", e, audio_writer.device_name()), true),
+                    (Ok(()), None) => (format!("Audio: {}", audio_writer.device_name()), true),
+                }
+            }
+        };
+        self.audio_error = (! started).then(|| status.clone());
+        self.device_status = Some(status);
+    }
+
+    pub fn play_demo(&mut self) {
+        match midi_file::read_midi_file(DEMO_SONG) {
+            Ok(events) => { self.synth.play_song(events); }
+            Err(e) => { println!("ERROR reading demo song: {}", e); }
+        }
+    }
+
+    pub fn select_instrument(&mut self, instrument: SynthInstrument) {
+        let instrument = self.synth.edited_instrument(instrument);
+        self.synth.set_instrument(instrument);
+        self.instrument = self.synth.get_instrument();
+    }
+
+    // Selects the instrument, discarding the changes made to it in the
+    // editor.
+    fn reset_instrument(&mut self, instrument: SynthInstrument) {
+        self.synth.discard_instrument_edits(instrument.id());
+        self.select_instrument(instrument);
+    }
+
+    // Shows a widget for one setting of the instrument played by the
+    // keys, and changes it in the synth when the widget is used.
+    fn edit_instrument<T: Copy + Send + Sync + 'static>(&mut self, ui: &mut egui::Ui, field: fn(&mut SynthInstrument) -> &mut T,
+                                                  widget: impl FnOnce(&mut egui::Ui, &mut T) -> egui::Response) {
+        let mut value = *field(&mut self.instrument);
+        if widget(ui, &mut value).changed() {
+            *field(&mut self.instrument) = value;
+            self.synth.update_instrument(move |instrument| *field(instrument) = value);
+        }
+    }
+
     pub fn select_midi_in_port(&self, port: String) {
         if let Some(command) = &self.reader_command {
             let cfg = MidiReaderConfigAcceptedPorts { accepted_midi_ports: vec![port] };
@@ -54,30 +335,468 @@ impl KeySynthApp {
         }
     }
 
+    pub fn select_midi_out_port(&mut self, port: String) {
+        self.midi_out = None;
+        match MidiOut::open(&port) {
+            Ok(midi_out) => { self.midi_out = Some(midi_out); }
+            Err(e) => { println!("ERROR opening MIDI output: {}", e); }
+        }
+    }
+
+    pub fn send_program_change(&mut self) {
+        let Some(midi_out) = &mut self.midi_out else { return; };
+        let message = MidiMessage::ProgramChange(self.out_channel, MidiProgramChangeEvent { number: self.out_program - 1 });
+        if let Err(e) = midi_out.send(&message) {
+            println!("ERROR sending program change: {}", e);
+        }
+    }
+
+    // Returns true if the MIDI output goes to the device connected to
+    // the MIDI input, which could send the notes back to the synth.
+    fn is_midi_out_feedback(&self) -> bool {
+        let Some(midi_out) = &self.midi_out else { return false; };
+        match self.synth.get_reader_status() {
+            Some(MidiReaderStatus::Connected(port)) => super::midi_ports::is_same_device(&port, midi_out.port_name()),
+            _ => false,
+        }
+    }
+
+    // Sends the notes played on the on-screen keyboard to the MIDI output.
+    fn forward_keyboard_notes(&mut self) {
+        let notes = self.keyboard_state.take_forwarded_notes();
+        if notes.is_empty() || self.is_midi_out_feedback() { return; }
+        let Some(midi_out) = &mut self.midi_out else { return; };
+        for message in notes {
+            if let Err(e) = midi_out.send(&message) {
+                println!("ERROR sending note: {}", e);
+            }
+        }
+    }
+
+    // Key releases are not seen while the window is not focused, so
+    // release the keys played from the UI when focus is lost.
+    fn check_focus(&mut self, ctx: &egui::Context) {
+        let focused = ctx.input(|i| i.focused);
+        if self.had_focus && ! focused && self.release_on_focus_loss {
+            self.keyboard_state.release_keys(&self.midi_write);
+        }
+        if self.had_focus && ! focused {
+            self.qwerty.release_keys(&self.midi_write);
+        }
+        self.had_focus = focused;
+    }
+
     fn update_menu(&mut self, ctx: &egui::Context) {
         let mut select_midi_in_port = None;
+        let mut select_midi_out_port = None;
+        let mut send_program_change = false;
+        let midi_out_feedback = self.is_midi_out_feedback();
         egui::TopBottomPanel::top("main_menu").show(ctx, |ui| {
             let quit_shortcut = egui::KeyboardShortcut::new(egui::Modifiers::CTRL, egui::Key::Q);
             if ui.input_mut(|i| i.consume_shortcut(&quit_shortcut)) {
+                self.shutdown();
                 ui.ctx().send_viewport_cmd(egui::ViewportCommand::Close);
             }
+            // don't switch instruments while typing in a text field or
+            // when the number keys play notes
+            if ! ui.ctx().wants_keyboard_input() && ! self.qwerty.enabled {
+                for (_, instrument, key) in INSTRUMENTS {
+                    if ui.input_mut(|i| i.consume_key(egui::Modifiers::NONE, key)) {
+                        self.select_instrument(instrument);
+                    }
+                }
+            }
             egui::MenuBar::new().ui(ui, |ui| {
-                ui.menu_button("Synth", |ui| {
-                    if ui.button("Piano").clicked() {
-                        self.synth.set_instrument(SynthInstrument::PIANO);
+                let synth_menu = ui.menu_button("Synth", |ui| {
+                    let mut hovered = None;
+                    for (index, (name, instrument, key)) in INSTRUMENTS.into_iter().enumerate() {
+                        let response = ui.add(egui::Button::new(name).shortcut_text(key.symbol_or_name()));
+                        if response.clicked() {
+                            self.select_instrument(instrument);
+                        }
+                        if response.hovered() {
+                            hovered = Some(index);
+                        }
+                    }
+                    let patch_bank = self.synth.get_patch_bank();
+                    if ! patch_bank.patches().is_empty() {
+                        ui.menu_button("GM patches", |ui| {
+                            for instrument in patch_bank.patches() {
+                                if ui.button(format!("{:3} {}", instrument.program.unwrap_or(0) + 1, instrument.name)).clicked() {
+                                    self.select_instrument(*instrument);
+                                }
+                            }
+                        });
+                    }
+                    // play a note when the mouse moves over an instrument
+                    if self.preview_instruments && let Some(index) = hovered && hovered != self.previewed_instrument {
+                        self.synth.audition(self.synth.edited_instrument(INSTRUMENTS[index].1));
+                    }
+                    self.previewed_instrument = hovered;
+                    ui.checkbox(&mut self.preview_instruments, "Preview on hover");
+                    ui.separator();
+                    ui.label("Envelope");
+                    self.edit_instrument(ui, |i| &mut i.attack, |ui, v| ui.add(egui::Slider::new(v, 0.0..=2.0).text("attack").suffix(" s")));
+                    self.edit_instrument(ui, |i| &mut i.decay_time, |ui, v| ui.add(egui::Slider::new(v, 0.0..=5.0).text("decay").suffix(" s")));
+                    self.edit_instrument(ui, |i| &mut i.sustain_level, |ui, v| ui.add(egui::Slider::new(v, 0.0..=1.0).text("sustain")));
+                    ui.label("Release (seconds)");
+                    self.edit_instrument(ui, |i| &mut i.release, |ui, v| ui.add(egui::Slider::new(v, 0.01..=5.0).logarithmic(true)));
+                    ui.horizontal(|ui| {
+                        ui.label("Transpose");
+                        let mut transpose = self.keyboard_state.transpose;
+                        ui.add(egui::DragValue::new(&mut transpose).range(-24..=24).suffix(" st"));
+                        if self.keyboard_state.transpose != transpose {
+                            self.keyboard_state.transpose = transpose;
+                            self.synth.set_transpose(transpose);
+                        }
+                    });
+                    self.edit_instrument(ui, |i| &mut i.random_phase, |ui, v| ui.checkbox(v, "Random start phase"));
+                    ui.label("Analog drift (cents)");
+                    self.edit_instrument(ui, |i| &mut i.analog_drift, |ui, v| ui.add(egui::Slider::new(v, 0.0..=20.0)));
+                    ui.label("Attack transient");
+                    self.edit_instrument(ui, |i| &mut i.transient_amount, |ui, v| ui.add(egui::Slider::new(v, 0.0..=1.0)));
+                    ui.label("Filter");
+                    self.edit_instrument(ui, |i| &mut i.cutoff, |ui, v| {
+                        ui.add(egui::Slider::new(v, 20.0..=SynthInstrument::MAX_CUTOFF).logarithmic(true).text("cutoff").suffix(" Hz"))
+                    });
+                    self.edit_instrument(ui, |i| &mut i.resonance, |ui, v| {
+                        ui.add(egui::Slider::new(v, SynthInstrument::MIN_RESONANCE..=SynthInstrument::MAX_RESONANCE).logarithmic(true).text("resonance"))
+                    });
+                    ui.label("Vibrato rate (mod wheel sets the depth when not controlling the filter)");
+                    self.edit_instrument(ui, |i| &mut i.lfo_rate, |ui, v| ui.add(egui::Slider::new(v, 0.1..=20.0).logarithmic(true).suffix(" Hz")));
+                    ui.label("Pan");
+                    self.edit_instrument(ui, |i| &mut i.pan, |ui, v| ui.add(egui::Slider::new(v, -1.0..=1.0).custom_formatter(|p, _| {
+                        if p < 0.0 { format!("L{:.0}", -p * 100.0) } else if p > 0.0 { format!("R{:.0}", p * 100.0) } else { "C".to_owned() }
+                    })));
+                    ui.label("Voices of each instrument");
+                    for (name, instrument, _) in INSTRUMENTS {
+                        let mut limit = self.synth.get_voice_limit(instrument.id());
+                        if ui.add(egui::Slider::new(&mut limit, 1..=self.max_voices).text(name)).changed() {
+                            self.synth.set_voice_limit(instrument.id(), limit);
+                        }
+                    }
+                    ui.label("Sympathetic resonance");
+                    self.edit_instrument(ui, |i| &mut i.sympathetic_resonance, |ui, v| ui.add(egui::Slider::new(v, 0.0..=0.1)));
+                    ui.label("Instrument compressor");
+                    self.edit_instrument(ui, |i| &mut i.compressor.threshold, |ui, v| ui.add(egui::Slider::new(v, -40.0..=0.0).text("threshold").suffix(" dB")));
+                    self.edit_instrument(ui, |i| &mut i.compressor.ratio, |ui, v| ui.add(egui::Slider::new(v, 1.0..=10.0).logarithmic(true).text("ratio")));
+                    self.edit_instrument(ui, |i| &mut i.compressor.makeup, |ui, v| ui.add(egui::Slider::new(v, 0.0..=12.0).text("makeup").suffix(" dB")));
+                    ui.label("Click smoothing");
+                    let mut click_smoothing_ms = self.click_smoothing_ms;
+                    ui.add(egui::Slider::new(&mut click_smoothing_ms, 0.0..=20.0).suffix(" ms"));
+                    if self.click_smoothing_ms != click_smoothing_ms {
+                        self.click_smoothing_ms = click_smoothing_ms;
+                        self.synth.set_click_smoothing_ms(self.click_smoothing_ms);
+                    }
+                    ui.label("Velocity floor");
+                    let mut velocity_floor = self.velocity_floor;
+                    ui.add(egui::Slider::new(&mut velocity_floor, 1..=127));
+                    if self.velocity_floor != velocity_floor {
+                        self.velocity_floor = velocity_floor;
+                        self.synth.set_velocity_floor(self.velocity_floor);
+                    }
+                    ui.label("Velocity trim");
+                    let mut velocity_trim = self.velocity_trim;
+                    ui.add(egui::Slider::new(&mut velocity_trim.gain, 0.25..=4.0).logarithmic(true).text("gain"));
+                    ui.add(egui::Slider::new(&mut velocity_trim.offset, -64..=64).text("offset"));
+                    if self.velocity_trim != velocity_trim {
+                        self.velocity_trim = velocity_trim;
+                        self.synth.set_velocity_trim(self.velocity_trim);
+                    }
+                    let (received, played) = self.synth.get_last_velocity().unwrap_or((0, 0));
+                    for (label, velocity) in [("in", received), ("out", played)] {
+                        ui.add(egui::ProgressBar::new(velocity as f32 / 127.0).desired_width(160.0).text(format!("{}: {}", label, velocity)));
                     }
-                    if ui.button("Vibraphone").clicked() {
-                        self.synth.set_instrument(SynthInstrument::VIBRAPHONE);
+                    ui.label("Playable keys");
+                    let max_key = SynthKeyboard::NUM_KEYS as u8 - 1;
+                    let (mut low, mut high) = self.key_range;
+                    ui.add(egui::Slider::new(&mut low, 0..=max_key).text("lowest"));
+                    ui.add(egui::Slider::new(&mut high, 0..=max_key).text("highest"));
+                    if self.key_range != (low, high) {
+                        // keep the other end from crossing the one being dragged
+                        if low != self.key_range.0 { high = high.max(low); } else { low = low.min(high); }
+                        self.key_range = (low, high);
+                        self.synth.set_key_range(low, high);
                     }
-                    if ui.button("Bell").clicked() {
-                        self.synth.set_instrument(SynthInstrument::BELL);
+                    ui.horizontal(|ui| {
+                        ui.label("Voices");
+                        if ui.add(egui::DragValue::new(&mut self.max_voices).range(1..=SynthKeyboard::MAX_VOICES)).changed() {
+                            self.synth.set_max_voices(self.max_voices);
+                        }
+                    });
+                    ui.label("Repeated notes");
+                    for repeated_note in RepeatedNote::ALL {
+                        if ui.radio_value(&mut self.repeated_note, repeated_note, repeated_note.name()).changed() {
+                            self.synth.set_repeated_note(self.repeated_note);
+                        }
+                    }
+                    ui.separator();
+                    if ui.button("All notes off").clicked() {
+                        self.synth.stop_all_keys();
+                    }
+                    if ui.checkbox(&mut self.muted, "Mute synth").changed() {
+                        self.synth.set_muted(self.muted);
+                    }
+                    let mut economy = self.synth.is_economy();
+                    if ui.checkbox(&mut economy, "Economy mode").on_hover_text("Bypass the effects and play fewer voices and overtones").changed() {
+                        self.synth.set_economy(economy);
+                    }
+                    if ui.checkbox(&mut self.auto_economy, "Enable economy mode when the audio can't keep up").changed() {
+                        self.synth.set_auto_economy(self.auto_economy);
+                    }
+                    if ui.checkbox(&mut self.high_res_controllers, "14-bit controllers").changed() {
+                        self.synth.set_high_res_controllers(self.high_res_controllers);
+                    }
+                    if ui.checkbox(&mut self.half_pedaling, "Half-pedaling (continuous sustain)").changed() {
+                        self.synth.set_half_pedaling(self.half_pedaling);
+                    }
+                    ui.horizontal(|ui| {
+                        ui.label("Pitch bend range");
+                        if ui.add(egui::DragValue::new(&mut self.pitch_bend_range).range(0.0..=24.0).speed(0.1).suffix(" semitones")).changed() {
+                            self.synth.set_pitch_bend_range(self.pitch_bend_range);
+                        }
+                    });
+                    let mut max_note_duration = self.max_note_duration;
+                    ui.horizontal(|ui| {
+                        let mut enabled = max_note_duration.is_some();
+                        ui.checkbox(&mut enabled, "Max. note duration");
+                        let mut duration = max_note_duration.unwrap_or(30.0);
+                        ui.add_enabled(enabled, egui::DragValue::new(&mut duration).range(1.0..=600.0).suffix(" s"));
+                        max_note_duration = enabled.then_some(duration);
+                    });
+                    if self.max_note_duration != max_note_duration {
+                        self.max_note_duration = max_note_duration;
+                        self.synth.set_max_note_duration(self.max_note_duration);
+                    }
+                    if ui.add_enabled(self.max_note_duration.is_some(), egui::Checkbox::new(&mut self.max_duration_sustained, "Also release notes held by the pedal")).changed() {
+                        self.synth.set_max_duration_sustained(self.max_duration_sustained);
+                    }
+                    ui.checkbox(&mut self.release_on_focus_loss, "Release keys on focus loss");
+                    if ui.checkbox(&mut self.qwerty.enabled, "Play notes with the computer keyboard")
+                        .on_hover_text("Z-M and Q-P rows, -/+ change the octave").changed() {
+                        self.qwerty.release_keys(&self.midi_write);
+                    }
+                    ui.separator();
+                    ui.label("Filter cutoff control");
+                    let mut filter_routing = self.filter_routing;
+                    ui.horizontal(|ui| {
+                        let mut enabled = filter_routing.control.is_some();
+                        ui.checkbox(&mut enabled, "CC");
+                        let mut control = filter_routing.control.unwrap_or(1);
+                        ui.add_enabled(enabled, egui::DragValue::new(&mut control).range(0..=127));
+                        filter_routing.control = enabled.then_some(control);
+                    });
+                    ui.add(egui::Slider::new(&mut filter_routing.min_cutoff, 20.0..=SynthInstrument::MAX_CUTOFF).logarithmic(true).text("min").suffix(" Hz"));
+                    ui.add(egui::Slider::new(&mut filter_routing.max_cutoff, 20.0..=SynthInstrument::MAX_CUTOFF).logarithmic(true).text("max").suffix(" Hz"));
+                    if self.filter_routing != filter_routing {
+                        self.filter_routing = filter_routing;
+                        self.synth.set_filter_routing(self.filter_routing);
+                    }
+                    ui.separator();
+                    if self.synth.is_song_playing() {
+                        if ui.button("Stop demo").clicked() {
+                            self.synth.stop_song();
+                        }
+                    } else if ui.button("Play demo").clicked() {
+                        self.play_demo();
                     }
                     ui.separator();
                     if ui.button("Quit").clicked() {
-                        self.close_midi_reader();
+                        self.shutdown();
                         ui.ctx().send_viewport_cmd(egui::ViewportCommand::Close);
                     }
                 });
+                if synth_menu.inner.is_none() {
+                    self.previewed_instrument = None;
+                }
+                ui.menu_button("Output", |ui| {
+                    if ui.button("Rescan devices").clicked() {
+                        self.rescan_devices(ui.ctx());
+                    }
+                    if ui.button("Record WAV...").clicked() {
+                        self.show_recording = true;
+                    }
+                    if ui.button("Log notes to CSV...").clicked() {
+                        self.show_note_log = true;
+                    }
+                    if ui.add_enabled(self.audio_writer.is_some(), egui::Button::new("Device info...")).clicked() &&
+                        let Some(audio_writer) = &self.audio_writer {
+                        self.device_info = Some(audio_writer.device_info());
+                    }
+                    ui.separator();
+                    ui.label("Stereo width");
+                    let mut width = self.width;
+                    ui.add(egui::Slider::new(&mut width, 0.0..=1.0).custom_formatter(|w, _| format!("{:.0}%", w * 100.0)));
+                    if self.width != width {
+                        self.width = width;
+                        self.synth.set_width(self.width);
+                    }
+                    ui.label("Key stereo spread");
+                    let mut key_spread = self.key_spread;
+                    ui.add(egui::Slider::new(&mut key_spread, 0.0..=1.0).custom_formatter(|s, _| format!("{:.0}%", s * 100.0)))
+                        .on_hover_text("Pan the low keys to the left and the high keys to the right");
+                    if self.key_spread != key_spread {
+                        self.key_spread = key_spread;
+                        self.synth.set_key_spread(self.key_spread);
+                    }
+                    if ui.checkbox(&mut self.force_mono, "Force mono").changed() {
+                        self.synth.set_force_mono(self.force_mono);
+                    }
+                    if ui.checkbox(&mut self.loudness, "Loudness compensation").on_hover_text("Boost the lows and highs at low volumes").changed() {
+                        self.synth.set_loudness(self.loudness);
+                    }
+                    ui.separator();
+                    ui.label("Notes left by other instruments")
+                        .on_hover_text("Notes keep their instrument when another one is selected; the instrument played by the keys is always heard");
+                    let solo_instrument = self.synth.get_solo_instrument();
+                    for (name, instrument, _) in INSTRUMENTS {
+                        let id = instrument.id();
+                        ui.add_enabled_ui(id != self.instrument.id(), |ui| {
+                            ui.horizontal(|ui| {
+                                let mut muted = self.synth.is_instrument_muted(id);
+                                if ui.toggle_value(&mut muted, "M").on_hover_text("Mute").changed() {
+                                    self.synth.set_instrument_muted(id, muted);
+                                }
+                                let mut solo = solo_instrument == Some(id);
+                                if ui.toggle_value(&mut solo, "S").on_hover_text("Solo (mutes the notes of the other instruments)").changed() {
+                                    self.synth.set_solo_instrument(solo.then_some(id));
+                                }
+                                ui.label(name);
+                            });
+                        });
+                    }
+                    ui.separator();
+                    ui.label("Effects (in order)");
+                    for (index, effect) in self.synth.get_effects().into_iter().enumerate() {
+                        ui.horizontal(|ui| {
+                            let mut enabled = effect.enabled;
+                            if ui.checkbox(&mut enabled, effect.name).changed() {
+                                self.synth.set_effect_enabled(index, enabled);
+                            }
+                            if ui.add_enabled(index > 0, egui::Button::new("Up")).clicked() {
+                                self.synth.move_effect_up(index);
+                            }
+                        });
+                    }
+                    ui.separator();
+                    ui.label("Chorus");
+                    let mut chorus = self.chorus;
+                    ui.add(egui::Slider::new(&mut chorus.rate, 0.1..=5.0).logarithmic(true).text("rate").suffix(" Hz"));
+                    ui.add(egui::Slider::new(&mut chorus.depth, 0.0..=1.0).text("depth"));
+                    ui.add(egui::Slider::new(&mut chorus.mix, 0.0..=1.0).text("mix"));
+                    if self.chorus != chorus {
+                        self.chorus = chorus;
+                        self.synth.set_chorus(self.chorus);
+                    }
+                    ui.separator();
+                    ui.label("Bitcrusher");
+                    let mut bitcrusher = self.bitcrusher;
+                    ui.add(egui::Slider::new(&mut bitcrusher.bits, 1..=BitcrusherSettings::MAX_BITS).text("bits"));
+                    ui.add(egui::Slider::new(&mut bitcrusher.downsample, 1..=BitcrusherSettings::MAX_DOWNSAMPLE).text("downsample"));
+                    if self.bitcrusher != bitcrusher {
+                        self.bitcrusher = bitcrusher;
+                        self.synth.set_bitcrusher(self.bitcrusher);
+                    }
+                });
+                ui.menu_button("Tuning", |ui| {
+                    if ui.button("Equal temperament").clicked() {
+                        self.synth.set_tuning(tuning::EQUAL_TEMPERAMENT);
+                    }
+                    if ui.button("Stretch tuning").clicked() {
+                        self.synth.set_tuning(tuning::stretch_tuning());
+                    }
+                    if ui.button("Load tuning file...").clicked() {
+                        self.show_tuning_file = true;
+                    }
+                    ui.separator();
+                    ui.label("Reference note");
+                    let reference_frequency = self.synth.get_reference_frequency();
+                    ui.horizontal(|ui| {
+                        let max_key = SynthKeyboard::NUM_KEYS as u8 - 1;
+                        let name = |key: f64, _| KeyLabels::PitchNames.key_label(key as usize).unwrap_or_default();
+                        if ui.add(egui::DragValue::new(&mut self.reference_key).range(0..=max_key).custom_formatter(name)).changed()
+                            && reference_frequency.is_some() {
+                            self.synth.start_reference(self.reference_key);
+                        }
+                        if reference_frequency.is_some() {
+                            if ui.button("Stop").clicked() {
+                                self.synth.stop_reference();
+                            }
+                        } else if ui.button("Play").clicked() {
+                            self.synth.start_reference(self.reference_key);
+                        }
+                    });
+                    if let Some(frequency) = reference_frequency {
+                        ui.label(format!("{:.2} Hz", frequency));
+                    }
+                });
+                ui.menu_button("View", |ui| {
+                    ui.checkbox(&mut self.keyboard_state.show_velocity, "Show velocity");
+                    ui.checkbox(&mut self.keyboard_state.c_markers, "Mark each C");
+                    ui.checkbox(&mut self.keyboard_state.position_velocity, "Mouse velocity from position");
+                    ui.add(egui::Slider::new(&mut self.keyboard_state.min_dwell, 0.0..=0.2).text("touch dwell").suffix(" s"))
+                        .on_hover_text("Time the pointer must stay on a key before it plays, to ignore keys brushed while moving");
+                    ui.add_enabled(! self.keyboard_state.position_velocity,
+                                   egui::Slider::new(&mut self.keyboard_state.mouse_velocity, 1..=127).text("mouse velocity"));
+                    ui.checkbox(&mut self.keyboard_state.follow_transpose, "Keyboard follows transpose");
+                    ui.checkbox(&mut self.show_pitch_bend, "Pitch bend meter");
+                    ui.checkbox(&mut self.show_scope, "Oscilloscope");
+                    ui.checkbox(&mut self.show_instrument_editor, "Instrument editor");
+                    ui.checkbox(&mut self.show_voice_monitor, "Voice monitor");
+                    let mut show_spectrum = self.spectrum.is_some();
+                    if ui.checkbox(&mut show_spectrum, "Spectrum").changed() {
+                        self.spectrum = show_spectrum.then(|| SpectrumAnalyzer::start(self.synth.clone(), ui.ctx().clone()));
+                    }
+                    ui.separator();
+                    ui.label("Volume control");
+                    for volume_control in VolumeControl::ALL {
+                        ui.radio_value(&mut self.volume_control, volume_control, volume_control.name());
+                    }
+                    ui.checkbox(&mut self.show_volume_value, "Show volume value");
+                    ui.separator();
+                    ui.label("Max. repaint rate for MIDI input");
+                    let mut repaint_rate = self.repaint_rate;
+                    ui.add(egui::Slider::new(&mut repaint_rate, 10..=120).suffix(" Hz"));
+                    if self.repaint_rate != repaint_rate {
+                        self.repaint_rate = repaint_rate;
+                        self.synth.set_repaint_interval(Duration::from_secs_f32(1.0 / self.repaint_rate as f32));
+                    }
+                    ui.separator();
+                    ui.label("Keyboard layout");
+                    for layout in KeyboardLayout::ALL {
+                        ui.radio_value(&mut self.keyboard_state.layout, layout, layout.name());
+                    }
+                    ui.add_enabled(self.keyboard_state.layout == KeyboardLayout::FitOctaves,
+                                   egui::Slider::new(&mut self.keyboard_state.num_octaves, KeyboardState::NUM_OCTAVES_RANGE).text("octaves"));
+                    ui.horizontal(|ui| {
+                        ui.label("First key");
+                        let mut first_octave = self.keyboard_state.first_key / 12;
+                        ui.add(egui::DragValue::new(&mut first_octave).range(0..=(SynthKeyboard::NUM_KEYS / 12 - 1))
+                               .custom_formatter(|n, _| format!("C{}", n as i32 - 1))
+                               .custom_parser(|s| s.trim().trim_start_matches(['C', 'c']).parse::<f64>().ok().map(|n| n + 1.0)));
+                        self.keyboard_state.first_key = first_octave * 12;
+                    });
+                    ui.label("Maximum keyboard height");
+                    ui.add(egui::Slider::new(&mut self.keyboard_state.max_height, KeyboardState::MAX_HEIGHT_RANGE).suffix(" px"));
+                    ui.label("Released key fade time");
+                    ui.add(egui::Slider::new(&mut self.keyboard_state.fade_time, 0.0..=2.0).suffix(" s"));
+                    ui.checkbox(&mut self.keyboard_state.follow_volume, "Held keys fade with the note volume");
+                    ui.separator();
+                    ui.label("Keyboard theme");
+                    for theme in KeyboardTheme::ALL {
+                        ui.radio_value(&mut self.keyboard_state.theme, theme, theme.name);
+                    }
+                    ui.separator();
+                    ui.label("Pressed key colors");
+                    for colors in KeyColorScheme::ALL {
+                        ui.radio_value(&mut self.keyboard_state.colors, colors, colors.name());
+                    }
+                    ui.separator();
+                    ui.label("Key labels");
+                    for labels in KeyLabels::ALL {
+                        ui.radio_value(&mut self.keyboard_state.labels, labels, labels.name());
+                    }
+                });
                 if self.reader_command.is_some() && let Some(midi_ports) = &mut self.midi_ports {
                     ui.menu_button("Midi In", |ui| {
                         for port in midi_ports.read_port_names() {
@@ -85,6 +804,39 @@ impl KeySynthApp {
                                 select_midi_in_port = Some(port.to_owned());
                             }
                         }
+                        ui.separator();
+                        ui.label("On disconnect");
+                        for disconnect_behavior in DisconnectBehavior::ALL {
+                            if ui.radio_value(&mut self.disconnect_behavior, disconnect_behavior, disconnect_behavior.name()).changed() {
+                                self.synth.set_disconnect_behavior(self.disconnect_behavior);
+                            }
+                        }
+                    });
+                }
+                if let Some(midi_ports) = &mut self.midi_ports {
+                    ui.menu_button("Midi Out", |ui| {
+                        let connected_port = self.midi_out.as_ref().map(|m| m.port_name());
+                        for port in midi_ports.read_output_port_names() {
+                            if ui.selectable_label(connected_port == Some(port.as_str()), port).clicked() {
+                                select_midi_out_port = Some(port.to_owned());
+                            }
+                        }
+                        ui.separator();
+                        ui.label("Program change");
+                        ui.horizontal(|ui| {
+                            ui.label("Channel");
+                            ui.add(egui::DragValue::new(&mut self.out_channel).range(1..=16));
+                            ui.label("Program");
+                            ui.add(egui::DragValue::new(&mut self.out_program).range(1..=128));
+                        });
+                        if ui.add_enabled(self.midi_out.is_some(), egui::Button::new("Send")).clicked() {
+                            send_program_change = true;
+                        }
+                        ui.separator();
+                        ui.checkbox(&mut self.keyboard_state.forward_notes, "Send on-screen keyboard notes");
+                        if self.keyboard_state.forward_notes && midi_out_feedback {
+                            ui.colored_label(ui.visuals().warn_fg_color, "Not sent: the output is the MIDI input device");
+                        }
                     });
                 }
             });
@@ -92,42 +844,394 @@ impl KeySynthApp {
         if let Some(port) = select_midi_in_port {
             self.select_midi_in_port(port);
         }
+        if let Some(port) = select_midi_out_port {
+            self.select_midi_out_port(port);
+        }
+        if send_program_change {
+            self.send_program_change();
+        }
+    }
+
+    fn update_tuning_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_tuning_file;
+        egui::Window::new("Load Tuning File").open(&mut open).collapsible(false).show(ctx, |ui| {
+            ui.label("Each line has a MIDI key number and an offset in cents.");
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut self.tuning_file);
+                if ui.button("Load").clicked() {
+                    match tuning::load_tuning_file(&self.tuning_file) {
+                        Ok(table) => {
+                            self.synth.set_tuning(table);
+                            self.tuning_error = None;
+                            self.show_tuning_file = false;
+                        }
+                        Err(e) => { self.tuning_error = Some(e.to_string()); }
+                    }
+                }
+            });
+            if let Some(error) = &self.tuning_error {
+                ui.colored_label(ui.visuals().error_fg_color, error);
+            }
+        });
+        self.show_tuning_file &= open;
+    }
+
+    fn update_recording_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_recording;
+        egui::Window::new("Record WAV").open(&mut open).collapsible(false).show(ctx, |ui| {
+            let recording = self.synth.is_recording();
+            ui.horizontal(|ui| {
+                ui.add_enabled(! recording, egui::TextEdit::singleline(&mut self.recording_file));
+                if recording {
+                    if ui.button("Stop").clicked() {
+                        self.save_recording();
+                    }
+                } else if ui.button("Record").clicked() {
+                    self.synth.start_recording();
+                    self.recording_status = None;
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.normalize_recording, "Normalize to");
+                ui.add_enabled(self.normalize_recording, egui::DragValue::new(&mut self.normalize_peak).range(-24.0..=0.0).speed(0.1).suffix(" dBFS"));
+            });
+            if let Some(status) = &self.recording_status {
+                ui.label(status);
+            }
+        });
+        self.show_recording &= open;
+    }
+
+    fn save_recording(&mut self) {
+        let Some(mut recording) = self.synth.stop_recording() else { return; };
+        if self.normalize_recording {
+            recording.normalize(self.normalize_peak);
+        }
+        self.recording_status = Some(match recording.save(&self.recording_file) {
+            Ok(()) if recording.truncated => format!("Saved {} (stopped at the WAV size limit)", self.recording_file),
+            Ok(()) if recording.lost_samples => format!("Saved {} (some audio was dropped)", self.recording_file),
+            Ok(()) => format!("Saved {}", self.recording_file),
+            Err(e) => format!("Error saving {}: {}", self.recording_file, e),
+        });
+    }
+
+    fn update_note_log_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_note_log;
+        egui::Window::new("Note Log").open(&mut open).collapsible(false).show(ctx, |ui| {
+            let logging = self.synth.is_note_logging();
+            ui.horizontal(|ui| {
+                ui.add_enabled(! logging, egui::TextEdit::singleline(&mut self.note_log_file));
+                if logging {
+                    if ui.button("Stop").clicked() {
+                        self.note_log_status = Some(match self.synth.stop_note_log() {
+                            Ok(()) => format!("Saved {}", self.note_log_file),
+                            Err(e) => format!("Error writing {}: {}", self.note_log_file, e),
+                        });
+                    }
+                } else if ui.button("Start").clicked() {
+                    self.note_log_status = match self.synth.start_note_log(&self.note_log_file) {
+                        Ok(()) => None,
+                        Err(e) => Some(format!("Error creating {}: {}", self.note_log_file, e)),
+                    };
+                }
+            });
+            ui.label("Each line has the time (in seconds), on/off, key and velocity.");
+            if let Some(status) = &self.note_log_status {
+                ui.label(status);
+            }
+        });
+        self.show_note_log &= open;
+    }
+
+    fn update_voice_monitor_window(&mut self, ctx: &egui::Context) {
+        if ! self.show_voice_monitor { return; }
+        let mut open = true;
+        egui::Window::new("Voice Monitor").open(&mut open).collapsible(false).show(ctx, |ui| {
+            egui::Grid::new("voices").striped(true).show(ui, |ui| {
+                for title in ["Voice", "Instrument", "Key", "Frequency", "Volume", "State"] {
+                    ui.strong(title);
+                }
+                ui.end_row();
+                for (index, voice) in self.synth.get_voice_info().iter().enumerate() {
+                    let state = match (voice.active, voice.stopping, voice.sustained) {
+                        (false, _, _) => "off",
+                        (true, true, _) => "releasing",
+                        (true, false, true) => "sustained",
+                        (true, false, false) => "playing",
+                    };
+                    ui.label(index.to_string());
+                    ui.label(voice.instrument);
+                    ui.label(KeyLabels::PitchNames.key_label(voice.key as usize).unwrap_or_default());
+                    ui.label(format!("{:.2} Hz", voice.freq));
+                    ui.label(format!("{:.3}", voice.volume));
+                    ui.label(state);
+                    ui.end_row();
+                }
+            });
+        });
+        self.show_voice_monitor = open;
+        ctx.request_repaint_after(VOICE_MONITOR_REPAINT_TIME);
+    }
+
+    fn update_audio_lost_window(&mut self, ctx: &egui::Context) {
+        if let Some(audio_writer) = &self.audio_writer && audio_writer.take_device_lost() {
+            self.synth.flush_scheduled();
+            self.audio_lost = true;
+            self.device_status = Some(format!("Audio device lost: {}", audio_writer.device_name()));
+        }
+        if ! self.audio_lost { return; }
+        let mut open = true;
+        let mut restart = false;
+        egui::Window::new("Audio Device Lost").open(&mut open).collapsible(false).resizable(false).show(ctx, |ui| {
+            ui.label("The audio output device is no longer available.");
+            if ui.button("Restart audio on the default device").clicked() {
+                restart = true;
+            }
+        });
+        if restart {
+            self.rescan_devices(ctx);
+        }
+        if restart || ! open {
+            self.audio_lost = false;
+        }
+    }
+
+    fn update_audio_error_window(&mut self, ctx: &egui::Context) {
+        let Some(error) = &self.audio_error else { return; };
+        let mut open = true;
+        let mut rescan = false;
+        egui::Window::new("Audio Not Available").open(&mut open).collapsible(false).show(ctx, |ui| {
+            egui::ScrollArea::vertical().max_height(200.0).show(ui, |ui| {
+                ui.label(error);
+            });
+            ui.label("The synth keeps running without sound until an audio device is found.");
+            if ui.button("Rescan devices").clicked() {
+                rescan = true;
+            }
+        });
+        if rescan {
+            self.rescan_devices(ctx);
+        } else if ! open {
+            self.audio_error = None;
+        }
+    }
+
+    fn update_device_info_window(&mut self, ctx: &egui::Context) {
+        let Some(device_info) = &self.device_info else { return; };
+        let mut open = true;
+        egui::Window::new("Device Info").open(&mut open).collapsible(false).show(ctx, |ui| {
+            if ui.button("Copy").clicked() {
+                ui.ctx().copy_text(device_info.clone());
+            }
+            egui::ScrollArea::both().max_height(300.0).show(ui, |ui| {
+                ui.monospace(device_info);
+            });
+        });
+        if ! open {
+            self.device_info = None;
+        }
     }
 
-    fn update_footer(&self, ctx: &egui::Context) {
+    fn update_footer(&mut self, ctx: &egui::Context) {
+        // the clip indicator stays on for a while after the output clips
+        if self.synth.take_clipped() {
+            self.clip_time = Some(Instant::now());
+        }
+        let clipping = self.clip_time.is_some_and(|t| t.elapsed() < CLIP_HOLD_TIME);
+        ctx.request_repaint_after(CLIP_CHECK_TIME);
+
         egui::TopBottomPanel::bottom("footer").show(ctx, |ui| {
             ui.add_space(2.0);
-            if self.synth.is_midi_connected() {
-                ui.label("MIDI input connected");
-            } else {
-                ui.label("MIDI input not connected");
+            ui.horizontal(|ui| {
+                let reader_status = self.synth.get_reader_status();
+                if self.synth.is_midi_connected() {
+                    match reader_status {
+                        Some(MidiReaderStatus::Connected(port)) => { ui.label(format!("MIDI input connected: {}", port)); }
+                        _ => { ui.label("MIDI input connected"); }
+                    }
+                } else {
+                    match reader_status {
+                        Some(MidiReaderStatus::Searching(ports)) => {
+                            ui.label(format!("Searching for MIDI input ({})…", ports.join(", ")));
+                        }
+                        Some(MidiReaderStatus::Connecting(port)) => {
+                            ui.label(format!("Connecting to {}…", port));
+                        }
+                        Some(MidiReaderStatus::Error(e)) => {
+                            ui.label(format!("MIDI input error: {}", e));
+                        }
+                        Some(MidiReaderStatus::Connected(_)) | None => {
+                            ui.label("MIDI input not connected");
+                        }
+                    }
+                }
+                if let Some(status) = &self.device_status {
+                    ui.separator();
+                    ui.label(status);
+                }
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    if clipping {
+                        ui.colored_label(CLIP_COLOR, "CLIP");
+                    }
+                    if self.muted {
+                        ui.label("MUTED");
+                    }
+                    if self.qwerty.enabled {
+                        ui.label(format!("QWERTY octave {:+}", self.qwerty.octave()));
+                    }
+                    if self.synth.is_sustain_pedal_down() {
+                        ui.label("PEDAL");
+                    }
+                    if self.synth.is_economy() {
+                        ui.colored_label(ui.visuals().warn_fg_color, "ECO")
+                            .on_hover_text("Economy mode: effects bypassed, fewer voices and overtones");
+                    }
+                    let correlation = self.synth.get_correlation();
+                    if correlation < PHASE_WARNING_CORRELATION {
+                        ui.colored_label(ui.visuals().warn_fg_color, "PHASE")
+                            .on_hover_text(format!("L/R correlation is {:.2}: the sound will lose level in mono", correlation));
+                    }
+                    if self.synth.is_recording() {
+                        ui.colored_label(CLIP_COLOR, "REC");
+                    }
+                    let (active_voices, total_voices) = self.synth.get_voice_usage();
+                    ui.label(format!("voices: {}/{}", active_voices, total_voices));
+                    ui.label(format!("{} Hz", self.synth.get_sample_rate()));
+                });
+            });
+        });
+    }
+
+    fn update_scope(&mut self, ctx: &egui::Context) {
+        if ! self.show_scope { return; }
+        egui::SidePanel::right("scope").resizable(true).default_width(300.0).show(ctx, |ui| {
+            self.synth.copy_output(&mut self.scope_samples);
+            super::scope::show_scope(ui, &self.scope_samples);
+        });
+        ctx.request_repaint_after(SCOPE_REPAINT_TIME);
+    }
+
+    fn update_instrument_editor(&mut self, ctx: &egui::Context) {
+        if ! self.show_instrument_editor { return; }
+        egui::SidePanel::left("instrument_editor").resizable(true).default_width(300.0).show(ctx, |ui| {
+            let mut instrument = self.synth.get_instrument();
+            ui.heading(instrument.name);
+            let mut changed = false;
+            egui::Grid::new("overtones").num_columns(3).show(ui, |ui| {
+                ui.label("");
+                ui.label("ratio");
+                ui.label("loudness");
+                ui.end_row();
+                for (index, overtone) in instrument.overtones.iter_mut().enumerate() {
+                    ui.label(format!("{}", index + 1));
+                    changed |= ui.add(egui::Slider::new(&mut overtone.frequency, 0.0..=16.0).logarithmic(true).smallest_positive(0.25)).changed();
+                    changed |= ui.add(egui::Slider::new(&mut overtone.loudness, 0.0..=1.0)).changed();
+                    ui.end_row();
+                }
+            });
+            ui.label("Decay (level kept every 21 ms)");
+            changed |= ui.add(egui::Slider::new(&mut instrument.decay, 0.5..=1.0)).changed();
+            if changed {
+                self.synth.set_edited_instrument(instrument);
             }
+            ui.separator();
+            ui.label("Reset to");
+            ui.horizontal_wrapped(|ui| {
+                for (name, instrument, _) in INSTRUMENTS {
+                    if ui.button(name).clicked() {
+                        self.reset_instrument(instrument);
+                    }
+                }
+            });
+        });
+    }
+
+    fn update_spectrum(&mut self, ctx: &egui::Context) {
+        let Some(spectrum) = &self.spectrum else { return; };
+        egui::SidePanel::right("spectrum").resizable(true).default_width(300.0).show(ctx, |ui| {
+            spectrum.show(ui);
         });
     }
 
     fn update_central_panel(&mut self, ctx: &egui::Context) {
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.horizontal_centered(|ui| {
-                ui.spacing_mut().slider_width = ui.available_height();
                 let mut volume = self.volume;
-                ui.add(egui::Slider::new(&mut volume, 0.0..=1.0).show_value(false).vertical());
+                let percent = |v: f64, _| format!("{:.0}%", v * 100.0);
+                match self.volume_control {
+                    VolumeControl::Slider => {
+                        // leave room for the value below the slider
+                        let value_height = if self.show_volume_value { ui.spacing().interact_size.y } else { 0.0 };
+                        ui.spacing_mut().slider_width = ui.available_height() - value_height;
+                        ui.add(egui::Slider::new(&mut volume, 0.0..=1.0).show_value(self.show_volume_value)
+                               .custom_formatter(percent).vertical());
+                    }
+                    VolumeControl::Compact => {
+                        ui.vertical(|ui| {
+                            ui.label("Vol");
+                            ui.add(egui::DragValue::new(&mut volume).range(0.0..=1.0).speed(0.01)
+                                   .custom_formatter(percent)
+                                   .custom_parser(|s| s.trim().trim_end_matches('%').parse::<f64>().ok().map(|v| v / 100.0)));
+                        });
+                    }
+                    VolumeControl::Hidden => {}
+                }
                 if self.volume != volume {
                     self.volume = volume;
                     self.synth.set_volume(self.volume);
                 }
 
-                let mut keys = [super::synth::SynthKeyState::Off; 88];
+                if self.show_pitch_bend {
+                    super::keyboard::show_pitch_bend(ui, &self.keyboard_state, self.synth.get_pitch_bend());
+                }
+
+                let mut keys = [super::synth::SynthKeyState::Off; SynthKeyboard::NUM_KEYS];
                 self.synth.copy_keys(&mut keys);
-                super::keyboard::show_keyboard(ui, &mut self.keyboard_state, &keys, &self.midi_write);
+                let mut strikes = [None; SynthKeyboard::NUM_KEYS];
+                self.synth.copy_key_strikes(&mut strikes);
+                let mut levels = [0.0; SynthKeyboard::NUM_KEYS];
+                if self.keyboard_state.follow_volume {
+                    self.synth.copy_key_volume_levels(&mut levels);
+                } else {
+                    self.synth.copy_key_levels(&mut levels);
+                }
+                super::keyboard::show_keyboard(ui, &mut self.keyboard_state, &keys, &strikes, &levels, &self.midi_write);
             });
         });
     }
 }
 
 impl eframe::App for KeySynthApp {
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        self.shutdown();
+    }
+
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        storage.set_string(KEYBOARD_MAX_HEIGHT_KEY, self.keyboard_state.max_height.to_string());
+    }
+
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        // the song is played by the audio thread, which can't repaint
+        if self.synth.is_song_playing() {
+            ctx.request_repaint_after(SONG_REPAINT_TIME);
+        }
+        self.check_focus(ctx);
+        self.qwerty.update(ctx, &self.midi_write);
+        // the instrument can also be changed by MIDI program changes and controllers
+        self.instrument = self.synth.get_instrument();
         self.update_menu(ctx);
+        self.update_tuning_window(ctx);
+        self.update_recording_window(ctx);
+        self.update_note_log_window(ctx);
+        self.update_device_info_window(ctx);
+        self.update_audio_lost_window(ctx);
+        self.update_audio_error_window(ctx);
+        self.update_voice_monitor_window(ctx);
         self.update_footer(ctx);
+        self.update_instrument_editor(ctx);
+        self.update_scope(ctx);
+        self.update_spectrum(ctx);
         self.update_central_panel(ctx);
+        self.forward_keyboard_notes();
     }
 }
@@ -1,6 +1,7 @@
 use std::result::Result;
 use std::error::Error;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use super::synth::SynthPlayer;
 
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
@@ -12,6 +13,32 @@ pub struct RequestedConfig {
     pub pref_sample_rate: u32,
     pub buffer_size: u32,
     pub num_channels: u16,
+    pub allow_any_sample_rate: bool,    // use the nearest rate if none is in the min/max range
+    pub sample_formats: &'static [cpal::SampleFormat],  // accepted formats, most preferred first
+}
+
+// Records that the output device disappeared while the stream was
+// running.  The stream keeps running after a device error, so the error
+// is recorded and the app is woken up to restart the audio.
+#[derive(Clone, Default)]
+struct DeviceLost(Arc<AtomicBool>);
+
+impl DeviceLost {
+    fn error_callback(&self, ctx: egui::Context) -> impl FnMut(cpal::StreamError) + Send + 'static {
+        let device_lost = self.0.clone();
+        move |err| {
+            println!("CPAL error: {}", err);
+            if let cpal::StreamError::DeviceNotAvailable = err {
+                device_lost.store(true, Ordering::Relaxed);
+                ctx.request_repaint();
+            }
+        }
+    }
+
+    // Returns true once after the device is lost.
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
 }
 
 #[allow(dead_code)]
@@ -19,7 +46,9 @@ pub struct AudioWriter {
     host: cpal::Host,
     device: cpal::Device,
     config: cpal::StreamConfig,
+    sample_format: cpal::SampleFormat,
     stream: Option<cpal::Stream>,
+    device_lost: DeviceLost,
     pub sample_rate: f32,
     pub num_channels: usize,
 }
@@ -39,12 +68,12 @@ impl AudioWriter {
         }
     }
 
-    fn find_preferred_config(device: &cpal::Device, pref_config: RequestedConfig)
+    fn find_preferred_config(device: &cpal::Device, pref_config: RequestedConfig, sample_format: cpal::SampleFormat)
                              -> Result<Option<cpal::SupportedStreamConfigRange>, Box<dyn Error>> {
         let configs = device.supported_output_configs()?.find(|range| {
             let min_sample_rate = pref_config.min_sample_rate.max(range.min_sample_rate().0);
             let max_sample_rate = pref_config.max_sample_rate.min(range.max_sample_rate().0);
-            if matches!(range.sample_format(), cpal::SampleFormat::I16) &&
+            if range.sample_format() == sample_format &&
                 range.channels() == pref_config.num_channels &&
                 min_sample_rate <= max_sample_rate &&
                 let cpal::SupportedBufferSize::Range{ min: min_buffer_size, max: max_buffer_size } = range.buffer_size() &&
@@ -58,12 +87,12 @@ impl AudioWriter {
         Ok(configs)
     }
 
-    fn find_acceptable_config(device: &cpal::Device, pref_config: RequestedConfig)
+    fn find_acceptable_config(device: &cpal::Device, pref_config: RequestedConfig, sample_format: cpal::SampleFormat)
                               -> Result<Option<cpal::SupportedStreamConfigRange>, Box<dyn Error>> {
         let configs = device.supported_output_configs()?.find(|range| {
             let min_sample_rate = pref_config.min_sample_rate.max(range.min_sample_rate().0);
             let max_sample_rate = pref_config.max_sample_rate.min(range.max_sample_rate().0);
-            if matches!(range.sample_format(), cpal::SampleFormat::I16) &&
+            if range.sample_format() == sample_format &&
                 range.channels() <= 2 &&
                 min_sample_rate <= max_sample_rate &&
                 let cpal::SupportedBufferSize::Range{ min: min_buffer_size, max: max_buffer_size } = range.buffer_size() &&
@@ -77,21 +106,52 @@ impl AudioWriter {
         Ok(configs)
     }
 
+    // Finds a config ignoring the requested sample rate range, choosing
+    // the one with the rate nearest to the preferred rate.
+    fn find_nearest_rate_config(device: &cpal::Device, pref_config: RequestedConfig, sample_format: cpal::SampleFormat)
+                                -> Result<Option<cpal::SupportedStreamConfigRange>, Box<dyn Error>> {
+        let config = device.supported_output_configs()?.filter(|range| {
+            range.sample_format() == sample_format &&
+                range.channels() <= 2 &&
+                matches!(range.buffer_size(), cpal::SupportedBufferSize::Range{ min: min_buffer_size, max: max_buffer_size }
+                         if *min_buffer_size <= pref_config.buffer_size && *max_buffer_size >= pref_config.buffer_size)
+        }).min_by_key(|range| {
+            let rate = pref_config.pref_sample_rate.clamp(range.min_sample_rate().0, range.max_sample_rate().0);
+            rate.abs_diff(pref_config.pref_sample_rate)
+        });
+        Ok(config)
+    }
+
     pub fn init(pref_config: RequestedConfig) -> Result<Self, Box<dyn Error>> {
         let host = cpal::default_host();
         let device = host.default_output_device().ok_or_else(|| {
             std::io::Error::other("can't open audio output device")
         })?;
-        let config_range = match Self::find_preferred_config(&device, pref_config)? {
-            Some(config_range) => Some(config_range),
-            None => Self::find_acceptable_config(&device, pref_config)?,
-        };
+        // each kind of config is searched in all formats, in order of
+        // preference, before the next kind
+        let mut config_range = None;
+        for &sample_format in pref_config.sample_formats {
+            config_range = Self::find_preferred_config(&device, pref_config, sample_format)?;
+            if config_range.is_some() { break; }
+        }
+        for &sample_format in pref_config.sample_formats {
+            if config_range.is_some() { break; }
+            config_range = Self::find_acceptable_config(&device, pref_config, sample_format)?;
+        }
+        let mut sample_rate_range = (pref_config.min_sample_rate, pref_config.max_sample_rate);
+        if config_range.is_none() && pref_config.allow_any_sample_rate {
+            for &sample_format in pref_config.sample_formats {
+                config_range = Self::find_nearest_rate_config(&device, pref_config, sample_format)?;
+                if config_range.is_some() { break; }
+            }
+            sample_rate_range = (0, u32::MAX);
+        }
         let config_range = config_range.ok_or_else(|| {
             std::io::Error::other(format!("no suitable config found.\nSupported configs:\n{}",
                                           Self::read_supported_output_configs(&device)))
         })?;
-        let min_sample_rate = config_range.min_sample_rate().0;
-        let max_sample_rate = config_range.max_sample_rate().0;
+        let min_sample_rate = config_range.min_sample_rate().0.max(sample_rate_range.0);
+        let max_sample_rate = config_range.max_sample_rate().0.min(sample_rate_range.1);
         let sample_rate = pref_config.pref_sample_rate.clamp(min_sample_rate, max_sample_rate);
         let mut config = config_range.try_with_sample_rate(cpal::SampleRate(sample_rate)).ok_or_else(|| {
             std::io::Error::other("sample rate not supported")
@@ -104,26 +164,102 @@ impl AudioWriter {
             host,
             device,
             config,
+            sample_format: config_range.sample_format(),
             sample_rate,
             num_channels,
             stream: None,
+            device_lost: DeviceLost::default(),
         })
     }
 
-    pub fn start(&mut self, player: Arc<Mutex<SynthPlayer>>) -> Result<(), Box<dyn Error>> {
-        let stream = self.device.build_output_stream(
-            &self.config,
-            move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
-                for spl in data.iter_mut() {
-                    *spl = 0;
-                }
-                let mut player = player.lock().unwrap();
-                player.gen_samples(data);
-            },
-            move |err| { println!("CPAL error: {}", err); },
-            None)?;
+    // Starts the audio stream; `ctx` is repainted when the device is lost.
+    pub fn start(&mut self, player: Arc<Mutex<SynthPlayer>>, ctx: egui::Context) -> Result<(), Box<dyn Error>> {
+        self.device_lost.take();
+        let stream = match self.sample_format {
+            cpal::SampleFormat::F32 => {
+                // the synth renders 16-bit samples, which are converted
+                let mut samples = Vec::new();
+                self.device.build_output_stream(
+                    &self.config,
+                    move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                        samples.clear();
+                        samples.resize(data.len(), 0);
+                        let mut player = player.lock().unwrap();
+                        player.gen_samples(&mut samples);
+                        for (out, spl) in data.iter_mut().zip(samples.iter()) {
+                            *out = *spl as f32 / 32768.0;
+                        }
+                    },
+                    self.device_lost.error_callback(ctx),
+                    None)?
+            }
+            _ => {
+                self.device.build_output_stream(
+                    &self.config,
+                    move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
+                        for spl in data.iter_mut() {
+                            *spl = 0;
+                        }
+                        let mut player = player.lock().unwrap();
+                        player.gen_samples(data);
+                    },
+                    self.device_lost.error_callback(ctx),
+                    None)?
+            }
+        };
         stream.play()?;
         self.stream = Some(stream);
         Ok(())
     }
+
+    // Returns a description of the device and the negotiated config,
+    // followed by all the configs supported by the device.
+    pub fn device_info(&self) -> String {
+        let device_name = self.device_name();
+        let buffer_size = match self.config.buffer_size {
+            cpal::BufferSize::Fixed(size) => size.to_string(),
+            cpal::BufferSize::Default => "default".to_owned(),
+        };
+        format!("Host: {}\nDevice: {}\nSample format: {}\nSample rate: {} Hz\nChannels: {}\nBuffer size: {}\n\nSupported configs:\n{}",
+                self.host.id().name(), device_name, self.sample_format, self.config.sample_rate.0,
+                self.config.channels, buffer_size, Self::read_supported_output_configs(&self.device))
+    }
+
+    pub fn device_name(&self) -> String {
+        self.device.name().unwrap_or_else(|e| e.to_string())
+    }
+
+    // Returns true once if the output device disappeared while the
+    // stream was running.
+    pub fn take_device_lost(&self) -> bool {
+        self.device_lost.take()
+    }
+
+    pub fn stop(&mut self) {
+        self.stream = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_error_flags_device_lost_once() {
+        let device_lost = DeviceLost::default();
+        let ctx = egui::Context::default();
+        let repainted = Arc::new(AtomicBool::new(false));
+        let repaint_flag = repainted.clone();
+        ctx.set_request_repaint_callback(move |_| repaint_flag.store(true, Ordering::Relaxed));
+        let mut error_callback = device_lost.error_callback(ctx);
+        error_callback(cpal::StreamError::BackendSpecific {
+            err: cpal::BackendSpecificError { description: "underrun".to_owned() },
+        });
+        assert!(! device_lost.take());
+        assert!(! repainted.load(Ordering::Relaxed));
+        error_callback(cpal::StreamError::DeviceNotAvailable);
+        assert!(repainted.load(Ordering::Relaxed));
+        assert!(device_lost.take());
+        assert!(! device_lost.take());
+    }
 }
@@ -0,0 +1,84 @@
+use std::any::Any;
+
+use super::effect::Effect;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BitcrusherSettings {
+    pub bits: u32,          // bit depth, 1 to 16
+    pub downsample: u32,    // each sample is held for this many frames
+}
+
+impl BitcrusherSettings {
+    pub const MAX_BITS: u32 = 16;
+    pub const MAX_DOWNSAMPLE: u32 = 32;
+
+    pub const DEFAULT: Self = BitcrusherSettings {
+        bits: Self::MAX_BITS,
+        downsample: 1,
+    };
+
+    pub fn is_off(&self) -> bool {
+        self.bits >= Self::MAX_BITS && self.downsample <= 1
+    }
+}
+
+// Reduces the bit depth of the mix and holds each sample for a few
+// frames to lower the effective sample rate.
+pub struct Bitcrusher {
+    pub settings: BitcrusherSettings,
+    held: Vec<f32>,         // last sample taken from each channel
+    hold_count: u32,        // frames left until the next sample is taken
+}
+
+impl Bitcrusher {
+    pub fn new() -> Self {
+        Bitcrusher {
+            settings: BitcrusherSettings::DEFAULT,
+            held: Vec::new(),
+            hold_count: 0,
+        }
+    }
+}
+
+impl Effect for Bitcrusher {
+    fn name(&self) -> &'static str {
+        "Bitcrusher"
+    }
+
+    fn process(&mut self, data: &mut [f32], num_channels: usize, _sample_rate: f32) {
+        if self.settings.is_off() || num_channels == 0 {
+            return;
+        }
+        if self.held.len() != num_channels {
+            self.held = vec![0.0; num_channels];
+            self.hold_count = 0;
+        }
+
+        // the mix goes from -1 to 1, so 1 bit gives 2 levels
+        let bits = self.settings.bits.clamp(1, BitcrusherSettings::MAX_BITS);
+        let step = 2.0 / (1u32 << bits) as f32;
+        let downsample = self.settings.downsample.clamp(1, BitcrusherSettings::MAX_DOWNSAMPLE);
+        for frame in data.chunks_exact_mut(num_channels) {
+            if self.hold_count == 0 {
+                for (held, spl) in self.held.iter_mut().zip(frame.iter()) {
+                    *held = (*spl / step).round() * step;
+                }
+                self.hold_count = downsample;
+            }
+            self.hold_count -= 1;
+            frame.copy_from_slice(&self.held);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.hold_count = 0;
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
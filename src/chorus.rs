@@ -0,0 +1,176 @@
+use std::any::Any;
+use std::f32::consts::TAU;
+
+use super::effect::Effect;
+
+// Center delay of the chorus taps and the maximum delay modulation.
+const BASE_DELAY: f32 = 0.015;
+const MAX_DEPTH: f32 = 0.008;
+const NUM_TAPS: usize = 2;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ChorusSettings {
+    pub rate: f32,      // LFO frequency in Hz
+    pub depth: f32,     // 0 to 1, fraction of MAX_DEPTH
+    pub mix: f32,       // 0 is dry only, 1 is wet only
+}
+
+impl ChorusSettings {
+    pub const DEFAULT: Self = ChorusSettings {
+        rate: 0.8,
+        depth: 0.5,
+        mix: 0.0,
+    };
+}
+
+// A few modulated short delay lines mixed with the dry signal.  Each
+// channel has its LFOs offset from the others to widen the image.
+pub struct Chorus {
+    pub settings: ChorusSettings,
+    sample_rate: f32,
+    num_channels: usize,
+    buffer: Vec<f32>,       // interleaved delay lines for all channels
+    write_pos: usize,
+    lfo_phase: f32,
+    bypassed: bool,
+}
+
+impl Chorus {
+    pub fn new() -> Self {
+        Chorus {
+            settings: ChorusSettings::DEFAULT,
+            sample_rate: 0.0,
+            num_channels: 0,
+            buffer: Vec::new(),
+            write_pos: 0,
+            lfo_phase: 0.0,
+            bypassed: true,
+        }
+    }
+
+    fn setup(&mut self, num_channels: usize, sample_rate: f32) {
+        let len = ((BASE_DELAY + MAX_DEPTH) * sample_rate).ceil() as usize + 2;
+        self.num_channels = num_channels;
+        self.sample_rate = sample_rate;
+        self.buffer = vec![0.0; len * num_channels];
+        self.write_pos = 0;
+    }
+
+    fn read_delayed(&self, channel: usize, delay: f32) -> f32 {
+        // linear interpolation between the two nearest samples
+        let len = self.buffer.len() / self.num_channels;
+        let pos = self.write_pos as f32 + len as f32 - delay;
+        let i0 = pos.floor() as usize % len;
+        let i1 = (i0 + 1) % len;
+        let frac = pos.fract();
+        let s0 = self.buffer[i0 * self.num_channels + channel];
+        let s1 = self.buffer[i1 * self.num_channels + channel];
+        s0 + (s1 - s0) * frac
+    }
+}
+
+impl Effect for Chorus {
+    fn name(&self) -> &'static str {
+        "Chorus"
+    }
+
+    fn process(&mut self, data: &mut [f32], num_channels: usize, sample_rate: f32) {
+        let mix = self.settings.mix.clamp(0.0, 1.0);
+        if mix <= 0.0 || num_channels == 0 {
+            self.bypassed = true;
+            return;
+        }
+        if num_channels != self.num_channels || sample_rate != self.sample_rate {
+            self.setup(num_channels, sample_rate);
+        }
+        if self.bypassed {
+            self.reset();
+            self.bypassed = false;
+        }
+
+        let len = self.buffer.len() / self.num_channels;
+        let lfo_delta = self.settings.rate / self.sample_rate;
+        let depth = self.settings.depth.clamp(0.0, 1.0) * MAX_DEPTH * self.sample_rate;
+        let base_delay = BASE_DELAY * self.sample_rate;
+        for frame in data.chunks_exact_mut(self.num_channels) {
+            for (channel, spl) in frame.iter_mut().enumerate() {
+                self.buffer[self.write_pos * self.num_channels + channel] = *spl;
+                let channel_offset = channel as f32 / (self.num_channels * NUM_TAPS) as f32;
+                let mut wet = 0.0;
+                for tap in 0..NUM_TAPS {
+                    let phase = self.lfo_phase + channel_offset + tap as f32 / NUM_TAPS as f32;
+                    let delay = base_delay + depth * (phase * TAU).sin();
+                    wet += self.read_delayed(channel, delay);
+                }
+                wet /= NUM_TAPS as f32;
+                *spl = *spl * (1.0 - mix) + wet * mix;
+            }
+            self.write_pos = (self.write_pos + 1) % len;
+            self.lfo_phase = (self.lfo_phase + lfo_delta).fract();
+        }
+    }
+
+    fn reset(&mut self) {
+        // don't play what was left in the delay lines
+        self.buffer.fill(0.0);
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48000.0;
+
+    // Runs the chorus over a stereo impulse followed by silence.
+    fn impulse_response(chorus: &mut Chorus, num_frames: usize) -> Vec<f32> {
+        let mut data = vec![0.0; 2 * num_frames];
+        data[0] = 1.0;
+        data[1] = 1.0;
+        chorus.process(&mut data, 2, SAMPLE_RATE);
+        data
+    }
+
+    #[test]
+    fn dry_chorus_leaves_samples_unchanged() {
+        let mut chorus = Chorus::new();
+        let data = impulse_response(&mut chorus, 1000);
+        assert_eq!(data[..2], [1.0, 1.0]);
+        assert!(data[2..].iter().all(|&spl| spl == 0.0));
+    }
+
+    #[test]
+    fn wet_chorus_delays_the_input() {
+        let mut chorus = Chorus::new();
+        chorus.settings = ChorusSettings { rate: 1.0, depth: 0.0, mix: 1.0 };
+        let data = impulse_response(&mut chorus, 1000);
+        let delay = (BASE_DELAY * SAMPLE_RATE) as usize;
+        for (frame, spl) in data.chunks_exact(2).enumerate() {
+            let expected = if frame == delay { 1.0 } else { 0.0 };
+            assert_eq!(spl, [expected, expected], "frame {}", frame);
+        }
+    }
+
+    #[test]
+    fn bypassed_chorus_forgets_its_delay_lines() {
+        let mut chorus = Chorus::new();
+        chorus.settings.mix = 1.0;
+        impulse_response(&mut chorus, 100);
+
+        // the impulse is still in the delay lines when the chorus is bypassed
+        chorus.settings.mix = 0.0;
+        chorus.process(&mut [0.0; 2], 2, SAMPLE_RATE);
+        chorus.settings.mix = 1.0;
+        let mut data = vec![0.0; 2 * 2000];
+        chorus.process(&mut data, 2, SAMPLE_RATE);
+        assert!(data.iter().all(|&spl| spl == 0.0));
+    }
+}
@@ -0,0 +1,149 @@
+use std::result::Result;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+
+use super::midi_message::{MidiMessage, MidiEvent, MidiKeyEvent, MidiControlEvent};
+
+const DEFAULT_VELOCITY: u8 = 100;
+
+// Listens for text commands from other programs on a local TCP port
+// and sends them to the synth like messages from a MIDI device.  Each
+// line is one command, with the numbers in decimal:
+//
+//    on <key> [velocity]       note on (the default velocity is 100)
+//    off <key>                 note off
+//    cc <control> <value>      control change
+//
+// Keys are MIDI note numbers (60 is middle C).  Empty lines and lines
+// starting with `#` are ignored, and invalid lines are reported back
+// to the client.  Notes left on when the client disconnects are turned
+// off.
+pub fn start(port: u16, midi_write: mpsc::Sender<MidiEvent>) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let midi_write = midi_write.clone();
+            std::thread::spawn(move || {
+                handle_client(stream, &midi_write);
+            });
+        }
+    });
+    Ok(())
+}
+
+fn handle_client(stream: TcpStream, midi_write: &mpsc::Sender<MidiEvent>) {
+    let Ok(mut writer) = stream.try_clone() else { return; };
+    let mut held_keys = [false; 128];
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else { break; };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue; }
+        match parse_command(line) {
+            Ok(message) => {
+                match &message {
+                    MidiMessage::NoteOn(_, ev) => held_keys[ev.key as usize] = ev.pressure > 0,
+                    MidiMessage::NoteOff(_, ev) => held_keys[ev.key as usize] = false,
+                    _ => {}
+                }
+                if midi_write.send(message.into()).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                if writeln!(writer, "error: {}", e).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    // release the notes the client left on
+    for key in (0..128).filter(|&key| held_keys[key as usize]) {
+        let message = MidiMessage::NoteOff(1, MidiKeyEvent { key, pressure: 0 });
+        if midi_write.send(message.into()).is_err() {
+            return;
+        }
+    }
+}
+
+fn parse_number(field: Option<&str>, name: &str) -> Result<u8, Box<dyn Error>> {
+    let field = field.ok_or_else(|| format!("missing {}", name))?;
+    let value = field.parse::<u8>().map_err(|e| format!("invalid {}: {}", name, e))?;
+    if value > 127 {
+        return Err(format!("{} out of range: {}", name, value).into());
+    }
+    Ok(value)
+}
+
+fn parse_command(line: &str) -> Result<MidiMessage, Box<dyn Error>> {
+    let mut fields = line.split_whitespace();
+    let command = fields.next().unwrap_or("");
+    let message = match command {
+        "on" => {
+            let key = parse_number(fields.next(), "key")?;
+            let pressure = match fields.next() {
+                Some(field) => parse_number(Some(field), "velocity")?,
+                None => DEFAULT_VELOCITY,
+            };
+            MidiMessage::NoteOn(1, MidiKeyEvent { key, pressure })
+        }
+        "off" => {
+            let key = parse_number(fields.next(), "key")?;
+            MidiMessage::NoteOff(1, MidiKeyEvent { key, pressure: 0 })
+        }
+        "cc" => {
+            let control = parse_number(fields.next(), "control")?;
+            let value = parse_number(fields.next(), "value")?;
+            MidiMessage::ControlChange(1, MidiControlEvent { control, value })
+        }
+        _ => { return Err(format!("unknown command: {}", command).into()); }
+    };
+    if fields.next().is_some() {
+        return Err("too many arguments".into());
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_parsed() {
+        assert!(matches!(parse_command("on 60 90").unwrap(), MidiMessage::NoteOn(1, MidiKeyEvent { key: 60, pressure: 90 })));
+        assert!(matches!(parse_command("on 60").unwrap(), MidiMessage::NoteOn(1, MidiKeyEvent { key: 60, pressure: DEFAULT_VELOCITY })));
+        assert!(matches!(parse_command("off  61").unwrap(), MidiMessage::NoteOff(1, MidiKeyEvent { key: 61, .. })));
+        assert!(matches!(parse_command("cc 64 127").unwrap(), MidiMessage::ControlChange(1, MidiControlEvent { control: 64, value: 127 })));
+    }
+
+    #[test]
+    fn invalid_commands_are_rejected() {
+        let error = |line: &str| parse_command(line).unwrap_err().to_string();
+        assert!(error("play 60").contains("unknown command"));
+        assert!(error("on").contains("missing key"));
+        assert!(error("on x").contains("invalid key"));
+        assert!(error("on 128").contains("out of range"));
+        assert!(error("on 60 300").contains("invalid velocity"));
+        assert!(error("cc 1").contains("missing value"));
+        assert!(error("off 60 0").contains("too many arguments"));
+    }
+
+    #[test]
+    fn held_notes_are_released_on_disconnect() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        writeln!(client, "on 60\non 62\non 64\noff 62\non 65 0").unwrap();
+        drop(client);
+
+        let (midi_write, midi_read) = mpsc::channel();
+        handle_client(stream, &midi_write);
+        drop(midi_write);
+        let messages = midi_read.iter().map(|event: MidiEvent| event.message).collect::<Vec<_>>();
+        assert_eq!(messages.len(), 7);
+        assert!(matches!(messages[5], MidiMessage::NoteOff(1, MidiKeyEvent { key: 60, .. })));
+        assert!(matches!(messages[6], MidiMessage::NoteOff(1, MidiKeyEvent { key: 64, .. })));
+    }
+}
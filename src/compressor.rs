@@ -0,0 +1,98 @@
+// Envelope follower times, in seconds.
+const ATTACK_TIME: f32 = 0.005;
+const RELEASE_TIME: f32 = 0.1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CompressorSettings {
+    pub threshold: f32,     // in dBFS
+    pub ratio: f32,         // 1 disables the compressor
+    pub makeup: f32,        // gain in dB applied after compression
+}
+
+impl CompressorSettings {
+    pub const OFF: Self = CompressorSettings {
+        threshold: 0.0,
+        ratio: 1.0,
+        makeup: 0.0,
+    };
+
+    pub fn is_off(&self) -> bool {
+        self.ratio <= 1.0 && self.makeup == 0.0
+    }
+}
+
+// A simple peak compressor.  The same gain is applied to all channels.
+pub struct Compressor {
+    envelope: f32,
+}
+
+impl Compressor {
+    pub fn new() -> Self {
+        Compressor {
+            envelope: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, data: &mut [f32], num_channels: usize, sample_rate: f32, settings: &CompressorSettings) {
+        if settings.is_off() || num_channels == 0 { return; }
+
+        let attack = 1.0 - (-1.0 / (ATTACK_TIME * sample_rate)).exp();
+        let release = 1.0 - (-1.0 / (RELEASE_TIME * sample_rate)).exp();
+        let ratio = settings.ratio.max(1.0);
+        for frame in data.chunks_exact_mut(num_channels) {
+            let peak = frame.iter().fold(0.0_f32, |peak, spl| peak.max(spl.abs()));
+            let coef = if peak > self.envelope { attack } else { release };
+            self.envelope += (peak - self.envelope) * coef;
+
+            let level = 20.0 * self.envelope.max(1e-6).log10();
+            let reduction = if level > settings.threshold {
+                (level - settings.threshold) * (1.0 - 1.0 / ratio)
+            } else {
+                0.0
+            };
+            let gain = 10.0_f32.powf((settings.makeup - reduction) / 20.0);
+            for spl in frame.iter_mut() {
+                *spl *= gain;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48000.0;
+
+    // Compresses one second of a constant stereo level and returns the
+    // output level at the end.
+    fn compressed_level(level: f32, settings: &CompressorSettings) -> f32 {
+        let mut data = vec![level; 2 * SAMPLE_RATE as usize];
+        Compressor::new().process(&mut data, 2, SAMPLE_RATE, settings);
+        let last = &data[data.len() - 2..];
+        assert_eq!(last[0], last[1]);
+        last[0]
+    }
+
+    fn db(level: f32) -> f32 {
+        20.0 * level.log10()
+    }
+
+    #[test]
+    fn off_compressor_leaves_samples_unchanged() {
+        assert_eq!(compressed_level(0.9, &CompressorSettings::OFF), 0.9);
+    }
+
+    #[test]
+    fn levels_above_threshold_are_reduced_by_the_ratio() {
+        let settings = CompressorSettings { threshold: -20.0, ratio: 4.0, makeup: 0.0 };
+        // 0.5 is about 14 dB above the threshold, which is reduced to 3.5 dB
+        let out = compressed_level(0.5, &settings);
+        assert!((db(out) - (-20.0 + (db(0.5) + 20.0) / 4.0)).abs() < 0.1, "{} dB", db(out));
+
+        // below the threshold only the makeup gain is applied
+        let settings = CompressorSettings { makeup: 6.0, ..settings };
+        let out = compressed_level(0.01, &settings);
+        assert!((db(out) - (db(0.01) + 6.0)).abs() < 0.1, "{} dB", db(out));
+    }
+}
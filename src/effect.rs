@@ -0,0 +1,158 @@
+use std::any::Any;
+
+// An effect applied to the master output.  The data is the interleaved
+// (unclamped) mix.
+pub trait Effect: Send {
+    fn name(&self) -> &'static str;
+    fn process(&mut self, data: &mut [f32], num_channels: usize, sample_rate: f32);
+
+    // Clears the internal state (e.g. delay lines) when the effect is
+    // enabled again, so it doesn't play what was left from before.
+    fn reset(&mut self) {}
+
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+struct EffectSlot {
+    effect: Box<dyn Effect>,
+    enabled: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct EffectInfo {
+    pub name: &'static str,
+    pub enabled: bool,
+}
+
+// Effects applied in order to the master output, each one with its own
+// bypass.
+pub struct EffectChain {
+    slots: Vec<EffectSlot>,
+}
+
+impl EffectChain {
+    pub fn new() -> Self {
+        EffectChain {
+            slots: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, effect: Box<dyn Effect>, enabled: bool) {
+        self.slots.push(EffectSlot { effect, enabled });
+    }
+
+    pub fn process(&mut self, data: &mut [f32], num_channels: usize, sample_rate: f32) {
+        for slot in self.slots.iter_mut().filter(|slot| slot.enabled) {
+            slot.effect.process(data, num_channels, sample_rate);
+        }
+    }
+
+    pub fn info(&self) -> Vec<EffectInfo> {
+        self.slots.iter().map(|slot| EffectInfo { name: slot.effect.name(), enabled: slot.enabled }).collect()
+    }
+
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) {
+        let Some(slot) = self.slots.get_mut(index) else { return; };
+        if enabled && ! slot.enabled {
+            slot.effect.reset();
+        }
+        slot.enabled = enabled;
+    }
+
+    // Moves the effect one position earlier in the chain.
+    pub fn move_up(&mut self, index: usize) {
+        if index > 0 && index < self.slots.len() {
+            self.slots.swap(index - 1, index);
+        }
+    }
+
+    pub fn get<T: Effect + 'static>(&self) -> Option<&T> {
+        self.slots.iter().find_map(|slot| slot.effect.as_any().downcast_ref::<T>())
+    }
+
+    pub fn get_mut<T: Effect + 'static>(&mut self) -> Option<&mut T> {
+        self.slots.iter_mut().find_map(|slot| slot.effect.as_any_mut().downcast_mut::<T>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Scales and offsets every sample, counting the resets.
+    struct TestEffect {
+        gain: f32,
+        offset: f32,
+        resets: usize,
+    }
+
+    impl TestEffect {
+        fn new(gain: f32, offset: f32) -> Box<Self> {
+            Box::new(TestEffect { gain, offset, resets: 0 })
+        }
+    }
+
+    impl Effect for TestEffect {
+        fn name(&self) -> &'static str {
+            "Test"
+        }
+
+        fn process(&mut self, data: &mut [f32], _num_channels: usize, _sample_rate: f32) {
+            for spl in data.iter_mut() {
+                *spl = *spl * self.gain + self.offset;
+            }
+        }
+
+        fn reset(&mut self) {
+            self.resets += 1;
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    fn process(chain: &mut EffectChain, value: f32) -> f32 {
+        let mut data = [value; 2];
+        chain.process(&mut data, 2, 48000.0);
+        data[0]
+    }
+
+    #[test]
+    fn effects_are_applied_in_order() {
+        let mut chain = EffectChain::new();
+        chain.add(TestEffect::new(1.0, 1.0), true);
+        chain.add(TestEffect::new(2.0, 0.0), true);
+        assert_eq!(process(&mut chain, 1.0), 4.0);
+        chain.move_up(1);
+        assert_eq!(process(&mut chain, 1.0), 3.0);
+        assert_eq!(chain.get::<TestEffect>().unwrap().gain, 2.0);
+
+        // moving the first effect (or one past the end) does nothing
+        chain.move_up(0);
+        chain.move_up(2);
+        assert_eq!(process(&mut chain, 1.0), 3.0);
+    }
+
+    #[test]
+    fn bypassed_effects_are_reset_when_enabled() {
+        let mut chain = EffectChain::new();
+        chain.add(TestEffect::new(2.0, 0.0), true);
+        chain.add(TestEffect::new(1.0, 1.0), false);
+        assert_eq!(process(&mut chain, 1.0), 2.0);
+        assert!(chain.info().iter().map(|info| info.enabled).eq([true, false]));
+
+        chain.set_enabled(0, false);
+        assert_eq!(process(&mut chain, 1.0), 1.0);
+        chain.set_enabled(0, true);
+        chain.set_enabled(0, true);
+        assert_eq!(chain.get::<TestEffect>().unwrap().resets, 1);
+        chain.set_enabled(5, true);
+        assert_eq!(process(&mut chain, 1.0), 2.0);
+    }
+}
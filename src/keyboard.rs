@@ -1,14 +1,141 @@
 use std::sync::mpsc;
+use std::time::{Duration, Instant};
 use egui::{Rect, Pos2, Vec2, Color32};
 
-use super::midi_message::{MidiMessage, MidiKeyEvent};
-use super::synth::SynthKeyState;
+use super::midi_message::{MidiMessage, MidiEvent, MidiKeyEvent};
+use super::synth::{SynthKeyState, SynthKeyStrike};
 
-const BORDER_SIZE: f32 = 4.0;
-const BORDER_COLOR: Color32 = Color32::BLACK;
-const TOP_BORDER_COLOR: Color32 = Color32::from_rgb(96,0,0);
-const PRESSED_KEY_COLOR: Color32 = Color32::from_rgb(64, 128, 255);
-const STOLEN_KEY_COLOR: Color32 = Color32::from_rgb(255, 128, 64);
+const VELOCITY_FADE_TIME: Duration = Duration::from_millis(1500);
+const ANIMATION_REPAINT_TIME: Duration = Duration::from_millis(30);
+const MOUSE_VELOCITY: u8 = 64;
+const MOUSE_MIN_VELOCITY: f32 = 16.0;
+
+const PITCH_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+const SOLFEGE_NAMES: [&str; 12] = ["Do", "Do#", "Re", "Re#", "Mi", "Fa", "Fa#", "Sol", "Sol#", "La", "La#", "Si"];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct KeyboardTheme {
+    pub name: &'static str,
+    pub border_size: f32,
+    pub corner_radius: u8,
+    pub border_color: Color32,
+    pub top_border_color: Color32,
+    pub white_key_color: Color32,
+    pub black_key_color: Color32,
+    pub key_line_color: Color32,
+    pub pressed_key_color: Color32,     // used by the single color scheme
+    pub stolen_key_color: Color32,
+    pub label_color: Color32,
+    pub black_key_label_color: Color32,
+}
+
+impl KeyboardTheme {
+    pub const CLASSIC: Self = KeyboardTheme {
+        name: "Classic",
+        border_size: 4.0,
+        corner_radius: 0,
+        border_color: Color32::BLACK,
+        top_border_color: Color32::from_rgb(96, 0, 0),
+        white_key_color: Color32::WHITE,
+        black_key_color: Color32::BLACK,
+        key_line_color: Color32::BLACK,
+        pressed_key_color: Color32::from_rgb(64, 128, 255),
+        stolen_key_color: Color32::from_rgb(255, 128, 64),
+        label_color: Color32::from_gray(96),
+        black_key_label_color: Color32::from_gray(192),
+    };
+    pub const DARK: Self = KeyboardTheme {
+        name: "Dark",
+        border_size: 6.0,
+        corner_radius: 3,
+        border_color: Color32::from_gray(16),
+        top_border_color: Color32::from_gray(48),
+        white_key_color: Color32::from_gray(72),
+        black_key_color: Color32::from_gray(8),
+        key_line_color: Color32::from_gray(16),
+        pressed_key_color: Color32::from_rgb(48, 96, 200),
+        stolen_key_color: Color32::from_rgb(200, 96, 48),
+        label_color: Color32::from_gray(160),
+        black_key_label_color: Color32::from_gray(128),
+    };
+    pub const ALL: [KeyboardTheme; 2] = [KeyboardTheme::CLASSIC, KeyboardTheme::DARK];
+
+    fn key_corner_radius(&self) -> egui::CornerRadius {
+        egui::CornerRadius { nw: 0, ne: 0, sw: self.corner_radius, se: self.corner_radius }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeyLabels {
+    None,
+    PitchNames,     // scientific pitch notation: C4 is MIDI note 60
+    Solfege,        // fixed Do: Do is always C
+}
+
+impl KeyLabels {
+    pub const ALL: [KeyLabels; 3] = [KeyLabels::None, KeyLabels::PitchNames, KeyLabels::Solfege];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyLabels::None => "None",
+            KeyLabels::PitchNames => "Note names (C4)",
+            KeyLabels::Solfege => "Solfège (Do)",
+        }
+    }
+
+    pub fn key_label(self, key: usize) -> Option<String> {
+        match self {
+            KeyLabels::None => None,
+            KeyLabels::PitchNames => Some(format!("{}{}", PITCH_NAMES[key % 12], (key / 12) as i32 - 1)),
+            KeyLabels::Solfege => Some(SOLFEGE_NAMES[key % 12].to_owned()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeyColorScheme {
+    Single,
+    PitchClass,     // chromatic rainbow: each note of the octave has its own color
+    Octave,         // each octave has its own color
+}
+
+impl KeyColorScheme {
+    pub const ALL: [KeyColorScheme; 3] = [KeyColorScheme::Single, KeyColorScheme::PitchClass, KeyColorScheme::Octave];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyColorScheme::Single => "Single color",
+            KeyColorScheme::PitchClass => "By note",
+            KeyColorScheme::Octave => "By octave",
+        }
+    }
+
+    fn pressed_key_color(self, key: usize, theme: &KeyboardTheme) -> Color32 {
+        let hue = match self {
+            KeyColorScheme::Single => { return theme.pressed_key_color; }
+            KeyColorScheme::PitchClass => (key % 12) as f32 / 12.0,
+            KeyColorScheme::Octave => (key / 12 % 8) as f32 / 8.0,
+        };
+        egui::ecolor::Hsva::new(hue, 0.75, 1.0, 1.0).into()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeyboardLayout {
+    FillWidth,      // the key size follows the height, showing as many octaves as fit
+    FitOctaves,     // the key size follows the width, showing `num_octaves` octaves
+}
+
+impl KeyboardLayout {
+    pub const ALL: [KeyboardLayout; 2] = [KeyboardLayout::FillWidth, KeyboardLayout::FitOctaves];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyboardLayout::FillWidth => "Fill window width",
+            KeyboardLayout::FitOctaves => "Fit octaves",
+        }
+    }
+}
 
 struct KeyCollision {
     key: usize,
@@ -19,29 +146,200 @@ struct KeyCollision {
 pub struct KeyboardState {
     collision: Vec<KeyCollision>,
     pressing_key: Option<usize>,
+    touched_key: Option<(usize, Instant)>,  // key under the pointer waiting for the dwell time
+    release_times: Vec<Option<Instant>>,    // when each (sounding) key was released
+    pub show_velocity: bool,
+    pub c_markers: bool,            // mark the start of each octave with a line and its name
+    pub transpose: i32,             // transpose of the synth, in semitones
+    pub follow_transpose: bool,     // show the keys that sound instead of the keys played
+    pub position_velocity: bool,    // mouse velocity depends on where the key is clicked
+    pub mouse_velocity: u8,         // velocity of mouse clicks without position velocity
+    pub forward_notes: bool,        // keep the notes played for the app to send to MIDI out
+    forwarded_notes: Vec<MidiMessage>,
+    pub labels: KeyLabels,
+    pub colors: KeyColorScheme,
+    pub theme: KeyboardTheme,
+    pub max_height: f32,
+    pub fade_time: f32,     // seconds for the highlight of released keys to fade out
+    pub min_dwell: f32,     // seconds the pointer must stay on a key before it plays
+    pub follow_volume: bool,    // dim the highlight of held keys as their notes decay
+    pub layout: KeyboardLayout,
+    pub num_octaves: usize,
+    pub first_key: usize,   // leftmost key shown (always a C), changed by scrolling
+    scroll: f32,            // scroll accumulated towards the next octave
 }
 
 impl KeyboardState {
+    pub const DEFAULT_MAX_HEIGHT: f32 = 400.0;
+    pub const MAX_HEIGHT_RANGE: std::ops::RangeInclusive<f32> = 100.0..=1000.0;
+    pub const DEFAULT_FIRST_KEY: usize = 36;
+    pub const NUM_OCTAVES_RANGE: std::ops::RangeInclusive<usize> = 1..=7;
+
     pub fn new() -> Self {
         KeyboardState {
             collision: Vec::new(),
             pressing_key: None,
+            touched_key: None,
+            release_times: Vec::new(),
+            show_velocity: false,
+            c_markers: false,
+            transpose: 0,
+            follow_transpose: false,
+            position_velocity: false,
+            mouse_velocity: MOUSE_VELOCITY,
+            forward_notes: false,
+            forwarded_notes: Vec::new(),
+            labels: KeyLabels::None,
+            colors: KeyColorScheme::Single,
+            theme: KeyboardTheme::CLASSIC,
+            max_height: KeyboardState::DEFAULT_MAX_HEIGHT,
+            fade_time: 0.0,
+            min_dwell: 0.0,
+            follow_volume: false,
+            layout: KeyboardLayout::FillWidth,
+            num_octaves: 4,
+            first_key: KeyboardState::DEFAULT_FIRST_KEY,
+            scroll: 0.0,
+        }
+    }
+
+    // Releases the notes played from the UI (but not the ones coming
+    // from MIDI devices).
+    pub fn release_keys(&mut self, midi_write: &mpsc::Sender<MidiEvent>) {
+        if let Some(pressing_key) = self.pressing_key.take() {
+            self.send_note_event(midi_write, pressing_key, 0);
+        }
+    }
+
+    // Returns the key that sounds when the given drawn key is played.
+    // The synth transposes the keys it receives and stores the key
+    // states by the key that sounds.  When the keyboard follows the
+    // transpose, each drawn key is the key that sounds; otherwise it's
+    // the key played, and it lights up when its transposed key sounds.
+    fn sounding_key(&self, key: usize) -> Option<usize> {
+        if self.follow_transpose {
+            Some(key)
+        } else {
+            key.checked_add_signed(self.transpose as isize)
+        }
+    }
+
+    // Returns the MIDI key that should be sent for the given drawn key.
+    fn midi_key(&self, key: usize) -> Option<u8> {
+        let key = if self.follow_transpose { key.checked_add_signed(-self.transpose as isize)? } else { key };
+        u8::try_from(key).ok().filter(|&key| key < 128)
+    }
+
+    fn send_note_event(&mut self, midi_write: &mpsc::Sender<MidiEvent>, key: usize, pressure: u8) {
+        let Some(key) = self.midi_key(key) else { return; };
+        let message = if pressure == 0 {
+            MidiMessage::NoteOff(1, MidiKeyEvent { key, pressure: 0 })
+        } else {
+            MidiMessage::NoteOn(1, MidiKeyEvent { key, pressure })
+        };
+        if self.forward_notes {
+            self.forwarded_notes.push(message.clone());
+        }
+        midi_write.send(message.into()).unwrap_or(());
+    }
+
+    // Returns the notes played since the last call, to be sent to the
+    // MIDI output.
+    pub fn take_forwarded_notes(&mut self) -> Vec<MidiMessage> {
+        std::mem::take(&mut self.forwarded_notes)
+    }
+}
+
+// Checks if the pointer stayed on the key for the minimum dwell time,
+// so keys brushed while moving to another key don't play.
+fn touch_dwell_reached(ui: &egui::Ui, state: &mut KeyboardState, key: usize) -> bool {
+    if state.min_dwell <= 0.0 { return true; }
+    let dwell = Duration::from_secs_f32(state.min_dwell);
+    match state.touched_key {
+        Some((touched_key, time)) if touched_key == key => {
+            if time.elapsed() >= dwell {
+                state.touched_key = None;
+                return true;
+            }
+            ui.ctx().request_repaint_after(dwell.saturating_sub(time.elapsed()));
+        }
+        _ => {
+            state.touched_key = Some((key, Instant::now()));
+            ui.ctx().request_repaint_after(dwell);
         }
     }
+    false
 }
 
-fn send_note_event(midi_write: &mpsc::Sender<MidiMessage>, key: usize, pressure: u8) {
-    if pressure == 0 {
-        midi_write.send(MidiMessage::NoteOff(1, MidiKeyEvent { key: key as u8, pressure: 0 })).unwrap_or(());
-    } else {
-        midi_write.send(MidiMessage::NoteOn(1, MidiKeyEvent { key: key as u8, pressure })).unwrap_or(());
+// Velocity of a mouse click.  With position velocity it's taken from
+// the position within the key's own rect (louder towards the front of
+// the key), so black and white keys respond the same.
+fn get_mouse_velocity(state: &KeyboardState, col: &KeyCollision, pos: Pos2) -> u8 {
+    if ! state.position_velocity {
+        return state.mouse_velocity;
     }
+    let frac = ((pos.y - col.rect.min.y) / col.rect.height()).clamp(0.0, 1.0);
+    (MOUSE_MIN_VELOCITY + frac * (127.0 - MOUSE_MIN_VELOCITY)).round() as u8
 }
 
 fn get_key_state(key: usize, keys: &[SynthKeyState]) -> SynthKeyState {
     keys.get(key).copied().unwrap_or(SynthKeyState::Off)
 }
 
+// Records when each key was released, for the fade out of its highlight.
+// Returns true if any key is still fading.
+fn update_release_times(state: &mut KeyboardState, keys: &[SynthKeyState]) -> bool {
+    state.release_times.resize(keys.len(), None);
+    let now = Instant::now();
+    let mut fading = false;
+    for (key_state, release_time) in keys.iter().zip(state.release_times.iter_mut()) {
+        match key_state {
+            SynthKeyState::Off => {
+                if release_time.is_some_and(|t| now.duration_since(t).as_secs_f32() >= state.fade_time) {
+                    *release_time = None;
+                }
+                fading |= release_time.is_some();
+            }
+            _ => {
+                *release_time = Some(now);
+                // held keys dim as the notes decay
+                fading |= state.follow_volume;
+            }
+        }
+    }
+    fading
+}
+
+// Returns how much of the highlight of a released key is left (0 to 1),
+// following the voice envelope but never longer than the fade time.
+fn get_fade_level(key: usize, levels: &[f32], state: &KeyboardState) -> f32 {
+    let Some(Some(release_time)) = state.release_times.get(key) else { return 0.0; };
+    if state.fade_time <= 0.0 { return 0.0; }
+    let time_level = 1.0 - release_time.elapsed().as_secs_f32() / state.fade_time;
+    time_level.min(levels.get(key).copied().unwrap_or(0.0)).max(0.0)
+}
+
+// Returns the color of a pressed key, or None if the key is not pressed.
+// The key pressed with the mouse is shown immediately, without waiting
+// for the synth to process the note.
+fn get_pressed_key_color(key: usize, keys: &[SynthKeyState], levels: &[f32], state: &KeyboardState) -> Option<Color32> {
+    let sounding_key = state.sounding_key(key);
+    let key_state = sounding_key.map_or(SynthKeyState::Off, |key| get_key_state(key, keys));
+    match key_state {
+        SynthKeyState::Playing(..) if state.follow_volume => {
+            let level = sounding_key.and_then(|key| levels.get(key)).copied().unwrap_or(1.0);
+            Some(state.colors.pressed_key_color(key, &state.theme).gamma_multiply(level))
+        }
+        SynthKeyState::Playing(..) => Some(state.colors.pressed_key_color(key, &state.theme)),
+        SynthKeyState::VoiceStolen => Some(state.theme.stolen_key_color),
+        SynthKeyState::Off if state.pressing_key == Some(key) => Some(state.colors.pressed_key_color(key, &state.theme)),
+        SynthKeyState::Off => {
+            let fade = sounding_key.map_or(0.0, |key| get_fade_level(key, levels, state));
+            (fade > 0.0).then(|| state.colors.pressed_key_color(key, &state.theme).gamma_multiply(fade))
+        }
+    }
+}
+
 /*
         block0            block1
      _____/\_____   ________/\________
@@ -75,9 +373,8 @@ octave_width / octave_height = 13.6 / 8.2
 const OCTAVE_ASPECT_RATIO: f32 = 13.6 / 8.2;
 const BLACK_KEY_HEIGHT: f32 = 5.0 / 8.0;
 
-fn build_key_collision(keyboard_rect: Rect, state: &mut KeyboardState, first_key: usize) {
+fn build_key_collision(keyboard_rect: Rect, octave_width: f32, state: &mut KeyboardState, first_key: usize) {
     let octave_height = keyboard_rect.height();
-    let octave_width = octave_height * OCTAVE_ASPECT_RATIO;
     let ww = octave_width / 7.0;
     let w0 = octave_width * 3.0 / 35.0;
     let w1 = octave_width * 4.0 / 49.0;
@@ -130,12 +427,109 @@ fn build_key_collision(keyboard_rect: Rect, state: &mut KeyboardState, first_key
     }
 }
 
-pub fn show_keyboard(ui: &mut egui::Ui, state: &mut KeyboardState, keys: &[SynthKeyState], midi_write: &mpsc::Sender<MidiMessage>) {
-    let size = ui.available_size();
+fn draw_velocities(ui: &egui::Ui, painter: &egui::Painter, state: &KeyboardState, keyboard_rect: Rect, strikes: &[Option<SynthKeyStrike>]) {
+    let font = egui::FontId::proportional((keyboard_rect.height() / 12.0).clamp(6.0, 16.0));
+    let mut fading = false;
+    for col in &state.collision {
+        if col.rect.min.x > keyboard_rect.max.x { continue; }
+        let Some(Some(strike)) = state.sounding_key(col.key).and_then(|key| strikes.get(key)) else { continue; };
+        let elapsed = strike.time.elapsed();
+        if elapsed >= VELOCITY_FADE_TIME { continue; }
+        fading = true;
+
+        // white keys show the velocity below the black keys, black keys near their bottom
+        let alpha = 1.0 - elapsed.as_secs_f32() / VELOCITY_FADE_TIME.as_secs_f32();
+        let (pos, color) = if col.black {
+            (col.rect.center(), state.theme.white_key_color)
+        } else {
+            let y = col.rect.min.y + col.rect.height() * (BLACK_KEY_HEIGHT + 1.0) / 2.0;
+            (Pos2::new(col.rect.center().x, y), state.theme.black_key_color)
+        };
+        painter.text(pos, egui::Align2::CENTER_CENTER, strike.velocity.to_string(), font.clone(), color.gamma_multiply(alpha));
+    }
+    if fading {
+        ui.ctx().request_repaint_after(ANIMATION_REPAINT_TIME);
+    }
+}
+
+fn draw_labels(painter: &egui::Painter, state: &KeyboardState, keyboard_rect: Rect) {
+    let font_size = (keyboard_rect.height() * OCTAVE_ASPECT_RATIO / 7.0 / 3.5).clamp(5.0, 14.0);
+    let font = egui::FontId::proportional(font_size);
+    for col in &state.collision {
+        if col.rect.min.x > keyboard_rect.max.x { continue; }
+        let Some(label) = state.labels.key_label(col.key) else { continue; };
+        let pos = Pos2::new(col.rect.center().x, col.rect.max.y - font_size);
+        let color = if col.black { state.theme.black_key_label_color } else { state.theme.label_color };
+        painter.text(pos, egui::Align2::CENTER_CENTER, label, font.clone(), color);
+    }
+}
+
+fn draw_c_markers(painter: &egui::Painter, state: &KeyboardState, keyboard_rect: Rect) {
+    let font_size = (keyboard_rect.height() * OCTAVE_ASPECT_RATIO / 7.0 / 3.5).clamp(5.0, 14.0);
+    let font = egui::FontId::proportional(font_size);
+    let stroke = egui::Stroke::new(2.0, state.theme.key_line_color);
+    for col in &state.collision {
+        if col.black || col.key % 12 != 0 { continue; }
+        if col.rect.min.x > keyboard_rect.max.x { break; }
+        painter.vline(col.rect.min.x, col.rect.y_range(), stroke);
+
+        // the name goes above the key label, so both can be shown
+        let pos = Pos2::new(col.rect.center().x, col.rect.max.y - 2.5 * font_size);
+        let name = format!("C{}", (col.key / 12) as i32 - 1);
+        painter.text(pos, egui::Align2::CENTER_CENTER, name, font.clone(), state.theme.label_color.gamma_multiply(0.6));
+    }
+}
+
+// Scrolling moves the first key shown by one octave at a time.  The
+// first key is always a C, and the last octave that can be reached is
+// the one that ends at the last key.
+const SCROLL_STEP: f32 = 50.0;
+const PITCH_BEND_METER_WIDTH: f32 = 6.0;
+
+fn scroll_keyboard(ui: &egui::Ui, state: &mut KeyboardState, num_keys: usize) {
+    let delta = ui.input(|i| i.smooth_scroll_delta);
+    state.scroll += if delta.x != 0.0 { delta.x } else { delta.y };
+    let max_first_key = num_keys.saturating_sub(12) / 12 * 12;
+    while state.scroll.abs() >= SCROLL_STEP {
+        if state.scroll > 0.0 {
+            state.first_key = state.first_key.saturating_sub(12);
+            state.scroll -= SCROLL_STEP;
+        } else {
+            state.first_key = (state.first_key + 12).min(max_first_key);
+            state.scroll += SCROLL_STEP;
+        }
+    }
+}
+
+// Shows the pitch bend (from -1 to 1) as a thin vertical meter filled
+// from the center, with the same height as the keyboard.
+pub fn show_pitch_bend(ui: &mut egui::Ui, state: &KeyboardState, bend: f32) {
+    let theme = state.theme;
+    let size = Vec2::new(PITCH_BEND_METER_WIDTH, ui.available_height().min(state.max_height));
+    let (response, painter) = ui.allocate_painter(size, egui::Sense::hover());
+    let rect = response.rect;
+    painter.rect_filled(rect, egui::CornerRadius::ZERO, theme.border_color);
+    let center = rect.center().y;
+    let end = center - bend.clamp(-1.0, 1.0) * rect.height() / 2.0;
+    let bar = Rect::from_x_y_ranges(rect.x_range(), center.min(end)..=center.max(end));
+    painter.rect_filled(bar, egui::CornerRadius::ZERO, theme.pressed_key_color);
+    painter.hline(rect.x_range(), center, egui::Stroke::new(1.0, theme.white_key_color));
+}
+
+pub fn show_keyboard(ui: &mut egui::Ui, state: &mut KeyboardState, keys: &[SynthKeyState], strikes: &[Option<SynthKeyStrike>],
+                     levels: &[f32], midi_write: &mpsc::Sender<MidiEvent>) {
+    // limit the height so the keys don't get too wide on tall windows
+    let theme = state.theme;
+    let mut height = ui.available_height().min(state.max_height);
+    if state.layout == KeyboardLayout::FitOctaves {
+        let octave_width = ui.available_width() / state.num_octaves.max(1) as f32;
+        height = height.min(octave_width / OCTAVE_ASPECT_RATIO + theme.border_size + 1.0);
+    }
+    let size = Vec2::new(ui.available_width(), height);
     let (response, mut painter) = ui.allocate_painter(size, egui::Sense::drag());
 
     let keyboard_rect = Rect {
-        min: response.rect.min + Vec2::new(0.0, BORDER_SIZE),
+        min: response.rect.min + Vec2::new(0.0, theme.border_size),
         max: response.rect.max - Vec2::splat(1.0),
     };
     let top_border_rect = Rect {
@@ -143,23 +537,61 @@ pub fn show_keyboard(ui: &mut egui::Ui, state: &mut KeyboardState, keys: &[Synth
         max: Pos2::new(response.rect.max.x, response.rect.max.y - 1.0)
     };
 
-    painter.rect_filled(response.rect, egui::CornerRadius::ZERO, BORDER_COLOR);
-    painter.rect_filled(top_border_rect, egui::CornerRadius::ZERO, TOP_BORDER_COLOR);
-    painter.rect_filled(keyboard_rect, egui::CornerRadius::ZERO, Color32::WHITE);
+    painter.rect_filled(response.rect, egui::CornerRadius::ZERO, theme.border_color);
+    painter.rect_filled(top_border_rect, egui::CornerRadius::ZERO, theme.top_border_color);
+    painter.rect_filled(keyboard_rect, egui::CornerRadius::ZERO, theme.white_key_color);
 
     painter.shrink_clip_rect(keyboard_rect);
-    build_key_collision(keyboard_rect, state, 36);
+    if response.hovered() {
+        scroll_keyboard(ui, state, keys.len());
+    }
+    let octave_width = match state.layout {
+        KeyboardLayout::FillWidth => keyboard_rect.height() * OCTAVE_ASPECT_RATIO,
+        KeyboardLayout::FitOctaves => keyboard_rect.width() / state.num_octaves.max(1) as f32,
+    };
+    build_key_collision(keyboard_rect, octave_width, state, state.first_key);
 
-    let stroke = egui::Stroke::new(1.0, Color32::BLACK);
+    // handle the mouse before drawing, so a clicked key shows up in this frame
+    if response.drag_stopped() && let Some(pressing_key) = state.pressing_key {
+        state.send_note_event(midi_write, pressing_key, 0);
+        state.pressing_key = None;
+    }
+    if ! response.is_pointer_button_down_on() {
+        state.touched_key = None;
+    }
+
+    if response.is_pointer_button_down_on() && let Some(pointer_pos) = response.interact_pointer_pos() {
+        let new_col = state.collision.iter().find(|col| col.rect.contains(pointer_pos));
+        let new_note = new_col.map(|col| (col.key, get_mouse_velocity(state, col, pointer_pos)));
+        if new_note.map(|(key, _)| key) != state.pressing_key {
+            if let Some(pressing_key) = state.pressing_key {
+                state.send_note_event(midi_write, pressing_key, 0);
+                state.pressing_key = None;
+            }
+            match new_note {
+                Some((key, velocity)) if touch_dwell_reached(ui, state, key) => {
+                    state.send_note_event(midi_write, key, velocity);
+                    state.pressing_key = Some(key);
+                }
+                Some(_) => {}
+                None => { state.touched_key = None; }
+            }
+        }
+    }
+
+    if update_release_times(state, keys) {
+        ui.ctx().request_repaint_after(ANIMATION_REPAINT_TIME);
+    }
+
+    let stroke = egui::Stroke::new(1.0, theme.key_line_color);
+    let corner_radius = theme.key_corner_radius();
 
     // draw pressed white keys
     for col in &state.collision {
         if col.black { continue; }
         if col.rect.min.x > keyboard_rect.max.x { break; }
-        match get_key_state(col.key, keys) {
-            SynthKeyState::Playing(..) => { painter.rect_filled(col.rect, egui::CornerRadius::ZERO, PRESSED_KEY_COLOR); }
-            SynthKeyState::VoiceStolen => { painter.rect_filled(col.rect, egui::CornerRadius::ZERO, STOLEN_KEY_COLOR); }
-            _ => {}
+        if let Some(color) = get_pressed_key_color(col.key, keys, levels, state) {
+            painter.rect_filled(col.rect, corner_radius, color);
         }
     }
 
@@ -176,36 +608,69 @@ pub fn show_keyboard(ui: &mut egui::Ui, state: &mut KeyboardState, keys: &[Synth
             break;
         }
         if col.black {
-            match get_key_state(col.key, keys) {
-                SynthKeyState::Playing(..) => {
-                    painter.rect(col.rect, egui::CornerRadius::ZERO, PRESSED_KEY_COLOR, stroke, egui::StrokeKind::Inside);
-                }
-                SynthKeyState::VoiceStolen => {
-                    painter.rect(col.rect, egui::CornerRadius::ZERO, STOLEN_KEY_COLOR, stroke, egui::StrokeKind::Inside);
-                }
-                SynthKeyState::Off => {
-                    painter.rect_filled(col.rect, egui::CornerRadius::ZERO, Color32::BLACK);
-                }
+            // a fading key is drawn over the black key color
+            painter.rect_filled(col.rect, corner_radius, theme.black_key_color);
+            if let Some(color) = get_pressed_key_color(col.key, keys, levels, state) {
+                painter.rect(col.rect, corner_radius, color, stroke, egui::StrokeKind::Inside);
             }
         }
     }
 
-    if response.drag_stopped() && let Some(pressing_key) = state.pressing_key {
-        send_note_event(midi_write, pressing_key, 0);
-        state.pressing_key = None;
+    if state.c_markers {
+        draw_c_markers(&painter, state, keyboard_rect);
     }
+    draw_labels(&painter, state, keyboard_rect);
 
-    if response.is_pointer_button_down_on() && let Some(pointer_pos) = response.interact_pointer_pos() {
-        let new_key = state.collision.iter().find(|col| col.rect.contains(pointer_pos)).map(|col| col.key);
-        if new_key != state.pressing_key {
-            if let Some(pressing_key) = state.pressing_key {
-                send_note_event(midi_write, pressing_key, 0);
-                state.pressing_key = None;
-            }
-            if let Some(new_key) = new_key {
-                send_note_event(midi_write, new_key, 64);
-                state.pressing_key = Some(new_key);
+    if state.show_velocity {
+        draw_velocities(ui, &painter, state, keyboard_rect, strikes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Clicks a drawn key and returns the MIDI key sent to the synth.
+    fn click_key(state: &mut KeyboardState, key: usize) -> Option<u8> {
+        let (midi_write, midi_read) = mpsc::channel();
+        state.send_note_event(&midi_write, key, 100);
+        match midi_read.try_recv().ok()?.message {
+            MidiMessage::NoteOn(_, MidiKeyEvent { key, .. }) => Some(key),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn clicked_key_lights_the_key_that_sounds() {
+        for follow_transpose in [false, true] {
+            for transpose in [-5, 0, 7] {
+                let mut state = KeyboardState::new();
+                state.follow_transpose = follow_transpose;
+                state.transpose = transpose;
+                for key in 20..60 {
+                    let midi_key = click_key(&mut state, key).unwrap();
+                    // the synth plays (and stores the state of) the transposed key
+                    let sounding = midi_key as i32 + transpose;
+                    assert_eq!(state.sounding_key(key).map(|k| k as i32), Some(sounding),
+                               "follow {} transpose {} key {}", follow_transpose, transpose, key);
+                    if follow_transpose {
+                        assert_eq!(sounding, key as i32, "the drawn key should sound");
+                    } else {
+                        assert_eq!(midi_key as usize, key, "the drawn key should be played");
+                    }
+                }
             }
         }
     }
+
+    #[test]
+    fn keys_out_of_range_are_not_played() {
+        let mut state = KeyboardState::new();
+        state.follow_transpose = true;
+        state.transpose = 5;
+        assert_eq!(click_key(&mut state, 2), None);
+        state.follow_transpose = false;
+        state.transpose = -5;
+        assert_eq!(state.sounding_key(2), None);
+    }
 }
@@ -1,19 +1,35 @@
 mod midi_message;
 mod midi_reader;
 mod midi_ports;
+mod midi_output;
+mod midi_file;
+mod midi_file_player;
+mod command_listener;
 mod audio_writer;
 mod synth;
 mod synth_voice;
+mod effect;
+mod chorus;
+mod bitcrusher;
+mod compressor;
 mod keyboard;
+mod qwerty;
 mod app;
-mod show_error;
+mod scope;
+mod spectrum;
+mod tuning;
+mod wav_file;
+mod note_log;
+mod patch_bank;
 
 use std::sync::mpsc;
+use std::error::Error;
 
-use midi_message::MidiMessage;
+use midi_message::MidiEvent;
 use midi_reader::MidiReaderCommand;
 use audio_writer::{AudioWriter, RequestedConfig};
 
+const APP_NAME: &str = "Key Synth";
 const DEFAULT_SLEEP_TIME: u64 = 5000;
 const DEFAULT_MIDI_PORTS: &[&str] = &[
     // MIDI inputs we'll try to connect to at startup (the actual port
@@ -26,39 +42,38 @@ const PREF_SOUND_CONFIG: RequestedConfig = RequestedConfig {
     pref_sample_rate: 48000,
     buffer_size: 1024,
     num_channels: 2,
+    allow_any_sample_rate: true,
+    sample_formats: &[cpal::SampleFormat::I16, cpal::SampleFormat::F32],
 };
 
-fn start_app(audio_writer: AudioWriter, midi_write: mpsc::Sender<MidiMessage>, midi_read: mpsc::Receiver<MidiMessage>,
+// The synth renders audio in blocks of this many frames, regardless of
+// the buffer size negotiated with the audio device.  Parameter changes
+// (note on/off, envelopes, etc.) are only applied between blocks, so
+// smaller blocks give finer timing resolution at a slightly higher CPU
+// cost.  The output latency is still determined by the device buffer
+// size (`PREF_SOUND_CONFIG.buffer_size`): lowering the latency means
+// requesting a smaller device buffer, which increases the risk of
+// audio dropouts on slow machines.
+const SYNTH_BLOCK_SIZE: usize = 64;
+
+fn start_app(audio_writer: Result<AudioWriter, Box<dyn Error>>, midi_write: mpsc::Sender<MidiEvent>, midi_read: mpsc::Receiver<MidiEvent>,
              reader_command: Option<mpsc::Sender<MidiReaderCommand>>) -> eframe::Result {
+    // The window size and position are restored from the last session,
+    // so only center the window when there's no saved session.
+    let has_saved_window = eframe::storage_dir(APP_NAME).is_some_and(|dir| dir.join("app.ron").exists());
     let viewport = egui::ViewportBuilder::default().with_inner_size([1800.0, 350.0]).with_min_inner_size([640.0, 236.0]);
     let options = eframe::NativeOptions {
         viewport,
-        centered: true,
-        ..Default::default()
-    };
-
-    eframe::run_native(
-        "Key Synth",
-        options,
-        Box::new(|cc| {
-            Ok(Box::new(app::KeySynthApp::new(cc, audio_writer, midi_read, midi_write, reader_command)))
-        })
-    )
-}
-
-fn show_error(message: String) -> eframe::Result {
-    let viewport = egui::ViewportBuilder::default().with_inner_size([640.0, 480.0]);
-    let options = eframe::NativeOptions {
-        viewport,
-        centered: true,
+        centered: ! has_saved_window,
+        persist_window: true,
         ..Default::default()
     };
 
     eframe::run_native(
-        "Key Synth - Error",
+        APP_NAME,
         options,
         Box::new(|cc| {
-            Ok(Box::new(show_error::ShowErrorApp::new(cc, message)))
+            Ok(Box::new(app::KeySynthApp::new(cc, audio_writer, PREF_SOUND_CONFIG, SYNTH_BLOCK_SIZE, midi_read, midi_write, reader_command)))
         })
     )
 }
@@ -66,19 +81,34 @@ fn show_error(message: String) -> eframe::Result {
 fn main() -> eframe::Result {
     // MIDI messages are written to `midi_write` by the UI and the
     // midi reader, and read from `midi_read` by the synth.
-    let (midi_write, midi_read) = mpsc::channel::<MidiMessage>();
+    let (midi_write, midi_read) = mpsc::channel::<MidiEvent>();
 
     // The midi reader receives events from the selected MIDI IN
     // port and writes midi messages to `midi_write`.  We control
     // it (configure/stop) by writing comands to `reader_command`.
     let reader_command = midi_reader::start(DEFAULT_SLEEP_TIME, DEFAULT_MIDI_PORTS, midi_write.clone()).ok();
 
+    // With `--listen <port>`, other programs can play notes by sending
+    // text commands to the port (see `command_listener`).
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--listen" {
+            match args.next().map(|port| port.parse::<u16>()) {
+                Some(Ok(port)) => {
+                    if let Err(e) = command_listener::start(port, midi_write.clone()) {
+                        println!("ERROR starting command listener on port {}: {}", port, e);
+                    }
+                }
+                _ => { println!("ERROR: --listen requires a port number"); }
+            }
+        }
+    }
+
     // The audio writer requests samples from the synth and
-    // sends audio to the output device. It will be started by the App.
+    // sends audio to the output device. It will be started by the App,
+    // which shows the error (and lets the devices be rescanned) if no
+    // audio device can be opened.
     let audio_writer = AudioWriter::init(PREF_SOUND_CONFIG);
 
-    match audio_writer {
-        Ok(audio_writer) => { start_app(audio_writer, midi_write, midi_read, reader_command) }
-        Err(e) => { show_error(format!("Error initializing sound: {}", e)) }
-    }
+    start_app(audio_writer, midi_write, midi_read, reader_command)
 }
@@ -0,0 +1,183 @@
+use std::result::Result;
+use std::error::Error;
+use std::time::Duration;
+
+use super::midi_message::{MidiMessage, MidiKeyEvent};
+
+const DEFAULT_TEMPO: u32 = 500_000;   // microseconds per quarter note (120 BPM)
+
+pub struct MidiFileEvent {
+    pub time: Duration,     // since the start of the song
+    pub message: MidiMessage,
+}
+
+struct TrackEvent {
+    tick: u64,
+    order: usize,           // keeps the file order for events with the same tick
+    kind: TrackEventKind,
+}
+
+enum TrackEventKind {
+    Tempo(u32),
+    Message(MidiMessage),
+}
+
+// chunk type and contents
+type Chunk<'a> = (&'a [u8], &'a [u8]);
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        if self.data.len() - self.pos < len {
+            return Err("unexpected end of MIDI file".into());
+        }
+        let bytes = &self.data[self.pos..self.pos+len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, Box<dyn Error>> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_var_len(&mut self) -> Result<u32, Box<dyn Error>> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.read_u8()?;
+            value = (value << 7) | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("invalid variable length quantity in MIDI file".into())
+    }
+
+    fn read_chunk(&mut self) -> Result<Chunk<'a>, Box<dyn Error>> {
+        let id = self.read_bytes(4)?;
+        let len = self.read_u32()? as usize;
+        Ok((id, self.read_bytes(len)?))
+    }
+}
+
+fn read_track(data: &[u8], events: &mut Vec<TrackEvent>) -> Result<(), Box<dyn Error>> {
+    let mut reader = Reader::new(data);
+    let mut tick = 0;
+    let mut running_status = None;
+    while ! reader.is_empty() {
+        tick += reader.read_var_len()? as u64;
+        let mut status = reader.read_u8()?;
+        let kind = match status {
+            0xFF => {
+                let meta_type = reader.read_u8()?;
+                let len = reader.read_var_len()? as usize;
+                let meta = reader.read_bytes(len)?;
+                match meta_type {
+                    0x2F => { break; }      // end of track
+                    0x51 if len == 3 => TrackEventKind::Tempo(u32::from_be_bytes([0, meta[0], meta[1], meta[2]])),
+                    _ => { continue; }
+                }
+            }
+
+            0xF0 | 0xF7 => {
+                // system exclusive messages are ignored
+                let len = reader.read_var_len()? as usize;
+                reader.read_bytes(len)?;
+                continue;
+            }
+
+            _ => {
+                // a data byte means the status byte of the previous message is reused
+                let mut first_data = None;
+                if status < 0x80 {
+                    first_data = Some(status);
+                    status = running_status.ok_or("MIDI file data without status byte")?;
+                }
+                running_status = Some(status);
+                let num_data = if matches!(status & 0xf0, 0xC0 | 0xD0) { 1 } else { 2 };
+                let mut message = vec![status];
+                message.extend(first_data);
+                while message.len() <= num_data {
+                    message.push(reader.read_u8()?);
+                }
+                match MidiMessage::decode(&message) {
+                    // note on with zero velocity is a note off
+                    MidiMessage::NoteOn(chan, MidiKeyEvent { key, pressure: 0 }) => {
+                        TrackEventKind::Message(MidiMessage::NoteOff(chan, MidiKeyEvent { key, pressure: 0 }))
+                    }
+                    message => TrackEventKind::Message(message),
+                }
+            }
+        };
+        events.push(TrackEvent { tick, order: events.len(), kind });
+    }
+    Ok(())
+}
+
+// Reads a standard MIDI file (format 0 or 1), returning all its
+// channel messages merged in a single list ordered by time.
+pub fn read_midi_file(data: &[u8]) -> Result<Vec<MidiFileEvent>, Box<dyn Error>> {
+    let mut reader = Reader::new(data);
+    let (id, header) = reader.read_chunk()?;
+    if id != b"MThd" || header.len() < 6 {
+        return Err("not a MIDI file".into());
+    }
+    let mut header = Reader::new(header);
+    let _format = header.read_u16()?;
+    let num_tracks = header.read_u16()?;
+    let division = header.read_u16()?;
+
+    let mut track_events = Vec::new();
+    for _ in 0..num_tracks {
+        let (id, track) = reader.read_chunk()?;
+        if id == b"MTrk" {
+            read_track(track, &mut track_events)?;
+        }
+    }
+    track_events.sort_by_key(|event| (event.tick, event.order));
+
+    // convert ticks to time, following the tempo changes
+    let mut events = Vec::new();
+    let mut tempo = DEFAULT_TEMPO;
+    let mut last_tick = 0;
+    let mut time = Duration::ZERO;
+    for event in track_events {
+        let ticks = (event.tick - last_tick) as f64;
+        let seconds = if division & 0x8000 != 0 {
+            // SMPTE: frames per second (negative) and ticks per frame
+            let fps = -((division >> 8) as i8) as f64;
+            let ticks_per_frame = (division & 0xff).max(1) as f64;
+            ticks / (fps * ticks_per_frame)
+        } else {
+            ticks * tempo as f64 / 1_000_000.0 / division.max(1) as f64
+        };
+        time += Duration::from_secs_f64(seconds.max(0.0));
+        last_tick = event.tick;
+        match event.kind {
+            TrackEventKind::Tempo(new_tempo) => { tempo = new_tempo; }
+            TrackEventKind::Message(message) => { events.push(MidiFileEvent { time, message }); }
+        }
+    }
+    Ok(events)
+}
@@ -0,0 +1,58 @@
+use super::midi_message::{MidiMessage, MidiKeyEvent};
+use super::midi_file::MidiFileEvent;
+
+// Plays the events of a MIDI file inside the synth.  The song is timed
+// by the number of frames rendered instead of the wall clock, so the
+// events are sample-accurate and never drift against the audio device.
+pub struct MidiFilePlayer {
+    events: Vec<MidiFileEvent>,
+    next: usize,            // index of the next event to play
+    frame: u64,             // frames rendered since the start of the song
+    held_keys: [bool; 128],
+}
+
+impl MidiFilePlayer {
+    pub fn new(events: Vec<MidiFileEvent>) -> Self {
+        MidiFilePlayer {
+            events,
+            next: 0,
+            frame: 0,
+            held_keys: [false; 128],
+        }
+    }
+
+    fn event_frame(event: &MidiFileEvent, sample_rate: f32) -> u64 {
+        (event.time.as_secs_f64() * sample_rate as f64).round() as u64
+    }
+
+    // Returns the number of frames until the next event, if any.
+    pub fn frames_to_next_event(&self, sample_rate: f32) -> Option<u64> {
+        let event = self.events.get(self.next)?;
+        Some(Self::event_frame(event, sample_rate).saturating_sub(self.frame))
+    }
+
+    // Returns the next event if it's due at the current frame.
+    pub fn take_due_event(&mut self, sample_rate: f32) -> Option<MidiMessage> {
+        if self.frames_to_next_event(sample_rate)? > 0 {
+            return None;
+        }
+        let message = self.events[self.next].message.clone();
+        self.next += 1;
+        match &message {
+            MidiMessage::NoteOn(_, MidiKeyEvent { key, .. }) => { self.held_keys[(*key & 0x7f) as usize] = true; }
+            MidiMessage::NoteOff(_, MidiKeyEvent { key, .. }) => { self.held_keys[(*key & 0x7f) as usize] = false; }
+            _ => {}
+        }
+        Some(message)
+    }
+
+    pub fn advance(&mut self, num_frames: usize) {
+        self.frame += num_frames as u64;
+    }
+
+    // Returns the keys that were played and not yet released, so they
+    // can be released when the song is stopped.
+    pub fn held_keys(&self) -> impl Iterator<Item = u8> + '_ {
+        self.held_keys.iter().enumerate().filter(|(_, held)| **held).map(|(key, _)| key as u8)
+    }
+}
@@ -1,3 +1,5 @@
+use std::time::Instant;
+
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub struct MidiKeyEvent {
@@ -33,7 +35,47 @@ pub struct MidiPitchEvent {
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub struct MidiSysExEvent {
-    pub data: [u8; 2],
+    pub data: Vec<u8>,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct MidiTimeCodeEvent {
+    pub message_type: u8,
+    pub value: u8,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct MidiSongPositionEvent {
+    pub position: u16,      // in MIDI beats (sixteenth notes) since the start of the song
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct MidiSongSelectEvent {
+    pub song: u8,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub enum MidiRealTimeEvent {
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    SystemReset,
+}
+
+// State of the MIDI reader, reported while it looks for an accepted
+// port and connects to it.
+#[derive(Clone, Debug)]
+pub enum MidiReaderStatus {
+    Searching(Vec<String>),     // names (or parts of names) of the accepted ports
+    Connecting(String),
+    Connected(String),
+    Error(String),
 }
 
 #[allow(dead_code)]
@@ -41,6 +83,8 @@ pub struct MidiSysExEvent {
 pub enum MidiMessage {
     PortConnected,
     PortDisconnected,
+    ReaderStatus(MidiReaderStatus),
+    Shutdown,       // sent by the app to stop the synth
     Invalid,
     NoteOn(u8, MidiKeyEvent),
     NoteOff(u8, MidiKeyEvent),
@@ -49,13 +93,36 @@ pub enum MidiMessage {
     ProgramChange(u8, MidiProgramChangeEvent),
     ChannelAftertouch(u8, MidiAftertouchEvent),
     PitchWheel(u8, MidiPitchEvent),
-    SysEx(u8, MidiSysExEvent),
+    SysEx(MidiSysExEvent),
+    TimeCodeQuarterFrame(MidiTimeCodeEvent),
+    SongPosition(MidiSongPositionEvent),
+    SongSelect(MidiSongSelectEvent),
+    TuneRequest,
+    RealTime(MidiRealTimeEvent),
+}
+
+// A message together with the time it was received from the device,
+// if known.  Messages generated by the app itself have no time and are
+// processed immediately.
+#[derive(Clone, Debug)]
+pub struct MidiEvent {
+    pub time: Option<Instant>,
+    pub message: MidiMessage,
+}
+
+impl From<MidiMessage> for MidiEvent {
+    fn from(message: MidiMessage) -> Self {
+        MidiEvent { time: None, message }
+    }
 }
 
 impl MidiMessage {
     pub fn decode(data: &[u8]) -> Self {
-        let chan = (data[0] & 0x0f) + 1;
-        match data[0] & 0xf0 {
+        let Some(&status) = data.first() else {
+            return MidiMessage::Invalid;
+        };
+        let chan = (status & 0x0f) + 1;
+        match status & 0xf0 {
             0x80 => if data.len() >= 3 {
                 MidiMessage::NoteOff(chan, MidiKeyEvent { key: data[1], pressure: data[2] })
             } else {
@@ -93,14 +160,107 @@ impl MidiMessage {
             }
 
             0xE0 => if data.len() >= 3 {
-                MidiMessage::PitchWheel(chan, MidiPitchEvent { wheel: ((data[2] as u16) << 8) | (data[1] as u16) })
+                MidiMessage::PitchWheel(chan, MidiPitchEvent { wheel: (((data[2] & 0x7f) as u16) << 7) | ((data[1] & 0x7f) as u16) })
             } else {
                 MidiMessage::Invalid
             }
 
-            0xF0 => MidiMessage::SysEx(chan, MidiSysExEvent { data: [data[1], data[2]] }),
+            0xF0 => Self::decode_system(data),
 
             _ => MidiMessage::Invalid,
         }
     }
+
+    // Encodes a channel message to raw bytes; other messages can't be
+    // encoded and return None.
+    pub fn encode(&self) -> Option<Vec<u8>> {
+        let status = |kind: u8, chan: u8| kind | (chan.saturating_sub(1) & 0x0f);
+        let data = match self {
+            MidiMessage::NoteOff(chan, ev) => vec![status(0x80, *chan), ev.key & 0x7f, ev.pressure & 0x7f],
+            MidiMessage::NoteOn(chan, ev) => vec![status(0x90, *chan), ev.key & 0x7f, ev.pressure & 0x7f],
+            MidiMessage::PolyAfertouch(chan, ev) => vec![status(0xA0, *chan), ev.key & 0x7f, ev.pressure & 0x7f],
+            MidiMessage::ControlChange(chan, ev) => vec![status(0xB0, *chan), ev.control & 0x7f, ev.value & 0x7f],
+            MidiMessage::ProgramChange(chan, ev) => vec![status(0xC0, *chan), ev.number & 0x7f],
+            MidiMessage::ChannelAftertouch(chan, ev) => vec![status(0xD0, *chan), ev.pressure & 0x7f],
+            MidiMessage::PitchWheel(chan, ev) => vec![status(0xE0, *chan), (ev.wheel & 0x7f) as u8, ((ev.wheel >> 7) & 0x7f) as u8],
+            _ => { return None; }
+        };
+        Some(data)
+    }
+
+    fn decode_system(data: &[u8]) -> Self {
+        match data[0] {
+            // system exclusive (0xF7 is sent alone when a long message is split)
+            0xF0 | 0xF7 => MidiMessage::SysEx(MidiSysExEvent { data: data.to_vec() }),
+
+            // system common
+            0xF1 => if data.len() >= 2 {
+                MidiMessage::TimeCodeQuarterFrame(MidiTimeCodeEvent { message_type: (data[1] >> 4) & 0x07, value: data[1] & 0x0f })
+            } else {
+                MidiMessage::Invalid
+            }
+
+            0xF2 => if data.len() >= 3 {
+                MidiMessage::SongPosition(MidiSongPositionEvent { position: (((data[2] & 0x7f) as u16) << 7) | ((data[1] & 0x7f) as u16) })
+            } else {
+                MidiMessage::Invalid
+            }
+
+            0xF3 => if data.len() >= 2 {
+                MidiMessage::SongSelect(MidiSongSelectEvent { song: data[1] })
+            } else {
+                MidiMessage::Invalid
+            }
+
+            0xF6 => MidiMessage::TuneRequest,
+
+            // system real time
+            0xF8 => MidiMessage::RealTime(MidiRealTimeEvent::TimingClock),
+            0xFA => MidiMessage::RealTime(MidiRealTimeEvent::Start),
+            0xFB => MidiMessage::RealTime(MidiRealTimeEvent::Continue),
+            0xFC => MidiMessage::RealTime(MidiRealTimeEvent::Stop),
+            0xFE => MidiMessage::RealTime(MidiRealTimeEvent::ActiveSensing),
+            0xFF => MidiMessage::RealTime(MidiRealTimeEvent::SystemReset),
+
+            // undefined (0xF4, 0xF5, 0xF9, 0xFD)
+            _ => MidiMessage::Invalid,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_song_position() {
+        let MidiMessage::SongPosition(ev) = MidiMessage::decode(&[0xF2, 0x05, 0x02]) else { panic!("not a song position"); };
+        assert_eq!(ev.position, (2 << 7) | 5);
+        let MidiMessage::SongPosition(ev) = MidiMessage::decode(&[0xF2, 0x7F, 0x7F]) else { panic!("not a song position"); };
+        assert_eq!(ev.position, 16383);
+        assert!(matches!(MidiMessage::decode(&[0xF2, 0x05]), MidiMessage::Invalid));
+    }
+
+    #[test]
+    fn decode_empty_message() {
+        assert!(matches!(MidiMessage::decode(&[]), MidiMessage::Invalid));
+    }
+
+    #[test]
+    fn decode_lone_status_byte() {
+        for status in [0x80, 0x90, 0xA0, 0xB0, 0xC0, 0xD0, 0xE0, 0xF1, 0xF2, 0xF3] {
+            assert!(matches!(MidiMessage::decode(&[status]), MidiMessage::Invalid), "status {:#04x}", status);
+        }
+        assert!(matches!(MidiMessage::decode(&[0xF8]), MidiMessage::RealTime(MidiRealTimeEvent::TimingClock)));
+    }
+
+    #[test]
+    fn decode_pitch_wheel() {
+        let MidiMessage::PitchWheel(1, ev) = MidiMessage::decode(&[0xE0, 0x00, 0x40]) else { panic!("not a pitch wheel"); };
+        assert_eq!(ev.wheel, 8192);
+        let MidiMessage::PitchWheel(1, ev) = MidiMessage::decode(&[0xE0, 0x7F, 0x7F]) else { panic!("not a pitch wheel"); };
+        assert_eq!(ev.wheel, 16383);
+        let MidiMessage::PitchWheel(1, ev) = MidiMessage::decode(&[0xE0, 0x00, 0x00]) else { panic!("not a pitch wheel"); };
+        assert_eq!(ev.wheel, 0);
+    }
 }
@@ -0,0 +1,37 @@
+use std::error::Error;
+
+use midir::{MidiOutput, MidiOutputConnection};
+
+use super::midi_message::MidiMessage;
+
+// Connection to a MIDI output port, used to send messages to external gear.
+pub struct MidiOut {
+    connection: MidiOutputConnection,
+    port_name: String,
+}
+
+impl MidiOut {
+    pub fn open(port_name: &str) -> Result<Self, Box<dyn Error>> {
+        let midi_out = MidiOutput::new("midir-write-output")?;
+        for port in midi_out.ports() {
+            if midi_out.port_name(&port)? == port_name {
+                let connection = midi_out.connect(&port, "key-synth-out")?;
+                return Ok(MidiOut {
+                    connection,
+                    port_name: port_name.to_owned(),
+                });
+            }
+        }
+        Err(format!("MIDI output port not found: {}", port_name).into())
+    }
+
+    pub fn port_name(&self) -> &str {
+        &self.port_name
+    }
+
+    pub fn send(&mut self, message: &MidiMessage) -> Result<(), Box<dyn Error>> {
+        let data = message.encode().ok_or("message can't be sent")?;
+        self.connection.send(&data)?;
+        Ok(())
+    }
+}
@@ -1,15 +1,27 @@
+// Checks if a MIDI input and output port belong to the same device.
+// Port names include the device (ALSA client) name before the first
+// ':', and the port names of a device are often identical.
+pub fn is_same_device(input_port: &str, output_port: &str) -> bool {
+    input_port.split(':').next() == output_port.split(':').next()
+}
+
 pub struct MidiPorts {
     midi_in: midir::MidiInput,
+    midi_out: midir::MidiOutput,
     port_names: Vec<String>,
+    output_port_names: Vec<String>,
     refresh_time: Option<std::time::Instant>,
 }
 
 impl MidiPorts {
     pub fn open() -> Option<Self> {
         let midi_in = midir::MidiInput::new("MIDI portlist").ok()?;
+        let midi_out = midir::MidiOutput::new("MIDI output portlist").ok()?;
         Some(MidiPorts {
             midi_in,
+            midi_out,
             port_names: Vec::new(),
+            output_port_names: Vec::new(),
             refresh_time: None,
         })
     }
@@ -21,14 +33,31 @@ impl MidiPorts {
             return &self.port_names;
         }
 
-        // refresh the port list
+        self.refresh();
+        &self.port_names
+    }
+
+    pub fn read_output_port_names(&mut self) -> &[String] {
+        if let Some(instant) = self.refresh_time && instant.elapsed().as_secs() <= 10 {
+            return &self.output_port_names;
+        }
+        self.refresh();
+        &self.output_port_names
+    }
+
+    pub fn refresh(&mut self) {
         self.port_names.clear();
         for port in self.midi_in.ports() {
             if let Ok(p) = self.midi_in.port_name(&port) {
                 self.port_names.push(p);
             }
         }
+        self.output_port_names.clear();
+        for port in self.midi_out.ports() {
+            if let Ok(p) = self.midi_out.port_name(&port) {
+                self.output_port_names.push(p);
+            }
+        }
         self.refresh_time = Some(std::time::Instant::now());
-        &self.port_names
     }
 }
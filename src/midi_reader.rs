@@ -1,9 +1,15 @@
 use std::result::Result;
 use std::error::Error;
-use std::sync::mpsc;
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::{Duration, Instant};
 use midir::{MidiInput, MidiInputPort};
 
-use super::midi_message::MidiMessage;
+use super::midi_message::{MidiMessage, MidiEvent, MidiRealTimeEvent, MidiReaderStatus};
+
+// Devices that send active sensing do it every 300ms or so; we allow
+// some slack before considering the device gone.
+const ACTIVE_SENSING_TIMEOUT: Duration = Duration::from_millis(500);
+const ACTIVE_SENSING_CHECK_TIME: Duration = Duration::from_millis(100);
 
 pub struct MidiReaderConfigAcceptedPorts {
     pub accepted_midi_ports: Vec<String>,
@@ -26,7 +32,7 @@ struct MidiConnector {
     sleep_time_millis: u64,
     midi_check: MidiInput,
     command_receiver: mpsc::Receiver<MidiReaderCommand>,
-    midi_sender: mpsc::Sender<MidiMessage>,
+    midi_sender: mpsc::Sender<MidiEvent>,
     connected_port_name: Option<String>,
 }
 
@@ -35,6 +41,74 @@ struct MidiReaderData {
     stop: bool,
 }
 
+// Shared between the MIDI input callback and the reader thread to
+// detect when a device that sends active sensing stops sending data
+// (for example, when the cable is pulled but the port still exists).
+struct ActiveSensing {
+    enabled: bool,
+    lost: bool,
+    last_message: Instant,
+}
+
+impl ActiveSensing {
+    fn new() -> Self {
+        ActiveSensing {
+            enabled: false,
+            lost: false,
+            last_message: Instant::now(),
+        }
+    }
+
+    // Returns true if the device was considered lost and is now back.
+    fn message_received(&mut self, message: &MidiMessage) -> bool {
+        if matches!(message, MidiMessage::RealTime(MidiRealTimeEvent::ActiveSensing)) {
+            self.enabled = true;
+        }
+        self.last_message = Instant::now();
+        let restored = self.lost;
+        self.lost = false;
+        restored
+    }
+
+    // Returns true if the device just timed out.
+    fn check_timeout(&mut self) -> bool {
+        if self.enabled && ! self.lost && self.last_message.elapsed() > ACTIVE_SENSING_TIMEOUT {
+            self.lost = true;
+            return true;
+        }
+        false
+    }
+}
+
+// Converts the timestamps sent by midir (microseconds since an
+// unspecified point in time) to `Instant`s.  We assume the message
+// that arrived the soonest after its timestamp was delivered with no
+// delay, and measure all other timestamps relative to it; this removes
+// the jitter of the delivery of the messages to our callback.
+struct StampClock {
+    base: Option<Instant>,
+}
+
+impl StampClock {
+    fn new() -> Self {
+        StampClock {
+            base: None,
+        }
+    }
+
+    fn stamp_time(&mut self, stamp: u64) -> Instant {
+        let now = Instant::now();
+        let stamp = Duration::from_micros(stamp);
+        if let Some(base) = now.checked_sub(stamp) && self.base.is_none_or(|b| base < b) {
+            self.base = Some(base);
+        }
+        match self.base {
+            Some(base) => base + stamp,
+            None => now,
+        }
+    }
+}
+
 impl MidiConnector {
     fn has_connected_midi_in_port(&self) -> bool {
         if let Some(connected_port_name) = &self.connected_port_name {
@@ -61,14 +135,23 @@ impl MidiConnector {
         Err("no suitable port found".into())
     }
 
+    fn send_status(&self, status: MidiReaderStatus) {
+        self.midi_sender.send(MidiMessage::ReaderStatus(status).into()).unwrap_or(());
+    }
+
     fn run_step(&mut self, data: MidiReaderData) -> MidiReaderData {
         let sleep_time = std::time::Duration::from_millis(self.sleep_time_millis);
 
         // select input port
+        let mut searching = false;
         let (in_port, in_port_name) = loop {
             match self.select_midi_in_port(&data.midi_in) {
                 Ok(v) => break v,
                 Err(_) => {
+                    if ! searching {
+                        self.send_status(MidiReaderStatus::Searching(self.accepted_midi_ports.clone()));
+                        searching = true;
+                    }
                     // error selecting port, sleep and check for commands
                     match self.command_receiver.recv_timeout(sleep_time) {
                         Ok(MidiReaderCommand::Close) | Err(mpsc::RecvTimeoutError::Disconnected) => {
@@ -80,6 +163,7 @@ impl MidiConnector {
 
                         Ok(MidiReaderCommand::ConfigAcceptedPorts(cfg)) => {
                             self.accepted_midi_ports = cfg.accepted_midi_ports;
+                            searching = false;
                         }
 
                         Ok(MidiReaderCommand::ConfigSleepTime(cfg)) => {
@@ -95,20 +179,30 @@ impl MidiConnector {
         };
 
         // connect to selected port
+        self.send_status(MidiReaderStatus::Connecting(in_port_name.clone()));
+        let active_sensing = Arc::new(Mutex::new(ActiveSensing::new()));
         let connect_result = data.midi_in.connect(
             &in_port,
             "midir-read-input",
-            move |_stamp, message, midi_sender| {
+            move |stamp, message, (midi_sender, active_sensing, stamp_clock)| {
                 //println!("data: {:x?}", message);
                 let midi_message = MidiMessage::decode(message);
-                if let Err(e) = midi_sender.send(midi_message) {
+                if active_sensing.lock().unwrap().message_received(&midi_message) {
+                    midi_sender.send(MidiMessage::PortConnected.into()).unwrap_or(());
+                }
+                if matches!(midi_message, MidiMessage::RealTime(MidiRealTimeEvent::ActiveSensing)) {
+                    return;
+                }
+                let event = MidiEvent { time: Some(stamp_clock.stamp_time(stamp)), message: midi_message };
+                if let Err(e) = midi_sender.send(event) {
                     println!("ERROR sending MIDI message: {}", e);
                 }
             },
-            self.midi_sender.clone()
+            (self.midi_sender.clone(), active_sensing.clone(), StampClock::new())
         );
         let midi_in_connection = match connect_result {
             Err(e) => {
+                self.send_status(MidiReaderStatus::Error(e.to_string()));
                 self.connected_port_name = None;
                 std::thread::sleep(sleep_time);
                 return MidiReaderData {
@@ -117,18 +211,21 @@ impl MidiConnector {
                 };
             }
             Ok(conn) => {
+                self.send_status(MidiReaderStatus::Connected(in_port_name.clone()));
                 self.connected_port_name = Some(in_port_name);
-                self.midi_sender.send(MidiMessage::PortConnected).unwrap_or(());
+                self.midi_sender.send(MidiMessage::PortConnected.into()).unwrap_or(());
                 conn
             }
         };
 
         // read commands and monitor the input ports (to check if the selected input port still exists)
+        let mut last_port_check = Instant::now();
         loop {
-            match self.command_receiver.recv_timeout(sleep_time) {
+            let wait_time = if active_sensing.lock().unwrap().enabled { ACTIVE_SENSING_CHECK_TIME } else { sleep_time };
+            match self.command_receiver.recv_timeout(wait_time) {
                 Ok(MidiReaderCommand::Close) | Err(mpsc::RecvTimeoutError::Disconnected) => {
                     // disconnect and exit midi reader
-                    self.midi_sender.send(MidiMessage::PortDisconnected).unwrap_or(());
+                    self.midi_sender.send(MidiMessage::PortDisconnected.into()).unwrap_or(());
                     self.connected_port_name = None;
                     let (midi_in, _) = midi_in_connection.close();
                     return MidiReaderData {
@@ -140,7 +237,7 @@ impl MidiConnector {
                 Ok(MidiReaderCommand::ConfigAcceptedPorts(cfg)) => {
                     // change configuration and disconnect/reconnect
                     self.accepted_midi_ports = cfg.accepted_midi_ports;
-                    self.midi_sender.send(MidiMessage::PortDisconnected).unwrap_or(());
+                    self.midi_sender.send(MidiMessage::PortDisconnected.into()).unwrap_or(());
                     self.connected_port_name = None;
                     let (midi_in, _) = midi_in_connection.close();
                     return MidiReaderData {
@@ -156,9 +253,18 @@ impl MidiConnector {
                 Err(mpsc::RecvTimeoutError::Timeout) => {}           // keep connection going
             }
 
+            // check if the device stopped sending active sensing
+            if active_sensing.lock().unwrap().check_timeout() {
+                self.midi_sender.send(MidiMessage::PortDisconnected.into()).unwrap_or(());
+            }
+
             // check if the connection's MIDI IN still exists
+            if last_port_check.elapsed() < sleep_time {
+                continue;
+            }
+            last_port_check = Instant::now();
             if ! self.has_connected_midi_in_port() {
-                self.midi_sender.send(MidiMessage::PortDisconnected).unwrap_or(());
+                self.midi_sender.send(MidiMessage::PortDisconnected.into()).unwrap_or(());
                 self.connected_port_name = None;
                 let (midi_in, _) = midi_in_connection.close();
                 return MidiReaderData {
@@ -180,7 +286,7 @@ impl MidiConnector {
     }
 }
 
-pub fn start(sleep_time_millis: u64, accepted_midi_ports: &[&str], midi_sender: mpsc::Sender<MidiMessage>)
+pub fn start(sleep_time_millis: u64, accepted_midi_ports: &[&str], midi_sender: mpsc::Sender<MidiEvent>)
              -> Result<mpsc::Sender<MidiReaderCommand>, Box<dyn Error>> {
     let midi_check = MidiInput::new("MIDI check")?;
     let midi_in = MidiInput::new("MIDI in")?;
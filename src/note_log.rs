@@ -0,0 +1,101 @@
+use std::result::Result;
+use std::error::Error;
+use std::io::{BufWriter, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use super::midi_message::{MidiMessage, MidiKeyEvent};
+
+// Header of the CSV file: the time is in seconds since the log started,
+// and the event is "on" or "off".
+const CSV_HEADER: &str = "time,event,key,velocity";
+
+struct NoteLogEntry {
+    time: Instant,
+    on: bool,
+    key: u8,
+    velocity: u8,
+}
+
+// Writes the notes played to a CSV file.  The file is written by its
+// own thread, so logging never makes the MIDI path wait for the disk.
+// The file is closed by `finish()`, which reports any write error.
+pub struct NoteLogger {
+    sender: mpsc::Sender<NoteLogEntry>,
+    writer: thread::JoinHandle<std::io::Result<()>>,
+}
+
+impl NoteLogger {
+    pub fn start(path: &str) -> Result<Self, Box<dyn Error>> {
+        let mut file = BufWriter::new(std::fs::File::create(path)?);
+        writeln!(file, "{}", CSV_HEADER)?;
+        let start = Instant::now();
+        let (sender, receiver) = mpsc::channel::<NoteLogEntry>();
+        let writer = thread::spawn(move || {
+            for entry in receiver {
+                let time = entry.time.saturating_duration_since(start).as_secs_f64();
+                let event = if entry.on { "on" } else { "off" };
+                writeln!(file, "{:.6},{},{},{}", time, event, entry.key, entry.velocity)?;
+            }
+            file.flush()
+        });
+        Ok(NoteLogger { sender, writer })
+    }
+
+    // Stops logging and waits until all the notes are written to the file.
+    pub fn finish(self) -> Result<(), Box<dyn Error>> {
+        drop(self.sender);
+        match self.writer.join() {
+            Ok(result) => Ok(result?),
+            Err(_) => Err("the note log writer crashed".into()),
+        }
+    }
+
+    // Logs the message if it's a note on or off; `time` is when the
+    // message was received, if known.
+    pub fn log(&self, message: &MidiMessage, time: Option<Instant>) {
+        let (on, key, velocity) = match *message {
+            MidiMessage::NoteOn(_, MidiKeyEvent { key, pressure }) => (pressure > 0, key, pressure),
+            MidiMessage::NoteOff(_, MidiKeyEvent { key, pressure }) => (false, key, pressure),
+            _ => { return; }
+        };
+        let time = time.unwrap_or_else(Instant::now);
+        self.sender.send(NoteLogEntry { time, on, key, velocity }).unwrap_or(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::midi_message::MidiControlEvent;
+
+    #[test]
+    fn log_is_written_as_csv() {
+        let path = std::env::temp_dir().join(format!("key-synth-test-{}.csv", std::process::id()));
+        let logger = NoteLogger::start(path.to_str().unwrap()).unwrap();
+        let start = Instant::now();
+        logger.log(&MidiMessage::NoteOn(1, MidiKeyEvent { key: 60, pressure: 100 }), Some(start + Duration::from_millis(500)));
+        logger.log(&MidiMessage::ControlChange(1, MidiControlEvent { control: 64, value: 127 }), None);
+        logger.log(&MidiMessage::NoteOn(1, MidiKeyEvent { key: 60, pressure: 0 }), Some(start + Duration::from_secs(1)));
+        logger.log(&MidiMessage::NoteOff(1, MidiKeyEvent { key: 62, pressure: 64 }), Some(start + Duration::from_secs(2)));
+        logger.finish().unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], CSV_HEADER);
+        let expected = [(0.5, "on", "60", "100"), (1.0, "off", "60", "0"), (2.0, "off", "62", "64")];
+        for (line, (time, event, key, velocity)) in lines[1..].iter().zip(expected) {
+            let fields = line.split(',').collect::<Vec<_>>();
+            assert_eq!(fields.len(), CSV_HEADER.split(',').count(), "{}", line);
+            // the log starts a little before `start`
+            let logged_time = fields[0].parse::<f64>().unwrap();
+            assert!(logged_time >= time && logged_time < time + 0.1, "{}", line);
+            assert_eq!(fields[1..], [event, key, velocity], "{}", line);
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::result::Result;
+use std::error::Error;
+
+use serde::Deserialize;
+
+use super::compressor::CompressorSettings;
+use super::synth_voice::{SynthInstrument, SynthInstrumentOvertone};
+
+const DEFAULT_BANK: &str = include_str!("../assets/gm_patches.ron");
+
+// Settings of the patches that aren't in the bank: a plain additive
+// instrument, with no filter, modulation, effects or compression.
+const PATCH_TEMPLATE: SynthInstrument = SynthInstrument {
+    name: "",
+    program: None,
+    decay: 1.0,
+    decay_key_tracking: 0.0,
+    inharmonicity: 0.0,
+    attack: 0.002,
+    decay_time: 0.0,
+    sustain_level: 1.0,
+    release: 0.3,
+    gain: 1.0,
+    infinite_sustain: false,
+    random_phase: false,
+    analog_drift: 0.0,
+    transient_amount: 0.0,
+    cutoff: SynthInstrument::MAX_CUTOFF,
+    resonance: SynthInstrument::DEFAULT_RESONANCE,
+    lfo_rate: 5.5,
+    sympathetic_resonance: 0.0,
+    pan: 0.0,
+    compressor: CompressorSettings::OFF,
+    overtones: [SynthInstrumentOvertone { frequency: 1.0, loudness: 0.0 }; SynthInstrument::NUM_OVERTONES],
+};
+
+// A patch as written in the bank file (see `assets/gm_patches.ron`).
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Patch {
+    program: u8,        // GM program number, 1-128
+    name: String,
+    decay: f32,
+    release: f32,
+    sustain: bool,
+    overtones: Vec<(f32, f32)>,     // frequency ratio and loudness
+    // optional envelope, missing fields are taken from the template
+    #[serde(default)]
+    attack: Option<f32>,
+    #[serde(default)]
+    decay_time: Option<f32>,
+    #[serde(default)]
+    sustain_level: Option<f32>,
+}
+
+// Instruments selected by MIDI program changes, loaded from a RON
+// patch file.
+pub struct PatchBank {
+    patches: Vec<SynthInstrument>,  // all with a program number
+}
+
+impl PatchBank {
+    pub fn empty() -> Self {
+        PatchBank { patches: Vec::new() }
+    }
+
+    pub fn load_default() -> Result<Self, Box<dyn Error>> {
+        Self::parse(DEFAULT_BANK)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+        let mut patches = Vec::new();
+        let options = ron::Options::default().with_default_extension(ron::extensions::Extensions::IMPLICIT_SOME);
+        for patch in options.from_str::<Vec<Patch>>(text)? {
+            let err = |msg: &str| format!("patch '{}': {}", patch.name, msg);
+            if ! (1..=128).contains(&patch.program) {
+                return Err(err("the program number must be from 1 to 128").into());
+            }
+            if patches.iter().any(|p: &SynthInstrument| p.program == Some(patch.program - 1)) {
+                return Err(err("repeated program number").into());
+            }
+            if patch.attack.is_some_and(|t| t < 0.0) || patch.decay_time.is_some_and(|t| t < 0.0) {
+                return Err(err("envelope times can't be negative").into());
+            }
+            if patch.sustain_level.is_some_and(|l| ! (0.0..=1.0).contains(&l)) {
+                return Err(err("the sustain level must be from 0 to 1").into());
+            }
+            if patch.overtones.len() > SynthInstrument::NUM_OVERTONES {
+                return Err(err("too many overtones").into());
+            }
+            let mut overtones = PATCH_TEMPLATE.overtones;
+            for (overtone, &(frequency, loudness)) in overtones.iter_mut().zip(patch.overtones.iter()) {
+                *overtone = SynthInstrumentOvertone { frequency, loudness };
+            }
+            patches.push(SynthInstrument {
+                // the bank is loaded once, so the names can live until the end of the program
+                name: Box::leak(patch.name.into_boxed_str()),
+                program: Some(patch.program - 1),
+                overtones,
+                decay: patch.decay,
+                release: patch.release,
+                attack: patch.attack.unwrap_or(PATCH_TEMPLATE.attack),
+                decay_time: patch.decay_time.unwrap_or(PATCH_TEMPLATE.decay_time),
+                sustain_level: patch.sustain_level.unwrap_or(PATCH_TEMPLATE.sustain_level),
+                infinite_sustain: patch.sustain,
+                ..PATCH_TEMPLATE
+            });
+        }
+        Ok(PatchBank { patches })
+    }
+
+    pub fn patches(&self) -> &[SynthInstrument] {
+        &self.patches
+    }
+
+    // Returns the instrument for a program number (0-127).  Programs
+    // missing from the bank get another instrument of the same GM
+    // family (groups of 8 programs), if there's one.
+    pub fn instrument(&self, program: u8) -> Option<SynthInstrument> {
+        let family = |p: &SynthInstrument| p.program.is_some_and(|p| p / 8 == program / 8);
+        self.patches.iter().find(|p| p.program == Some(program))
+            .or_else(|| self.patches.iter().find(|p| family(p)))
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(text: &str) -> String {
+        match PatchBank::parse(text) {
+            Ok(_) => panic!("parsed invalid bank: {}", text),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn default_bank_loads() {
+        let bank = PatchBank::load_default().unwrap();
+        assert!(bank.patches().len() >= 12);
+        let piano = bank.instrument(0).unwrap();
+        assert_eq!(piano.name, "Acoustic Grand Piano");
+        assert_eq!(piano.program, Some(0));
+        assert_eq!(piano.compressor, CompressorSettings::OFF);
+
+        // a missing program gets an instrument of its family
+        assert_eq!(bank.instrument(1).unwrap().program, Some(0));
+    }
+
+    #[test]
+    fn patch_fields_are_read() {
+        let bank = PatchBank::parse(r#"[
+            (program: 128, name: "Test", decay: 0.9, release: 0.5, sustain: true, overtones: [(1.0, 1.0), (2.5, 0.25)]),
+        ]"#).unwrap();
+        let patch = bank.instrument(127).unwrap();
+        assert_eq!(patch.name, "Test");
+        assert_eq!(patch.decay, 0.9);
+        assert_eq!(patch.release, 0.5);
+        assert!(patch.infinite_sustain);
+        assert_eq!(patch.overtones[1].frequency, 2.5);
+        assert_eq!(patch.overtones[1].loudness, 0.25);
+        assert_eq!(patch.overtones[2].loudness, 0.0);
+        assert_eq!(patch.attack, PATCH_TEMPLATE.attack);
+        assert_eq!(patch.sustain_level, PATCH_TEMPLATE.sustain_level);
+
+        let bank = PatchBank::parse(r#"[
+            (program: 1, name: "Pad", decay: 1.0, release: 1.0, sustain: true, overtones: [(1.0, 1.0)],
+                attack: 0.4, decay_time: 0.2, sustain_level: 0.6),
+        ]"#).unwrap();
+        let patch = bank.instrument(0).unwrap();
+        assert_eq!(patch.attack, 0.4);
+        assert_eq!(patch.decay_time, 0.2);
+        assert_eq!(patch.sustain_level, 0.6);
+    }
+
+    #[test]
+    fn invalid_banks_are_rejected() {
+        let patch = |program: u32, overtones: &str| format!(
+            "(program: {}, name: \"Bad\", decay: 0.9, release: 0.5, sustain: false, overtones: [{}])", program, overtones);
+        let bank = |patches: &[String]| format!("[{}]", patches.join(", "));
+        assert!(parse_error(&bank(&[patch(0, "(1.0, 1.0)")])).contains("program number"));
+        assert!(parse_error(&bank(&[patch(129, "(1.0, 1.0)")])).contains("program number"));
+        assert!(parse_error(&bank(&[patch(1, "(1.0, 1.0), (2.0, 1.0), (3.0, 1.0), (4.0, 1.0), (5.0, 1.0), (6.0, 1.0)")])).contains("too many overtones"));
+        assert!(parse_error(&bank(&[patch(1, ""), patch(1, "")])).contains("repeated"));
+        assert!(parse_error("[(program: 1, name: \"Bad\", decay: 0.9, release: 0.5, sustain: false, overtones: [], attack: -1.0)]").contains("negative"));
+        assert!(parse_error("[(program: 1, name: \"Bad\", decay: 0.9, release: 0.5, sustain: false, overtones: [], sustain_level: 1.5)]").contains("sustain level"));
+
+        // syntax errors and unknown or missing fields are reported by the parser
+        assert!(! parse_error("[(program: 1,").is_empty());
+        assert!(parse_error("[(program: 1, name: \"Bad\", decay: 0.9, release: 0.5, sustain: false, overtones: [], gain: 1.0)]").contains("gain"));
+        assert!(parse_error("[(program: 1, name: \"Bad\", decay: 0.9, release: 0.5, overtones: [])]").contains("sustain"));
+        assert!(! parse_error(&bank(&[patch(300, "")])).is_empty());
+    }
+}
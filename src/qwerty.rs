@@ -0,0 +1,107 @@
+use std::sync::mpsc;
+
+use super::midi_message::{MidiMessage, MidiEvent, MidiKeyEvent};
+use super::synth::SynthPlayer;
+
+// Velocity of the notes played with the computer keyboard.
+const QWERTY_VELOCITY: u8 = 96;
+
+// Key of the leftmost note at octave shift 0 (C3), and the range of the
+// octave shift, which keeps every note of the layout on the keyboard of
+// the synth.
+const BASE_KEY: i32 = 48;
+const MIN_OCTAVE: i32 = -BASE_KEY / 12;
+const MAX_OCTAVE: i32 = (SynthPlayer::NUM_KEYS as i32 - 1 - BASE_KEY - HIGHEST_OFFSET) / 12;
+const HIGHEST_OFFSET: i32 = 28;
+
+// Keys laid out like a piano, in semitones from the base key: the two
+// lower rows start at C, the two upper rows an octave higher.
+const NOTE_KEYS: &[(egui::Key, i32)] = &[
+    (egui::Key::Z, 0), (egui::Key::S, 1), (egui::Key::X, 2), (egui::Key::D, 3), (egui::Key::C, 4),
+    (egui::Key::V, 5), (egui::Key::G, 6), (egui::Key::B, 7), (egui::Key::H, 8), (egui::Key::N, 9),
+    (egui::Key::J, 10), (egui::Key::M, 11), (egui::Key::Comma, 12), (egui::Key::L, 13),
+    (egui::Key::Period, 14), (egui::Key::Semicolon, 15), (egui::Key::Slash, 16),
+    (egui::Key::Q, 12), (egui::Key::Num2, 13), (egui::Key::W, 14), (egui::Key::Num3, 15), (egui::Key::E, 16),
+    (egui::Key::R, 17), (egui::Key::Num5, 18), (egui::Key::T, 19), (egui::Key::Num6, 20), (egui::Key::Y, 21),
+    (egui::Key::Num7, 22), (egui::Key::U, 23), (egui::Key::I, 24), (egui::Key::Num9, 25), (egui::Key::O, 26),
+    (egui::Key::Num0, 27), (egui::Key::P, HIGHEST_OFFSET),
+];
+
+// Plays notes with the computer keyboard.  The octave is shifted with
+// the - and + keys.
+pub struct QwertyKeyboard {
+    pub enabled: bool,
+    octave: i32,
+    held: Vec<(egui::Key, u8)>,     // keys held down and the notes they play
+}
+
+impl QwertyKeyboard {
+    pub fn new() -> Self {
+        QwertyKeyboard {
+            enabled: false,
+            octave: 0,
+            held: Vec::new(),
+        }
+    }
+
+    pub fn octave(&self) -> i32 {
+        self.octave
+    }
+
+    // Reads the key presses and releases of this frame and sends the
+    // notes they play.
+    pub fn update(&mut self, ctx: &egui::Context, midi_write: &mpsc::Sender<MidiEvent>) {
+        if ! self.enabled { return; }
+        // keys typed in a text field don't play notes (but are still released)
+        let typing = ctx.wants_keyboard_input();
+        let events = ctx.input(|i| i.events.clone());
+        for event in events {
+            let egui::Event::Key { key, pressed, repeat, modifiers, .. } = event else { continue; };
+            if ! pressed {
+                // the note is released even if the octave changed since it was pressed
+                if let Some(index) = self.held.iter().position(|(k, _)| *k == key) {
+                    let (_, note) = self.held.swap_remove(index);
+                    midi_write.send(MidiMessage::NoteOff(1, MidiKeyEvent { key: note, pressure: 0 }).into()).unwrap_or(());
+                }
+                continue;
+            }
+            // held keys repeat, but the note must not be struck again
+            if typing || repeat || modifiers.ctrl || modifiers.alt || modifiers.command { continue; }
+            match key {
+                egui::Key::Minus => { self.octave = (self.octave - 1).max(MIN_OCTAVE); }
+                egui::Key::Plus | egui::Key::Equals => { self.octave = (self.octave + 1).min(MAX_OCTAVE); }
+                _ => {
+                    if self.held.iter().any(|(k, _)| *k == key) { continue; }
+                    let Some((_, offset)) = NOTE_KEYS.iter().find(|(k, _)| *k == key) else { continue; };
+                    let Ok(note) = u8::try_from(BASE_KEY + self.octave * 12 + offset) else { continue; };
+                    self.held.push((key, note));
+                    midi_write.send(MidiMessage::NoteOn(1, MidiKeyEvent { key: note, pressure: QWERTY_VELOCITY }).into()).unwrap_or(());
+                }
+            }
+        }
+    }
+
+    // Releases all notes, since key releases are not seen while the
+    // window is not focused.
+    pub fn release_keys(&mut self, midi_write: &mpsc::Sender<MidiEvent>) {
+        for (_, note) in self.held.drain(..) {
+            midi_write.send(MidiMessage::NoteOff(1, MidiKeyEvent { key: note, pressure: 0 }).into()).unwrap_or(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_octaves_stay_on_the_keyboard() {
+        assert_eq!(NOTE_KEYS.iter().map(|&(_, offset)| offset).max(), Some(HIGHEST_OFFSET));
+        for octave in [MIN_OCTAVE, MAX_OCTAVE] {
+            for &(_, offset) in NOTE_KEYS {
+                let key = BASE_KEY + octave * 12 + offset;
+                assert!((0..SynthPlayer::NUM_KEYS as i32).contains(&key), "key {} at octave {}", key, octave);
+            }
+        }
+    }
+}
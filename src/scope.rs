@@ -0,0 +1,37 @@
+use egui::{Pos2, Color32};
+
+const SCOPE_WINDOW: usize = 1024;
+const SCOPE_BACKGROUND_COLOR: Color32 = Color32::from_rgb(0, 24, 0);
+const SCOPE_AXIS_COLOR: Color32 = Color32::from_rgb(0, 80, 0);
+const SCOPE_TRACE_COLOR: Color32 = Color32::from_rgb(64, 255, 64);
+const SCOPE_CLIP_COLOR: Color32 = Color32::from_rgb(255, 64, 64);
+
+// Find the last rising zero crossing that still leaves a full window
+// of samples after it, so the waveform stays still from frame to frame.
+fn find_trigger(samples: &[i16], window: usize) -> usize {
+    let last = samples.len().saturating_sub(window);
+    (1..=last).rev().find(|&i| samples[i-1] < 0 && samples[i] >= 0).unwrap_or(last)
+}
+
+pub fn show_scope(ui: &mut egui::Ui, samples: &[i16]) {
+    let size = ui.available_size();
+    let (response, painter) = ui.allocate_painter(size, egui::Sense::hover());
+    let rect = response.rect;
+
+    painter.rect_filled(rect, egui::CornerRadius::ZERO, SCOPE_BACKGROUND_COLOR);
+    painter.hline(rect.x_range(), rect.center().y, egui::Stroke::new(1.0, SCOPE_AXIS_COLOR));
+
+    let window = SCOPE_WINDOW.min(samples.len());
+    if window < 2 { return; }
+    let start = find_trigger(samples, window);
+    let samples = &samples[start..start+window];
+
+    let clipping = samples.iter().any(|&s| s == i16::MAX || s == i16::MIN);
+    let color = if clipping { SCOPE_CLIP_COLOR } else { SCOPE_TRACE_COLOR };
+    let points = samples.iter().enumerate().map(|(i, &s)| {
+        let x = rect.min.x + i as f32 * rect.width() / (window - 1) as f32;
+        let y = rect.center().y - s as f32 / i16::MAX as f32 * rect.height() / 2.0;
+        Pos2::new(x, y)
+    }).collect::<Vec<_>>();
+    painter.add(egui::Shape::line(points, egui::Stroke::new(1.0, color)));
+}
@@ -0,0 +1,139 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use egui::{Pos2, Color32};
+
+use super::synth::SynthKeyboard;
+
+const UPDATE_TIME: Duration = Duration::from_millis(200);
+const MIN_FREQUENCY: f32 = 20.0;
+const MAX_FREQUENCY: f32 = 20000.0;
+const MIN_DB: f32 = -100.0;
+const BACKGROUND_COLOR: Color32 = Color32::from_rgb(0, 0, 32);
+const GRID_COLOR: Color32 = Color32::from_rgb(0, 0, 96);
+const TRACE_COLOR: Color32 = Color32::from_rgb(96, 160, 255);
+
+// In-place iterative radix-2 FFT; `re` and `im` must have the same
+// power of two length.
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -std::f32::consts::TAU / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len/2 {
+                let (w_im, w_re) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len/2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+// Returns the magnitude in dBFS of the first half of the spectrum of
+// the (Hann-windowed) samples.
+fn compute_spectrum(samples: &[i16], magnitudes: &mut Vec<f32>) {
+    let n = samples.len().next_power_of_two();
+    let mut re = vec![0.0; n];
+    let mut im = vec![0.0; n];
+    let window_scale = std::f32::consts::TAU / (samples.len() as f32 - 1.0).max(1.0);
+    for (i, &s) in samples.iter().enumerate() {
+        let window = 0.5 - 0.5 * (i as f32 * window_scale).cos();
+        re[i] = s as f32 / i16::MAX as f32 * window;
+    }
+    fft(&mut re, &mut im);
+
+    // a full scale sine has magnitude n/4 with the Hann window
+    let scale = 4.0 / n as f32;
+    magnitudes.clear();
+    magnitudes.extend(re.iter().zip(im.iter()).take(n / 2).map(|(re, im)| {
+        (20.0 * ((re * re + im * im).sqrt() * scale).log10()).max(MIN_DB)
+    }));
+}
+
+pub struct SpectrumAnalyzer {
+    magnitudes: Arc<Mutex<Vec<f32>>>,
+    running: Arc<AtomicBool>,
+    sample_rate: f32,
+}
+
+impl SpectrumAnalyzer {
+    pub fn start(synth: SynthKeyboard, egui_ctx: egui::Context) -> Self {
+        let magnitudes = Arc::new(Mutex::new(Vec::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        let analyzer = SpectrumAnalyzer {
+            magnitudes: magnitudes.clone(),
+            running: running.clone(),
+            sample_rate: synth.get_sample_rate(),
+        };
+        std::thread::spawn(move || {
+            let mut samples = Vec::new();
+            let mut spectrum = Vec::new();
+            while running.load(Ordering::Relaxed) {
+                synth.copy_output(&mut samples);
+                compute_spectrum(&samples, &mut spectrum);
+                magnitudes.lock().unwrap().clone_from(&spectrum);
+                egui_ctx.request_repaint();
+                std::thread::sleep(UPDATE_TIME);
+            }
+        });
+        analyzer
+    }
+
+    pub fn show(&self, ui: &mut egui::Ui) {
+        let size = ui.available_size();
+        let (response, painter) = ui.allocate_painter(size, egui::Sense::hover());
+        let rect = response.rect;
+        painter.rect_filled(rect, egui::CornerRadius::ZERO, BACKGROUND_COLOR);
+
+        // frequency axis is logarithmic, with a line at each decade
+        let log_range = (MAX_FREQUENCY / MIN_FREQUENCY).ln();
+        let freq_x = |freq: f32| rect.min.x + (freq / MIN_FREQUENCY).ln() / log_range * rect.width();
+        for freq in [100.0, 1000.0, 10000.0] {
+            painter.vline(freq_x(freq), rect.y_range(), egui::Stroke::new(1.0, GRID_COLOR));
+        }
+
+        let magnitudes = self.magnitudes.lock().unwrap();
+        if magnitudes.len() < 2 { return; }
+        let bin_freq = self.sample_rate / (2 * magnitudes.len()) as f32;
+        let num_columns = rect.width().max(1.0) as usize;
+        let mut points = Vec::with_capacity(num_columns);
+        for col in 0..num_columns {
+            // take the loudest bin in the frequency range covered by this column
+            let freq0 = MIN_FREQUENCY * (col as f32 / num_columns as f32 * log_range).exp();
+            let freq1 = MIN_FREQUENCY * ((col + 1) as f32 / num_columns as f32 * log_range).exp();
+            let bin0 = ((freq0 / bin_freq) as usize).min(magnitudes.len() - 1);
+            let bin1 = ((freq1 / bin_freq) as usize).clamp(bin0 + 1, magnitudes.len());
+            let db = magnitudes[bin0..bin1].iter().copied().fold(MIN_DB, f32::max);
+            let y = rect.min.y + db / MIN_DB * rect.height();
+            points.push(Pos2::new(rect.min.x + col as f32, y));
+        }
+        painter.add(egui::Shape::line(points, egui::Stroke::new(1.0, TRACE_COLOR)));
+    }
+}
+
+impl Drop for SpectrumAnalyzer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
@@ -1,8 +1,21 @@
+use std::collections::VecDeque;
 use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use super::midi_message::{MidiMessage, MidiKeyEvent};
-use super::synth_voice::{SynthVoice, SynthInstrument};
+use super::midi_message::{MidiMessage, MidiEvent, MidiKeyEvent, MidiControlEvent, MidiPitchEvent, MidiProgramChangeEvent, MidiReaderStatus};
+use super::synth_voice::{SynthVoice, SynthInstrument, InstrumentId};
+use super::tuning::{TuningTable, EQUAL_TEMPERAMENT};
+use super::midi_file::MidiFileEvent;
+use super::midi_file_player::MidiFilePlayer;
+use super::wav_file::{Recording, Recorder};
+use super::note_log::NoteLogger;
+use super::patch_bank::PatchBank;
+use super::effect::{EffectChain, EffectInfo};
+use super::chorus::{Chorus, ChorusSettings};
+use super::bitcrusher::{Bitcrusher, BitcrusherSettings};
+use super::compressor::{Compressor, CompressorSettings};
 
 #[derive(Clone, Copy, Debug)]
 pub struct SynthVoiceIndex(usize);
@@ -14,185 +27,2363 @@ pub enum SynthKeyState {
     VoiceStolen,
 }
 
+// What happens when a key that is still sounding is struck again.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RepeatedNote {
+    Retrigger,  // the same voice ramps to the new volume
+    Layer,      // the old voice is released and a new voice plays the strike
+}
+
+impl RepeatedNote {
+    pub const ALL: [RepeatedNote; 2] = [RepeatedNote::Retrigger, RepeatedNote::Layer];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RepeatedNote::Retrigger => "Retrigger",
+            RepeatedNote::Layer => "Layer",
+        }
+    }
+}
+
+// What happens to the keys held when the MIDI input port disconnects,
+// since their note offs will never arrive.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DisconnectBehavior {
+    Release,    // release all keys
+    Ring,       // let the notes decay naturally, as if held by the damper pedal
+    Hold,       // keep the keys held until they're played again
+}
+
+impl DisconnectBehavior {
+    pub const ALL: [DisconnectBehavior; 3] = [DisconnectBehavior::Release, DisconnectBehavior::Ring, DisconnectBehavior::Hold];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DisconnectBehavior::Release => "Release all",
+            DisconnectBehavior::Ring => "Let ring",
+            DisconnectBehavior::Hold => "Hold",
+        }
+    }
+}
+
+// Snapshot of the state of a voice, for debugging.
+#[derive(Clone, Copy, Debug)]
+pub struct VoiceInfo {
+    pub instrument: &'static str,
+    pub key: u8,
+    pub freq: f32,
+    pub volume: f32,
+    pub active: bool,
+    pub stopping: bool,
+    pub sustained: bool,
+}
+
+// Level of the mix above which the soft clipping starts.
+const SOFT_CLIP_THRESHOLD: f32 = 0.5;
+
+// Controllers with a 14-bit value: CC 0-31 hold the high 7 bits, CC
+// 32-63 the low 7 bits.
+const NUM_HIGH_RES_CONTROLLERS: usize = 32;
+const CC_MODULATION: usize = 1;
+const CC_VOLUME: usize = 7;
+const CC_EXPRESSION: usize = 11;
+const CC_SUSTAIN: usize = 64;
+
+// With half-pedaling, how many times longer the release of the notes
+// is with the sustain pedal almost fully down.
+const HALF_PEDAL_MAX_RELEASE_SCALE: f32 = 10.0;
+const CONTROLLER_MAX: f32 = (127 << 7) as f32;
+
+// Vibrato depth with the modulation wheel all the way up, in cents.
+const MAX_VIBRATO_DEPTH: f32 = 20.0;
+
+// Routing of a controller to the cutoff of the voice filters.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FilterRouting {
+    pub control: Option<u8>,    // controller number (None disables the routing)
+    pub min_cutoff: f32,        // cutoff at controller value 0, in Hz
+    pub max_cutoff: f32,        // cutoff at the maximum controller value, in Hz
+}
+
+impl FilterRouting {
+    pub const DEFAULT: Self = FilterRouting {
+        control: Some(1),       // modulation wheel
+        min_cutoff: 500.0,
+        max_cutoff: SynthInstrument::MAX_CUTOFF,
+    };
+
+    // Returns the cutoff for a controller value from 0 to 1, with an
+    // exponential curve so the sweep sounds even.
+    fn cutoff(&self, value: f32) -> f32 {
+        let min_cutoff = self.min_cutoff.max(1.0);
+        min_cutoff * (self.max_cutoff.max(min_cutoff) / min_cutoff).powf(value.clamp(0.0, 1.0))
+    }
+}
+
+// Linear trim applied to incoming velocities, to match the dynamics of
+// a weak or hot controller.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct VelocityTrim {
+    pub gain: f32,
+    pub offset: i32,
+}
+
+impl VelocityTrim {
+    pub const DEFAULT: Self = VelocityTrim {
+        gain: 1.0,
+        offset: 0,
+    };
+
+    // A velocity of 0 (a note off) is never trimmed.
+    fn apply(&self, velocity: u8) -> u8 {
+        if velocity == 0 { return 0; }
+        (velocity as f32 * self.gain + self.offset as f32).round().clamp(0.0, 127.0) as u8
+    }
+}
+
+// Time constant (in seconds) of the master volume smoothing.
+const VOLUME_SMOOTHING_TIME: f32 = 0.02;
+
+// How long after receiving a timed event the UI should repaint to show
+// it (the event is only applied when the audio is generated).
+const SCHEDULED_REPAINT_DELAY: Duration = Duration::from_millis(50);
+
+// Scheduled events are only applied while audio is generated, so events
+// older than this are applied right away instead of piling up when
+// there's no audio output.
+const MAX_SCHEDULE_DELAY: Duration = Duration::from_secs(1);
+
+// Default minimum time between UI repaints requested by the synth
+// thread (about 60 Hz).
+const DEFAULT_REPAINT_INTERVAL: Duration = Duration::from_micros(16_667);
+
+// Coalesces the repaints requested for incoming MIDI messages, so a
+// dense stream of messages doesn't redraw the UI for every message.
+// A repaint is always requested at most `interval` after a message.
+struct RepaintThrottle {
+    egui_ctx: egui::Context,
+    last_repaint: Option<Instant>,
+}
+
+impl RepaintThrottle {
+    fn new(egui_ctx: egui::Context) -> Self {
+        RepaintThrottle {
+            egui_ctx,
+            last_repaint: None,
+        }
+    }
+
+    fn request(&mut self, interval: Duration) {
+        let now = Instant::now();
+        match self.last_repaint {
+            Some(last) if now < last + interval => {
+                self.egui_ctx.request_repaint_after(last + interval - now);
+            }
+            _ => {
+                self.egui_ctx.request_repaint();
+                self.last_repaint = Some(now);
+            }
+        }
+    }
+}
+
+// Time constant (in seconds) of the smoothing of the correlation between
+// the left and right channels, and the minimum level of the output (in
+// squared sample values) for the correlation to be measured.
+const CORRELATION_SMOOTHING_TIME: f32 = 0.3;
+const CORRELATION_MIN_POWER: f64 = 100.0;
+
+// Note played to preview an instrument.
+const AUDITION_KEY: u8 = 60;
+const AUDITION_VELOCITY: u8 = 80;
+const AUDITION_TIME: f32 = 0.6;
+
+// Velocity of the reference note played to tune other instruments.
+const REFERENCE_VELOCITY: u8 = 64;
+
+// In economy mode the effects are bypassed and fewer voices and
+// overtones are rendered, to keep the audio going on slow machines.
+const ECONOMY_VOICES: usize = 4;
+const ECONOMY_OVERTONES: usize = 3;
+
+// With loudness compensation, the lows (and, less, the highs) are
+// boosted by shelving filters as the master volume goes down, like
+// the "loudness" button of a hi-fi.  At full volume it's flat.
+const LOUDNESS_BASS_FREQ: f32 = 150.0;
+const LOUDNESS_TREBLE_FREQ: f32 = 6000.0;
+const LOUDNESS_MAX_BASS_GAIN: f32 = 1.0;    // +6 dB at zero volume
+const LOUDNESS_MAX_TREBLE_GAIN: f32 = 0.4;
+
+// Rendering a buffer is considered an overload when it takes longer
+// than this fraction of the buffer duration.  With automatic economy
+// mode, economy mode is enabled after this many overloads in a row.
+const OVERLOAD_LOAD: f32 = 0.9;
+const ECONOMY_TRIGGER_OVERLOADS: u32 = 8;
+
+// Time for a stolen voice to fade out while the voice plays its new
+// note, so it doesn't get cut off abruptly.
+const STEAL_FADE_TIME: f32 = 0.005;
+
+struct Audition {
+    voice: usize,
+    frames_left: usize,     // until the note is released
+    reference: bool,        // a reference note plays until it's stopped
+}
+
+// Mix of the voices playing one instrument, compressed before being
+// added to the master mix.  The submixes are allocated with the output
+// format, and each one is free (with no id) until a voice uses it.
+struct Submix {
+    id: Option<InstrumentId>,
+    buffer: Vec<f32>,       // room for a block of `block_size` frames
+    compressor: Compressor,
+    settings: CompressorSettings,
+    used: bool,             // a voice played in the last block
+}
+
+impl Submix {
+    fn new(buffer_len: usize) -> Self {
+        Submix {
+            id: None,
+            buffer: vec![0.0; buffer_len],
+            compressor: Compressor::new(),
+            settings: CompressorSettings::OFF,
+            used: false,
+        }
+    }
+}
+
+struct ScheduledEvent {
+    time: Instant,
+    message: MidiMessage,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SynthKeyStrike {
+    pub velocity: u8,
+    pub time: Instant,
+}
+
 pub struct SynthPlayer {
-    voices: [SynthVoice; SynthPlayer::MAX_VOICES],
+    voices: Vec<SynthVoice>,
+    fading_voices: Vec<SynthVoice>,     // copies of stolen voices, fading out
+    instrument: SynthInstrument,    // instrument played by the keys
     keys: [SynthKeyState; SynthPlayer::NUM_KEYS],
+    strikes: [Option<SynthKeyStrike>; SynthPlayer::NUM_KEYS],
+    tuning: TuningTable,
+    transpose: i32,         // semitones added to incoming keys
+    scheduled: VecDeque<ScheduledEvent>,
+    patch_bank: Arc<PatchBank>,     // instruments selected by program changes
+    edited_instruments: Vec<SynthInstrument>,   // instruments changed in the editor, used when selected again
+    voice_limits: Vec<(InstrumentId, usize)>,   // maximum voices played by each instrument (all voices if not set)
+    audition: Option<Audition>,
+    song: Option<MidiFilePlayer>,
     next_voice: usize,
+    num_voices: usize,      // voices that can be used, fewer than all voices in economy mode
+    start_count: u64,
+    num_channels: usize,
+    sample_rate: f32,
+    block_size: usize,
     midi_connected: bool,
-    volume: f32,
+    reader_status: Option<MidiReaderStatus>,
+    muted: bool,            // keys are still tracked, but the voices don't run
+    economy: bool,          // use less CPU: no effects, fewer voices and overtones
+    auto_economy: bool,     // enable economy mode when the audio can't keep up
+    overloads: u32,         // number of buffers in a row that took too long to render
+    target_volume: f32,     // master volume set by the UI
+    current_volume: f32,    // master volume applied to the mix, ramping to the target
+    velocity_floor: u8,     // incoming velocities are raised to at least this
+    velocity_trim: VelocityTrim,
+    click_smoothing: f32,   // minimum attack and release of all notes, in seconds
+    last_velocity: Option<(u8, u8)>,    // last velocity received and played
+    repeated_note: RepeatedNote,
+    key_range: (u8, u8),    // lowest and highest key played, others are ignored
+    disconnect_behavior: DisconnectBehavior,
+    repaint_interval: Duration,     // minimum time between repaints for MIDI messages
+    max_note_duration: Option<f32>,     // in seconds, notes are released after this
+    max_duration_sustained: bool,       // apply the maximum duration to notes held by the pedal
+    controllers: [u16; NUM_HIGH_RES_CONTROLLERS],
+    high_res_controllers: bool,     // combine CC n and n+32 into 14-bit values
+    sustain: f32,           // sustain pedal position, from 0 (up) to 1 (fully down)
+    pitch_bend: f32,        // position of the pitch wheel, from -1 to 1
+    pitch_bend_range: f32,  // semitones of bend at each end of the pitch wheel
+    mod_depth: f32,         // position of the modulation wheel, from 0 (no vibrato) to 1
+    half_pedaling: bool,    // use the sustain pedal value instead of just on/off
+    filter_routing: FilterRouting,
+    width: f32,             // stereo width: 0 is mono, 1 is the full stereo image
+    key_spread: f32,        // pan of the lowest and highest keys (to the left and right), like a piano
+    force_mono: bool,       // write the same signal to all channels
+    loudness: bool,         // boost the lows and highs at low volumes
+    loudness_state: Vec<(f32, f32)>,    // low-pass filter states (bass, treble) of each channel
+    effects: EffectChain,
+    mix: Vec<f32>,
+    submixes: Vec<Submix>,
+    muted_instruments: Vec<InstrumentId>,   // notes of these instruments are left out of the mix
+    solo_instrument: Option<InstrumentId>,  // when set, notes of the other instruments are left out of the mix
+    clipped: Arc<AtomicBool>,
+    correlation: f32,       // between the first two channels, from -1 (out of phase) to 1 (mono)
+    output: Vec<i16>,       // last OUTPUT_HISTORY samples of the first channel
+    output_pos: usize,
+    recording: Option<Recorder>,    // records everything written to the output
 }
 
 impl SynthPlayer {
-    pub const MAX_VOICES: usize = 8;
+    pub const MAX_VOICES: usize = 64;
+    pub const DEFAULT_VOICES: usize = 8;
     pub const NUM_KEYS: usize = 88;
+    pub const OUTPUT_HISTORY: usize = 2048;
 
-    fn new(num_channels: usize, sample_rate: f32) -> Self {
+    // General MIDI defaults: volume at 100, expression at full and all
+    // other controllers at 0.
+    const DEFAULT_CONTROLLERS: [u16; NUM_HIGH_RES_CONTROLLERS] = {
+        let mut controllers = [0; NUM_HIGH_RES_CONTROLLERS];
+        controllers[CC_VOLUME] = 100 << 7;
+        controllers[CC_EXPRESSION] = 127 << 7;
+        controllers
+    };
+
+    fn new(num_channels: usize, sample_rate: f32, block_size: usize, clipped: Arc<AtomicBool>) -> Self {
         SynthPlayer {
-            voices: [SynthVoice::new(num_channels, sample_rate); SynthPlayer::MAX_VOICES],
+            voices: vec![SynthVoice::new(num_channels, sample_rate); Self::DEFAULT_VOICES],
+            fading_voices: Vec::with_capacity(Self::MAX_VOICES),
+            instrument: SynthInstrument::PIANO,
             keys: [SynthKeyState::Off; Self::NUM_KEYS],
+            strikes: [None; Self::NUM_KEYS],
+            tuning: EQUAL_TEMPERAMENT,
+            transpose: 0,
+            scheduled: VecDeque::new(),
+            patch_bank: Arc::new(PatchBank::empty()),
+            edited_instruments: Vec::new(),
+            voice_limits: Vec::new(),
+            audition: None,
+            song: None,
             next_voice: 0,
+            num_voices: Self::DEFAULT_VOICES,
+            start_count: 0,
+            num_channels,
+            sample_rate,
+            block_size: block_size.max(1),
             midi_connected: false,
-            volume: 0.7,
+            reader_status: None,
+            muted: false,
+            economy: false,
+            auto_economy: false,
+            overloads: 0,
+            target_volume: 0.7,
+            current_volume: 0.7,
+            velocity_floor: 1,
+            velocity_trim: VelocityTrim::DEFAULT,
+            click_smoothing: 0.0,
+            last_velocity: None,
+            repeated_note: RepeatedNote::Retrigger,
+            key_range: (0, Self::NUM_KEYS as u8 - 1),
+            disconnect_behavior: DisconnectBehavior::Release,
+            repaint_interval: DEFAULT_REPAINT_INTERVAL,
+            max_note_duration: None,
+            max_duration_sustained: true,
+            controllers: Self::DEFAULT_CONTROLLERS,
+            high_res_controllers: false,
+            sustain: 0.0,
+            pitch_bend: 0.0,
+            pitch_bend_range: 2.0,
+            mod_depth: 0.0,
+            half_pedaling: false,
+            filter_routing: FilterRouting::DEFAULT,
+            width: 1.0,
+            key_spread: 0.0,
+            force_mono: false,
+            loudness: false,
+            loudness_state: Vec::new(),
+            effects: Self::default_effects(),
+            mix: Vec::with_capacity(block_size.max(1) * num_channels),
+            submixes: Self::new_submixes(num_channels, block_size.max(1)),
+            muted_instruments: Vec::new(),
+            solo_instrument: None,
+            clipped,
+            correlation: 1.0,
+            output: vec![0; Self::OUTPUT_HISTORY],
+            output_pos: 0,
+            recording: None,
         }
     }
 
-    fn get_new_voice(&mut self) -> usize {
+    fn default_effects() -> EffectChain {
+        let mut effects = EffectChain::new();
+        effects.add(Box::new(Chorus::new()), true);
+        effects.add(Box::new(Bitcrusher::new()), true);
+        effects
+    }
+
+    // Maximum number of voices the instrument can play at the same time.
+    fn voice_limit(&self, instrument: InstrumentId) -> usize {
+        let limit = self.voice_limits.iter().find(|(id, _)| *id == instrument).map_or(Self::MAX_VOICES, |(_, limit)| *limit);
+        limit.clamp(1, self.num_voices)
+    }
+
+    fn get_new_voice(&mut self, instrument: InstrumentId) -> usize {
+        // an instrument already playing all the voices it's allowed
+        // steals one of its own voices, so it can't take the voices of
+        // other instruments
+        let playing = self.voices[..self.num_voices].iter().filter(|v| v.active && v.instrument.id() == instrument).count();
+        if playing >= self.voice_limit(instrument) {
+            let voice_index = self.choose_stolen_voice(Some(instrument));
+            self.next_voice = (voice_index + 1) % self.num_voices;
+            return voice_index;
+        }
+
         // if the next voice is available, use it
         if ! self.voices[self.next_voice].active {
             let voice_index = self.next_voice;
-            self.next_voice = (self.next_voice + 1) % Self::MAX_VOICES;
+            self.next_voice = (self.next_voice + 1) % self.num_voices;
             return voice_index;
         }
 
-        // check if any other voice is available; if not, use the next voice anyway
+        // check if any other voice is available; if not, steal one
         let mut voice_index = self.next_voice;
-        for _ in 0..Self::MAX_VOICES {
-            voice_index = (voice_index + 1) % Self::MAX_VOICES;
+        for _ in 0..self.num_voices {
+            voice_index = (voice_index + 1) % self.num_voices;
             if ! self.voices[voice_index].active {
-                break;
+                self.next_voice = (voice_index + 1) % self.num_voices;
+                return voice_index;
+            }
+        }
+        let voice_index = self.choose_stolen_voice(None);
+        self.next_voice = (voice_index + 1) % self.num_voices;
+        voice_index
+    }
+
+    fn choose_stolen_voice(&self, instrument: Option<InstrumentId>) -> usize {
+        // Prefer the audition voice, so played notes are never cut off
+        // by it, then voices that are already releasing, then the
+        // quietest (most decayed) voice, then the oldest one.
+        let audition_voice = self.audition.as_ref().map(|a| a.voice);
+        self.voices[..self.num_voices].iter().enumerate().filter(|(_, v)| {
+            instrument.is_none_or(|id| v.active && v.instrument.id() == id)
+        }).min_by(|(a_index, a), (b_index, b)| {
+            (Some(*b_index) == audition_voice).cmp(&(Some(*a_index) == audition_voice))
+                .then(b.is_stopping().cmp(&a.is_stopping()))
+                .then(a.output_level().total_cmp(&b.output_level()))
+                .then(a.start_count.cmp(&b.start_count))
+        }).map(|(index, _)| index).unwrap_or(self.next_voice)
+    }
+
+    // Gets a new voice to play, stealing one if necessary.
+    fn take_voice(&mut self, instrument: InstrumentId) -> usize {
+        let voice_index = self.get_new_voice(instrument);
+
+        // If the voice was playing a key, mark the key as having the
+        // voice stolen.  A voice that is only releasing belongs to a key
+        // that is already off.  To avoid an audible "pop", a copy of the
+        // stolen voice keeps playing for a moment while it fades out.
+        if self.voices[voice_index].active {
+            if self.fading_voices.len() < self.fading_voices.capacity() {
+                let mut fading = self.voices[voice_index];
+                fading.fade_out(STEAL_FADE_TIME);
+                self.fading_voices.push(fading);
+            }
+            let stolen_key = self.voices[voice_index].key as usize;
+            if let SynthKeyState::Playing(SynthVoiceIndex(stolen_voice)) = self.keys[stolen_key] && stolen_voice == voice_index {
+                self.keys[stolen_key] = SynthKeyState::VoiceStolen;
             }
         }
-        self.next_voice = (voice_index + 1) % Self::MAX_VOICES;
+        self.end_audition(voice_index);
         voice_index
     }
 
+    // Plays a short note with the given instrument, without changing the
+    // instrument of the other voices or the state of the keys.
+    fn audition(&mut self, instrument: SynthInstrument) {
+        let frames = (AUDITION_TIME * self.sample_rate) as usize;
+        self.start_audition(instrument, AUDITION_KEY, AUDITION_VELOCITY, frames);
+    }
+
+    // The audition only uses a free voice, so it never cuts off a note
+    // being played; with no free voice, it doesn't play.
+    fn start_audition(&mut self, instrument: SynthInstrument, key: u8, velocity: u8, frames: usize) {
+        // only one audition plays at a time
+        if let Some(previous) = self.audition.as_ref().map(|a| a.voice) {
+            if ! self.voices[previous].is_stopping() {
+                self.voices[previous].stop();
+            }
+            self.end_audition(previous);
+        }
+        let Some(voice_index) = self.voices[..self.num_voices].iter().position(|v| ! v.active) else { return; };
+        self.start_count += 1;
+        let voice = &mut self.voices[voice_index];
+        voice.instrument = instrument;
+        voice.pan = 0.0;
+        voice.pitch_bend = 0.0;
+        voice.vibrato_depth = 0.0;
+        voice.start(key, velocity, self.tuning[key as usize]);
+        voice.start_count = self.start_count;
+        self.audition = Some(Audition {
+            voice: voice_index,
+            frames_left: frames,
+            reference: false,
+        });
+    }
+
+    // Plays a sine at the frequency of the key in the current tuning,
+    // until `stop_reference` is called.
+    fn start_reference(&mut self, key: u8) {
+        self.stop_reference();
+        self.start_audition(SynthInstrument::REFERENCE_TONE, key, REFERENCE_VELOCITY, usize::MAX);
+        if let Some(audition) = &mut self.audition {
+            audition.reference = true;
+        }
+    }
+
+    fn stop_reference(&mut self) {
+        if let Some(audition) = &mut self.audition && audition.reference {
+            audition.frames_left = 0;
+            audition.reference = false;
+        }
+    }
+
+    fn reference_frequency(&self) -> Option<f32> {
+        let audition = self.audition.as_ref().filter(|a| a.reference)?;
+        Some(self.voices[audition.voice].freq)
+    }
+
+    // Pan of a key with the key spread, from the left for the lowest key
+    // to the right for the highest key.
+    fn key_pan(&self, key: u8) -> f32 {
+        let center = (Self::NUM_KEYS - 1) as f32 / 2.0;
+        (key as f32 - center) / center * self.key_spread
+    }
+
+    // Returns the instrument with the changes made in the editor, if any.
+    fn edited_instrument(&self, instrument: SynthInstrument) -> SynthInstrument {
+        self.edited_instruments.iter().find(|i| i.id() == instrument.id()).copied().unwrap_or(instrument)
+    }
+
+    // Returns the instrument played by the keys, which may not be the
+    // instrument of the audition voice.
+    fn key_instrument(&self) -> &SynthInstrument {
+        &self.instrument
+    }
+
+    // Gives the audition voice back the instrument, pitch bend and
+    // vibrato of the keys.
+    fn end_audition(&mut self, voice_index: usize) {
+        if self.audition.as_ref().is_some_and(|a| a.voice == voice_index) {
+            self.audition = None;
+            let pitch_bend = self.pitch_bend_cents();
+            let vibrato_depth = self.mod_depth * MAX_VIBRATO_DEPTH;
+            let voice = &mut self.voices[voice_index];
+            voice.instrument = self.instrument;
+            voice.pitch_bend = pitch_bend;
+            voice.vibrato_depth = vibrato_depth;
+        }
+    }
+
+    // Voices that play the keys: all but the audition voice, which keeps
+    // its own instrument and is not bent or modulated.
+    fn key_voices_mut(&mut self) -> impl Iterator<Item = &mut SynthVoice> {
+        let audition_voice = self.audition.as_ref().map(|a| a.voice);
+        self.voices.iter_mut().enumerate().filter(move |(index, _)| Some(*index) != audition_voice).map(|(_, voice)| voice)
+    }
+
+    fn update_audition(&mut self, num_frames: usize) {
+        let Some(audition) = &mut self.audition else { return; };
+        let voice_index = audition.voice;
+        if audition.frames_left > num_frames {
+            audition.frames_left -= num_frames;
+        } else if ! self.voices[voice_index].is_stopping() {
+            self.voices[voice_index].stop();
+        }
+        if ! self.voices[voice_index].active {
+            self.end_audition(voice_index);
+        }
+    }
+
     fn play_key(&mut self, key: u8, pressure: u8) {
+        if key < self.key_range.0 || key > self.key_range.1 { return; }
         let key_index = key as usize;
+        let trimmed = self.velocity_trim.apply(pressure).max(self.velocity_floor);
+        self.last_velocity = Some((pressure, trimmed));
+        let pressure = trimmed;
+        self.start_count += 1;
+        self.strikes[key_index] = Some(SynthKeyStrike { velocity: pressure, time: Instant::now() });
 
-        // if this key is already playing, just start it again
+        // if this key is already playing, strike it again or let it
+        // ring under the new strike
         if let SynthKeyState::Playing(SynthVoiceIndex(voice_index)) = self.keys[key_index] {
-            self.voices[voice_index].start(key, pressure, self.volume);
-            return;
+            match self.repeated_note {
+                RepeatedNote::Retrigger => {
+                    self.voices[voice_index].retrigger(pressure);
+                    self.voices[voice_index].start_count = self.start_count;
+                    return;
+                }
+                RepeatedNote::Layer => {
+                    Self::let_ring(&mut self.voices[voice_index]);
+                    self.keys[key_index] = SynthKeyState::Off;
+                }
+            }
         }
 
-        // get a new voice to play
-        let voice_index = self.get_new_voice();
-
-        // If the voice was playing a key, mark the key as having the
-        // voice stolen.  Sadly, this will produce an audible "pop" as
-        // the stolen voice gets cutoff abruptly.
-        if self.voices[voice_index].active {
-            let stolen_key = self.voices[voice_index].key as usize;
-            self.keys[stolen_key] = SynthKeyState::VoiceStolen;
+        // a key released while the pedal is down is still sounding, so
+        // it's struck again like a key that is still held (when layering,
+        // it keeps ringing under the new strike, held by the pedal)
+        if self.repeated_note == RepeatedNote::Retrigger {
+            let audition_voice = self.audition.as_ref().map(|a| a.voice);
+            let sustained_voice = self.voices.iter().enumerate().position(|(index, v)| {
+                v.active && v.sustained && v.key == key && Some(index) != audition_voice
+            });
+            if let Some(voice_index) = sustained_voice {
+                self.voices[voice_index].sustained = false;
+                self.voices[voice_index].retrigger(pressure);
+                self.voices[voice_index].start_count = self.start_count;
+                self.keys[key_index] = SynthKeyState::Playing(SynthVoiceIndex(voice_index));
+                return;
+            }
         }
 
-        // start playing the new voice
-        self.voices[voice_index].start(key, pressure, self.volume);
+        // start playing a new voice
+        let voice_index = self.take_voice(self.key_instrument().id());
+        self.voices[voice_index].instrument = self.instrument;
+        self.voices[voice_index].pan = self.key_pan(key);
+        self.voices[voice_index].start(key, pressure, self.tuning[key_index]);
+        self.voices[voice_index].start_count = self.start_count;
         self.keys[key_index] = SynthKeyState::Playing(SynthVoiceIndex(voice_index));
+        self.excite_held_voices(voice_index);
+    }
+
+    // Adds some energy to the voices of held keys (or keys held by the
+    // pedal) that share overtones with a newly struck voice, like the
+    // undamped strings of a piano.  The level added is a fraction of the
+    // level of the struck voice.
+    fn excite_held_voices(&mut self, struck_index: usize) {
+        let struck = self.voices[struck_index];
+        let amount = struck.instrument.sympathetic_resonance.clamp(0.0, 1.0) * struck.strike_volume;
+        if amount <= 0.0 { return; }
+        let audition_voice = self.audition.as_ref().map(|a| a.voice);
+        for (voice_index, voice) in self.voices.iter_mut().enumerate() {
+            if voice_index == struck_index || Some(voice_index) == audition_voice { continue; }
+            if voice.active && ! voice.is_stopping() {
+                voice.resonate(amount * voice.harmonic_coupling(&struck));
+            }
+        }
     }
 
     fn stop_key(&mut self, key: u8) {
         let key_index = key as usize;
         if let SynthKeyState::Playing(SynthVoiceIndex(voice_index)) = self.keys[key_index] {
-            self.voices[voice_index].stop();
+            let release_scale = self.sustain_release_scale();
+            let voice = &mut self.voices[voice_index];
+            voice.sustained = self.sustain > 0.0;
+            if self.sustain < 1.0 {
+                voice.stop();
+                voice.set_release_scale(release_scale);
+            }
         }
         self.keys[key_index] = SynthKeyState::Off;
     }
 
-    fn set_instrument(&mut self, instrument: SynthInstrument) {
-        for voice in self.voices.iter_mut() {
+    // Releases every key, including notes scheduled to start later and
+    // notes held by the sustain pedal.
+    fn stop_all_keys(&mut self) {
+        self.scheduled.clear();
+        for key in 0..Self::NUM_KEYS {
+            self.stop_key(key as u8);
+        }
+        for voice in self.voices.iter_mut().filter(|v| v.sustained) {
+            voice.sustained = false;
+            if ! voice.is_stopping() {
+                voice.stop();
+            }
+        }
+    }
+
+    // Releases the notes playing for longer than the maximum duration,
+    // in case their note off was lost.
+    fn release_expired_notes(&mut self) {
+        let Some(max_note_duration) = self.max_note_duration else { return; };
+        let max_frames = (max_note_duration * self.sample_rate) as usize;
+        for (voice_index, voice) in self.voices.iter_mut().enumerate() {
+            if ! voice.active || voice.is_stopping() || voice.age < max_frames { continue; }
+            if voice.sustained && ! self.max_duration_sustained { continue; }
+            voice.sustained = false;
+            voice.stop();
+            let key = voice.key as usize;
+            if let Some(SynthKeyState::Playing(SynthVoiceIndex(key_voice))) = self.keys.get(key) && *key_voice == voice_index {
+                self.keys[key] = SynthKeyState::Off;
+            }
+        }
+    }
+
+    // How much longer the release of the notes is with the sustain pedal
+    // partially down.
+    fn sustain_release_scale(&self) -> f32 {
+        1.0 + self.sustain * HALF_PEDAL_MAX_RELEASE_SCALE
+    }
+
+    // The wheel is a 14-bit value centered at 0x2000, which is no bend.
+    fn set_pitch_wheel(&mut self, wheel: u16) {
+        self.pitch_bend = ((wheel as f32 - 8192.0) / 8192.0).clamp(-1.0, 1.0);
+        self.update_pitch_bend();
+    }
+
+    fn pitch_bend_cents(&self) -> f32 {
+        self.pitch_bend * self.pitch_bend_range * 100.0
+    }
+
+    fn update_pitch_bend(&mut self) {
+        let cents = self.pitch_bend_cents();
+        for voice in self.key_voices_mut() {
+            voice.pitch_bend = cents;
+        }
+    }
+
+    // The modulation wheel sets the vibrato depth, unless it's routed to
+    // the filter cutoff (as it is by default), so that a cutoff sweep
+    // doesn't also add vibrato.
+    fn update_vibrato(&mut self) {
+        let mod_wheel_routed = self.filter_routing.control.is_some_and(|c| c as usize == CC_MODULATION);
+        self.mod_depth = if mod_wheel_routed {
+            0.0
+        } else {
+            (self.controllers[CC_MODULATION] as f32 / CONTROLLER_MAX).min(1.0)
+        };
+        let depth = self.mod_depth * MAX_VIBRATO_DEPTH;
+        for voice in self.key_voices_mut() {
+            voice.vibrato_depth = depth;
+        }
+    }
+
+    fn set_sustain(&mut self, value: u8) {
+        self.sustain = if self.half_pedaling {
+            value as f32 / 127.0
+        } else if value >= 64 {
+            1.0
+        } else {
+            0.0
+        };
+
+        // notes held by the pedal are damped according to the new position
+        let release_scale = self.sustain_release_scale();
+        for voice in self.voices.iter_mut().filter(|v| v.sustained) {
+            if self.sustain >= 1.0 { continue; }
+            if ! voice.is_stopping() {
+                voice.stop();
+            }
+            voice.set_release_scale(release_scale);
+            voice.sustained = self.sustain > 0.0;
+        }
+    }
+
+    // Lets the voice of a released key keep sounding as if held by the
+    // pedal (so the pedal can still damp it); only a note that would
+    // never decay is stopped.
+    fn let_ring(voice: &mut SynthVoice) {
+        if voice.instrument.infinite_sustain {
+            if ! voice.is_stopping() {
+                voice.stop();
+            }
+        } else {
+            voice.sustained = true;
+        }
+    }
+
+    fn disconnect_keys(&mut self) {
+        match self.disconnect_behavior {
+            DisconnectBehavior::Release => { self.stop_all_keys(); }
+            DisconnectBehavior::Ring => {
+                // the keys are released without stopping their voices
+                for key in self.keys.iter_mut() {
+                    if let SynthKeyState::Playing(SynthVoiceIndex(voice_index)) = *key {
+                        Self::let_ring(&mut self.voices[voice_index]);
+                    }
+                    *key = SynthKeyState::Off;
+                }
+            }
+            DisconnectBehavior::Hold => {}
+        }
+    }
+
+    fn control_change(&mut self, control: u8, value: u8) {
+        let control = control as usize;
+        let value = (value & 0x7f) as u16;
+        // the high resolution controller updated by this message, if any
+        // (low parts are ignored unless high resolution is enabled)
+        let updated = if control < NUM_HIGH_RES_CONTROLLERS {
+            // a new high part resets the low part
+            self.controllers[control] = value << 7;
+            Some(control)
+        } else if control < 2 * NUM_HIGH_RES_CONTROLLERS && self.high_res_controllers {
+            let control = control - NUM_HIGH_RES_CONTROLLERS;
+            self.controllers[control] = (self.controllers[control] & !0x7f) | value;
+            Some(control)
+        } else {
+            if control == CC_SUSTAIN {
+                self.set_sustain(value as u8);
+            }
+            None
+        };
+
+        if updated == Some(CC_MODULATION) {
+            self.update_vibrato();
+        }
+
+        // update the filter cutoff if the controller is routed to it
+        if let Some(routed) = self.filter_routing.control.map(|c| c as usize) {
+            let routed_value = if routed < NUM_HIGH_RES_CONTROLLERS {
+                (updated == Some(routed)).then(|| self.controllers[routed])
+            } else {
+                (control == routed).then_some(value << 7)
+            };
+            if let Some(routed_value) = routed_value {
+                let cutoff = self.filter_routing.cutoff(routed_value as f32 / CONTROLLER_MAX);
+                self.set_cutoff(cutoff);
+            }
+        }
+    }
+
+    // Changes the key instrument, including the notes it's playing.
+    fn update_instrument(&mut self, update: impl Fn(&mut SynthInstrument)) {
+        let id = self.instrument.id();
+        update(&mut self.instrument);
+        for voice in self.key_voices_mut().filter(|v| v.instrument.id() == id) {
+            let mut instrument = voice.instrument;
+            update(&mut instrument);
             voice.set_instrument(instrument);
         }
     }
 
-    pub fn gen_samples(&mut self, data: &mut [i16]) {
+    fn set_cutoff(&mut self, cutoff: f32) {
+        self.update_instrument(|instrument| instrument.cutoff = cutoff);
+    }
+
+    // Gain set by the volume and expression controllers.
+    fn controller_gain(&self) -> f32 {
+        let volume = (self.controllers[CC_VOLUME] as f32 / CONTROLLER_MAX).min(1.0);
+        let expression = (self.controllers[CC_EXPRESSION] as f32 / CONTROLLER_MAX).min(1.0);
+        volume * expression
+    }
+
+    // Changes the output format when the audio device changes.  Playing
+    // voices are stopped, since they were set up for the old format, but
+    // the instrument and controller state are kept.
+    fn set_output_format(&mut self, num_channels: usize, sample_rate: f32) {
+        if num_channels == self.num_channels && sample_rate == self.sample_rate { return; }
+        self.stop_all_keys();
+        if let Some(voice_index) = self.audition.as_ref().map(|a| a.voice) {
+            self.end_audition(voice_index);
+        }
+        self.fading_voices.clear();
         for voice in self.voices.iter_mut() {
-            if voice.active {
-                voice.gen_samples(data);
-            }
+            voice.set_output_format(num_channels, sample_rate);
         }
+        self.submixes = Self::new_submixes(num_channels, self.block_size);
+        self.mix = Vec::with_capacity(self.block_size * num_channels);
+        self.num_channels = num_channels;
+        self.sample_rate = sample_rate;
     }
-}
 
-#[derive(Clone)]
-pub struct SynthKeyboard {
-    player: Arc<Mutex<SynthPlayer>>,
-}
+    // Changes the instrument played by the keys.  Voices get the
+    // instrument when they start, so notes already playing keep theirs.
+    fn set_instrument(&mut self, instrument: SynthInstrument) {
+        self.instrument = instrument;
+    }
 
-impl SynthKeyboard {
-    pub const NUM_KEYS: usize = SynthPlayer::NUM_KEYS;
+    fn set_click_smoothing(&mut self, click_smoothing: f32) {
+        self.click_smoothing = click_smoothing;
+        for voice in self.voices.iter_mut() {
+            voice.click_smoothing = click_smoothing;
+        }
+    }
 
-    pub fn is_midi_connected(&self) -> bool {
-        self.player.lock().unwrap().midi_connected
+    // Changes the number of voices.  Keys playing voices that are removed
+    // are marked as having their voices stolen, and the removed voices
+    // fade out like stolen voices.
+    fn set_max_voices(&mut self, max_voices: usize) {
+        let max_voices = max_voices.clamp(1, Self::MAX_VOICES);
+        if max_voices < self.voices.len() {
+            for voice in self.voices[max_voices..].iter().filter(|v| v.active) {
+                if self.fading_voices.len() < self.fading_voices.capacity() {
+                    let mut fading = *voice;
+                    fading.fade_out(STEAL_FADE_TIME);
+                    self.fading_voices.push(fading);
+                }
+            }
+            for key in self.keys.iter_mut() {
+                if let SynthKeyState::Playing(SynthVoiceIndex(voice_index)) = *key && voice_index >= max_voices {
+                    *key = SynthKeyState::VoiceStolen;
+                }
+            }
+            if self.audition.as_ref().is_some_and(|a| a.voice >= max_voices) {
+                self.audition = None;
+            }
+        }
+        // new voices get the settings of the other voices and the
+        // instrument played by the keys
+        let mut new_voice = self.voices[0];
+        new_voice.active = false;
+        new_voice.sustained = false;
+        new_voice.set_instrument(self.instrument);
+        self.voices.resize(max_voices, new_voice);
+        self.update_num_voices();
     }
 
-    pub fn set_midi_connected(&self, connected: bool) {
-        self.player.lock().unwrap().midi_connected = connected;
+    fn max_overtones(&self) -> usize {
+        if self.economy { ECONOMY_OVERTONES } else { SynthInstrument::NUM_OVERTONES }
     }
 
-    pub fn get_volume(&self) -> f32 {
-        self.player.lock().unwrap().volume
+    fn update_num_voices(&mut self) {
+        self.num_voices = if self.economy { ECONOMY_VOICES.min(self.voices.len()) } else { self.voices.len() };
+        self.next_voice %= self.num_voices;
     }
 
-    pub fn set_volume(&self, volume: f32) {
-        self.player.lock().unwrap().volume = volume;
+    fn set_economy(&mut self, economy: bool) {
+        self.economy = economy;
+        self.overloads = 0;
+        self.update_num_voices();
+        let max_overtones = self.max_overtones();
+        for (index, voice) in self.voices.iter_mut().enumerate() {
+            voice.max_overtones = max_overtones;
+            if index >= self.num_voices && voice.active {
+                voice.stop();
+            }
+        }
     }
 
-    pub fn play_key(&self, key: u8, pressure: u8) {
-        let key_index = key as usize;
-        if key_index >= Self::NUM_KEYS { return; }
-        let mut player = self.player.lock().unwrap();
-        player.play_key(key, pressure);
+    // Counts the buffers that took too long to render, switching to
+    // economy mode (if enabled) when it happens too often.
+    fn check_overload(&mut self, render_time: Duration, num_frames: usize) {
+        let buffer_duration = num_frames as f32 / self.sample_rate;
+        if render_time.as_secs_f32() > buffer_duration * OVERLOAD_LOAD {
+            self.overloads += 1;
+            if self.auto_economy && ! self.economy && self.overloads >= ECONOMY_TRIGGER_OVERLOADS {
+                self.set_economy(true);
+            }
+        } else {
+            self.overloads = 0;
+        }
     }
 
-    pub fn stop_key(&self, key: u8) {
-        let key_index = key as usize;
-        if key_index >= Self::NUM_KEYS { return; }
-        let mut player = self.player.lock().unwrap();
-        player.stop_key(key);
+    fn schedule(&mut self, time: Instant, message: MidiMessage) {
+        while self.scheduled.front().is_some_and(|event| time.saturating_duration_since(event.time) > MAX_SCHEDULE_DELAY) {
+            if let Some(event) = self.scheduled.pop_front() {
+                self.apply_event(event.message);
+            }
+        }
+        let index = self.scheduled.partition_point(|event| event.time <= time);
+        self.scheduled.insert(index, ScheduledEvent { time, message });
     }
 
-    pub fn copy_keys(&self, keys: &mut [SynthKeyState]) {
-        if keys.len() != Self::NUM_KEYS { return; }
-        let player = self.player.lock().unwrap();
-        keys.clone_from_slice(&player.keys);
+    // Returns the key that sounds when the given MIDI key is played,
+    // or None if it's out of range.
+    fn transposed_key(&self, key: u8) -> Option<u8> {
+        let key = key as i32 + self.transpose;
+        (0..Self::NUM_KEYS as i32).contains(&key).then_some(key as u8)
     }
 
-    pub fn set_instrument(&self, instrument: SynthInstrument) {
-        let mut player = self.player.lock().unwrap();
-        player.set_instrument(instrument);
+    // Applies all the scheduled events right away, when the audio output
+    // stops.
+    fn flush_scheduled(&mut self) {
+        while let Some(event) = self.scheduled.pop_front() {
+            self.apply_event(event.message);
+        }
     }
 
-    pub fn get_player(&self) -> Arc<Mutex<SynthPlayer>> {
-        self.player.clone()
+    fn apply_event(&mut self, message: MidiMessage) {
+        match message {
+            // a note on with velocity 0 is a note off
+            MidiMessage::NoteOn(_, MidiKeyEvent { key, pressure: 0 }) => {
+                if let Some(key) = self.transposed_key(key) {
+                    self.stop_key(key);
+                }
+            }
+            MidiMessage::NoteOn(_, MidiKeyEvent { key, pressure }) => {
+                if let Some(key) = self.transposed_key(key) {
+                    self.play_key(key, pressure);
+                }
+            }
+            MidiMessage::NoteOff(_, MidiKeyEvent { key, .. }) => {
+                if let Some(key) = self.transposed_key(key) {
+                    self.stop_key(key);
+                }
+            }
+            MidiMessage::ControlChange(_, MidiControlEvent { control, value }) => {
+                self.control_change(control, value);
+            }
+            MidiMessage::ProgramChange(_, MidiProgramChangeEvent { number }) => {
+                if let Some(instrument) = self.patch_bank.instrument(number) {
+                    self.set_instrument(self.edited_instrument(instrument));
+                }
+            }
+            MidiMessage::PitchWheel(_, MidiPitchEvent { wheel }) => {
+                self.set_pitch_wheel(wheel);
+            }
+            _ => {}
+        }
+    }
+
+    // Applies the scheduled events due at or before `frame` and returns
+    // the frame of the next scheduled event.  Events are played exactly
+    // one buffer after they were received, so they keep the relative
+    // timing they had when they were played.
+    fn apply_scheduled_events(&mut self, buffer_time: Instant, frame: usize, num_frames: usize) -> usize {
+        let latency = Duration::from_secs_f32(num_frames as f32 / self.sample_rate);
+        while let Some(event) = self.scheduled.front() {
+            let delay = (event.time + latency).saturating_duration_since(buffer_time);
+            let event_frame = (delay.as_secs_f32() * self.sample_rate) as usize;
+            if event_frame > frame {
+                return event_frame;
+            }
+            if let Some(event) = self.scheduled.pop_front() {
+                self.apply_event(event.message);
+            }
+        }
+        usize::MAX
     }
 
-    fn run(&self, midi_read: mpsc::Receiver<MidiMessage>, egui_ctx: egui::Context) {
+    // Applies the song events due at the current frame of the song and
+    // returns the frame of the next song event.
+    fn apply_song_events(&mut self, frame: usize) -> usize {
         loop {
-            while let Ok(msg) = midi_read.try_recv() {
-                match msg {
-                    MidiMessage::PortConnected => {
-                        self.set_midi_connected(true);
-                        egui_ctx.request_repaint();
-                    }
-                    MidiMessage::PortDisconnected => {
-                        self.set_midi_connected(false);
-                        egui_ctx.request_repaint();
-                    }
-                    MidiMessage::NoteOn(_, MidiKeyEvent { key, pressure }) => {
-                        self.play_key(key, pressure);
-                        egui_ctx.request_repaint();
-                    }
-                    MidiMessage::NoteOff(_, MidiKeyEvent { key, .. }) => {
-                        self.stop_key(key);
-                        egui_ctx.request_repaint();
-                    }
-                    _ => {
-                        //println!("-> [{:016x}] {:?}", stamp, msg);
-                    }
-                }
+            let Some(song) = &mut self.song else { return usize::MAX; };
+            let Some(message) = song.take_due_event(self.sample_rate) else {
+                let Some(frames) = song.frames_to_next_event(self.sample_rate) else {
+                    self.song = None;
+                    return usize::MAX;
+                };
+                return frame.saturating_add(frames as usize);
+            };
+            match message {
+                MidiMessage::ControlChange(_, MidiControlEvent { control, value }) => { self.control_change(control, value); }
+                MidiMessage::PitchWheel(_, MidiPitchEvent { wheel }) => { self.set_pitch_wheel(wheel); }
+                // songs play with the instrument selected in the app
+                MidiMessage::ProgramChange(..) => {}
+                message => { self.apply_event(message); }
             }
         }
     }
 
-    pub fn start(midi_read: mpsc::Receiver<MidiMessage>, egui_ctx: egui::Context, num_channels: usize, sample_rate: f32) -> Self {
-        let synth = SynthKeyboard {
-            player: Arc::new(Mutex::new(SynthPlayer::new(num_channels, sample_rate))),
+    fn play_song(&mut self, events: Vec<MidiFileEvent>) {
+        self.stop_song();
+        self.song = Some(MidiFilePlayer::new(events));
+    }
+
+    fn stop_song(&mut self) {
+        if let Some(song) = self.song.take() {
+            for key in song.held_keys() {
+                self.apply_event(MidiMessage::NoteOff(1, MidiKeyEvent { key, pressure: 0 }));
+            }
+        }
+    }
+
+    pub fn gen_samples(&mut self, data: &mut [i16]) {
+        let buffer_time = Instant::now();
+        let num_channels = self.num_channels.max(1);
+        let num_frames = data.len() / num_channels;
+
+        // render in blocks of `block_size` frames (independent of the
+        // device buffer size), splitting blocks at scheduled events
+        let mut frame = 0;
+        while frame < num_frames {
+            let next_event_frame = self.apply_scheduled_events(buffer_time, frame, num_frames);
+            let next_song_frame = self.apply_song_events(frame);
+            let end = (frame + self.block_size).min(next_event_frame).min(next_song_frame).min(num_frames);
+            self.gen_block(&mut data[frame*num_channels..end*num_channels]);
+            if let Some(song) = &mut self.song {
+                song.advance(end - frame);
+            }
+            frame = end;
+        }
+        self.update_audition(num_frames);
+        self.release_expired_notes();
+        self.measure_correlation(data);
+        self.record_output(data);
+        self.check_overload(buffer_time.elapsed(), num_frames);
+    }
+
+    // Returns enough submixes for every voice (including the fading
+    // voices) to play a different instrument.
+    fn new_submixes(num_channels: usize, block_size: usize) -> Vec<Submix> {
+        (0..2 * Self::MAX_VOICES).map(|_| Submix::new(block_size * num_channels)).collect()
+    }
+
+    fn gen_block(&mut self, block: &mut [i16]) {
+        let len = block.len();
+        self.mix.clear();
+        self.mix.resize(len, 0.0);
+        for submix in self.submixes.iter_mut().filter(|m| m.id.is_some()) {
+            submix.buffer[..len].fill(0.0);
+            submix.used = false;
+        }
+        for voice in self.voices.iter_mut().chain(self.fading_voices.iter_mut()) {
+            if self.muted {
+                // released voices end immediately since they're not heard
+                if voice.is_stopping() {
+                    voice.active = false;
+                }
+            } else if voice.active {
+                let id = voice.instrument.id();
+                let index = match self.submixes.iter().position(|m| m.id == Some(id)) {
+                    Some(index) => index,
+                    None => {
+                        let Some(index) = self.submixes.iter().position(|m| m.id.is_none()) else { continue; };
+                        let submix = &mut self.submixes[index];
+                        submix.id = Some(id);
+                        submix.compressor = Compressor::new();
+                        submix.buffer[..len].fill(0.0);
+                        index
+                    }
+                };
+                let submix = &mut self.submixes[index];
+                submix.settings = voice.instrument.compressor;
+                submix.used = true;
+                voice.gen_samples(&mut submix.buffer[..len]);
+            }
+        }
+        self.fading_voices.retain(|v| v.active);
+        // mute and solo only apply to the notes left playing by other
+        // instruments, the instrument played by the keys is always heard
+        let key_id = self.key_instrument().id();
+        for submix in self.submixes.iter_mut().filter(|m| m.id.is_some()) {
+            // the submix of an instrument no longer playing is freed
+            if ! submix.used {
+                submix.id = None;
+                continue;
+            }
+            let buffer = &mut submix.buffer[..len];
+            submix.compressor.process(buffer, self.num_channels, self.sample_rate, &submix.settings);
+            let audible = submix.id == Some(key_id) || match self.solo_instrument {
+                Some(solo) => submix.id == Some(solo),
+                None => ! submix.id.is_some_and(|id| self.muted_instruments.contains(&id)),
+            };
+            if ! audible { continue; }
+            for (out, spl) in self.mix.iter_mut().zip(buffer.iter()) {
+                *out += spl;
+            }
+        }
+        if self.force_mono {
+            self.mix_to_mono();
+        } else {
+            self.apply_width();
+        }
+        if ! self.economy {
+            self.effects.process(&mut self.mix, self.num_channels, self.sample_rate);
+        }
+        self.apply_loudness();
+        self.apply_volume();
+        self.write_mix(block);
+    }
+
+    // Applies the master volume, smoothly moving it towards the target
+    // so fast changes don't produce zipper noise.
+    fn apply_volume(&mut self) {
+        let coef = 1.0 - (-1.0 / (VOLUME_SMOOTHING_TIME * self.sample_rate)).exp();
+        let target_volume = self.target_volume * self.controller_gain();
+        for frame in self.mix.chunks_exact_mut(self.num_channels.max(1)) {
+            self.current_volume += (target_volume - self.current_volume) * coef;
+            for spl in frame.iter_mut() {
+                *spl *= self.current_volume;
+            }
+        }
+    }
+
+    fn apply_loudness(&mut self) {
+        if ! self.loudness { return; }
+        let num_channels = self.num_channels.max(1);
+        self.loudness_state.resize(num_channels, (0.0, 0.0));
+        let amount = (1.0 - self.current_volume).clamp(0.0, 1.0);
+        let bass_gain = amount * LOUDNESS_MAX_BASS_GAIN;
+        let treble_gain = amount * LOUDNESS_MAX_TREBLE_GAIN;
+        let bass_coef = 1.0 - (-std::f32::consts::TAU * LOUDNESS_BASS_FREQ / self.sample_rate).exp();
+        let treble_coef = 1.0 - (-std::f32::consts::TAU * LOUDNESS_TREBLE_FREQ / self.sample_rate).exp();
+        for frame in self.mix.chunks_exact_mut(num_channels) {
+            for (spl, (bass, treble)) in frame.iter_mut().zip(self.loudness_state.iter_mut()) {
+                *bass += (*spl - *bass) * bass_coef;
+                *treble += (*spl - *treble) * treble_coef;
+                *spl += *bass * bass_gain + (*spl - *treble) * treble_gain;
+            }
+        }
+    }
+
+    fn mix_to_mono(&mut self) {
+        let num_channels = self.num_channels.max(1);
+        for frame in self.mix.chunks_exact_mut(num_channels) {
+            let mono = frame.iter().sum::<f32>() / num_channels as f32;
+            frame.fill(mono);
+        }
+    }
+
+    fn apply_width(&mut self) {
+        if self.num_channels != 2 || self.width >= 1.0 { return; }
+        for frame in self.mix.chunks_exact_mut(2) {
+            let mid = (frame[0] + frame[1]) / 2.0;
+            let side = (frame[0] - frame[1]) / 2.0 * self.width;
+            frame[0] = mid + side;
+            frame[1] = mid - side;
+        }
+    }
+
+    // Below the threshold the signal is untouched; above it, it's
+    // smoothly compressed with tanh so it never exceeds full scale.
+    fn soft_clip(val: f32) -> f32 {
+        let excess = val.abs() - SOFT_CLIP_THRESHOLD;
+        if excess <= 0.0 {
+            return val;
+        }
+        let headroom = 1.0 - SOFT_CLIP_THRESHOLD;
+        val.signum() * (SOFT_CLIP_THRESHOLD + headroom * (excess / headroom).tanh())
+    }
+
+    // Convert the mix to the output buffer, flagging if it clips.
+    fn write_mix(&mut self, block: &mut [i16]) {
+        let mut clipped = false;
+        for (out, &val) in block.iter_mut().zip(self.mix.iter()) {
+            clipped |= val.abs() > 1.0;
+            *out = (Self::soft_clip(val) * i16::MAX as f32).round() as i16;
+        }
+        if clipped {
+            self.clipped.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Measures how much the first two channels are alike: a negative
+    // correlation means the mono sum loses level from phase cancellation.
+    fn measure_correlation(&mut self, data: &[i16]) {
+        if self.num_channels < 2 { return; }
+        let (mut lr, mut ll, mut rr) = (0.0, 0.0, 0.0);
+        for frame in data.chunks_exact(self.num_channels) {
+            let (l, r) = (frame[0] as f64, frame[1] as f64);
+            lr += l * r;
+            ll += l * l;
+            rr += r * r;
+        }
+        let num_frames = (data.len() / self.num_channels) as f64;
+        if ll.min(rr) < CORRELATION_MIN_POWER * num_frames { return; }
+        let correlation = (lr / (ll * rr).sqrt()) as f32;
+        let coef = 1.0 - (-(num_frames as f32) / (CORRELATION_SMOOTHING_TIME * self.sample_rate)).exp();
+        self.correlation += (correlation - self.correlation) * coef;
+    }
+
+    fn record_output(&mut self, data: &[i16]) {
+        if let Some(recording) = &mut self.recording {
+            recording.write(data);
+        }
+        for frame in data.chunks_exact(self.num_channels.max(1)) {
+            self.output[self.output_pos] = frame[0];
+            self.output_pos = (self.output_pos + 1) % Self::OUTPUT_HISTORY;
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SynthKeyboard {
+    player: Arc<Mutex<SynthPlayer>>,
+    clipped: Arc<AtomicBool>,
+    thread: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+    note_log: Arc<Mutex<Option<NoteLogger>>>,
+}
+
+impl SynthKeyboard {
+    pub const NUM_KEYS: usize = SynthPlayer::NUM_KEYS;
+    pub const MAX_VOICES: usize = SynthPlayer::MAX_VOICES;
+
+    pub fn is_midi_connected(&self) -> bool {
+        self.player.lock().unwrap().midi_connected
+    }
+
+    pub fn get_correlation(&self) -> f32 {
+        self.player.lock().unwrap().correlation
+    }
+
+    pub fn get_reader_status(&self) -> Option<MidiReaderStatus> {
+        self.player.lock().unwrap().reader_status.clone()
+    }
+
+    pub fn set_midi_connected(&self, connected: bool) {
+        self.player.lock().unwrap().midi_connected = connected;
+    }
+
+    // Returns whether the output clipped since the last call.
+    pub fn take_clipped(&self) -> bool {
+        self.clipped.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn get_sample_rate(&self) -> f32 {
+        self.player.lock().unwrap().sample_rate
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.player.lock().unwrap().muted
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.player.lock().unwrap().muted = muted;
+    }
+
+    pub fn is_economy(&self) -> bool {
+        self.player.lock().unwrap().economy
+    }
+
+    pub fn set_economy(&self, economy: bool) {
+        self.player.lock().unwrap().set_economy(economy);
+    }
+
+    pub fn is_auto_economy(&self) -> bool {
+        self.player.lock().unwrap().auto_economy
+    }
+
+    pub fn set_auto_economy(&self, auto_economy: bool) {
+        self.player.lock().unwrap().auto_economy = auto_economy;
+    }
+
+    pub fn set_output_format(&self, num_channels: usize, sample_rate: f32) {
+        self.player.lock().unwrap().set_output_format(num_channels, sample_rate);
+    }
+
+    pub fn get_volume(&self) -> f32 {
+        self.player.lock().unwrap().target_volume
+    }
+
+    pub fn set_volume(&self, volume: f32) {
+        self.player.lock().unwrap().target_volume = volume;
+    }
+
+    pub fn is_high_res_controllers(&self) -> bool {
+        self.player.lock().unwrap().high_res_controllers
+    }
+
+    pub fn set_high_res_controllers(&self, high_res_controllers: bool) {
+        self.player.lock().unwrap().high_res_controllers = high_res_controllers;
+    }
+
+    pub fn get_pitch_bend(&self) -> f32 {
+        self.player.lock().unwrap().pitch_bend
+    }
+
+    pub fn is_sustain_pedal_down(&self) -> bool {
+        self.player.lock().unwrap().sustain > 0.0
+    }
+
+    pub fn get_pitch_bend_range(&self) -> f32 {
+        self.player.lock().unwrap().pitch_bend_range
+    }
+
+    pub fn set_pitch_bend_range(&self, pitch_bend_range: f32) {
+        let mut player = self.player.lock().unwrap();
+        player.pitch_bend_range = pitch_bend_range.clamp(0.0, 24.0);
+        player.update_pitch_bend();
+    }
+
+    pub fn is_half_pedaling(&self) -> bool {
+        self.player.lock().unwrap().half_pedaling
+    }
+
+    pub fn set_half_pedaling(&self, half_pedaling: bool) {
+        self.player.lock().unwrap().half_pedaling = half_pedaling;
+    }
+
+    pub fn get_max_note_duration(&self) -> Option<f32> {
+        self.player.lock().unwrap().max_note_duration
+    }
+
+    pub fn set_max_note_duration(&self, max_note_duration: Option<f32>) {
+        self.player.lock().unwrap().max_note_duration = max_note_duration;
+    }
+
+    pub fn is_max_duration_sustained(&self) -> bool {
+        self.player.lock().unwrap().max_duration_sustained
+    }
+
+    pub fn set_max_duration_sustained(&self, max_duration_sustained: bool) {
+        self.player.lock().unwrap().max_duration_sustained = max_duration_sustained;
+    }
+
+    pub fn get_filter_routing(&self) -> FilterRouting {
+        self.player.lock().unwrap().filter_routing
+    }
+
+    pub fn set_filter_routing(&self, filter_routing: FilterRouting) {
+        let mut player = self.player.lock().unwrap();
+        player.filter_routing = filter_routing;
+        player.update_vibrato();
+    }
+
+    pub fn get_velocity_floor(&self) -> u8 {
+        self.player.lock().unwrap().velocity_floor
+    }
+
+    pub fn set_velocity_floor(&self, velocity_floor: u8) {
+        self.player.lock().unwrap().velocity_floor = velocity_floor.clamp(1, 127);
+    }
+
+    pub fn get_repeated_note(&self) -> RepeatedNote {
+        self.player.lock().unwrap().repeated_note
+    }
+
+    pub fn set_repeated_note(&self, repeated_note: RepeatedNote) {
+        self.player.lock().unwrap().repeated_note = repeated_note;
+    }
+
+    pub fn get_key_range(&self) -> (u8, u8) {
+        self.player.lock().unwrap().key_range
+    }
+
+    pub fn set_key_range(&self, low: u8, high: u8) {
+        let max_key = SynthPlayer::NUM_KEYS as u8 - 1;
+        let low = low.min(max_key);
+        self.player.lock().unwrap().key_range = (low, high.clamp(low, max_key));
+    }
+
+    pub fn get_disconnect_behavior(&self) -> DisconnectBehavior {
+        self.player.lock().unwrap().disconnect_behavior
+    }
+
+    pub fn set_disconnect_behavior(&self, disconnect_behavior: DisconnectBehavior) {
+        self.player.lock().unwrap().disconnect_behavior = disconnect_behavior;
+    }
+
+    pub fn get_repaint_interval(&self) -> Duration {
+        self.player.lock().unwrap().repaint_interval
+    }
+
+    pub fn set_repaint_interval(&self, repaint_interval: Duration) {
+        self.player.lock().unwrap().repaint_interval = repaint_interval;
+    }
+
+    // The click smoothing is in milliseconds.
+    pub fn get_click_smoothing_ms(&self) -> f32 {
+        self.player.lock().unwrap().click_smoothing * 1000.0
+    }
+
+    pub fn set_click_smoothing_ms(&self, click_smoothing_ms: f32) {
+        self.player.lock().unwrap().set_click_smoothing(click_smoothing_ms.max(0.0) / 1000.0);
+    }
+
+    pub fn get_velocity_trim(&self) -> VelocityTrim {
+        self.player.lock().unwrap().velocity_trim
+    }
+
+    pub fn set_velocity_trim(&self, velocity_trim: VelocityTrim) {
+        self.player.lock().unwrap().velocity_trim = velocity_trim;
+    }
+
+    // Returns the last velocity received and the velocity played after
+    // the trim and the floor.
+    pub fn get_last_velocity(&self) -> Option<(u8, u8)> {
+        self.player.lock().unwrap().last_velocity
+    }
+
+    pub fn is_instrument_muted(&self, id: InstrumentId) -> bool {
+        self.player.lock().unwrap().muted_instruments.contains(&id)
+    }
+
+    // Mutes the notes still playing with an instrument that's no longer
+    // played by the keys.
+    pub fn set_instrument_muted(&self, id: InstrumentId, muted: bool) {
+        let mut player = self.player.lock().unwrap();
+        player.muted_instruments.retain(|&i| i != id);
+        if muted {
+            player.muted_instruments.push(id);
+        }
+    }
+
+    pub fn get_solo_instrument(&self) -> Option<InstrumentId> {
+        self.player.lock().unwrap().solo_instrument
+    }
+
+    pub fn set_solo_instrument(&self, id: Option<InstrumentId>) {
+        self.player.lock().unwrap().solo_instrument = id;
+    }
+
+    pub fn is_loudness(&self) -> bool {
+        self.player.lock().unwrap().loudness
+    }
+
+    pub fn set_loudness(&self, loudness: bool) {
+        let mut player = self.player.lock().unwrap();
+        player.loudness = loudness;
+        player.loudness_state.clear();
+    }
+
+    pub fn is_force_mono(&self) -> bool {
+        self.player.lock().unwrap().force_mono
+    }
+
+    pub fn set_force_mono(&self, force_mono: bool) {
+        self.player.lock().unwrap().force_mono = force_mono;
+    }
+
+    pub fn get_chorus(&self) -> ChorusSettings {
+        self.player.lock().unwrap().effects.get::<Chorus>().map(|c| c.settings).unwrap_or(ChorusSettings::DEFAULT)
+    }
+
+    pub fn set_chorus(&self, settings: ChorusSettings) {
+        if let Some(chorus) = self.player.lock().unwrap().effects.get_mut::<Chorus>() {
+            chorus.settings = settings;
+        }
+    }
+
+    pub fn get_bitcrusher(&self) -> BitcrusherSettings {
+        self.player.lock().unwrap().effects.get::<Bitcrusher>().map(|b| b.settings).unwrap_or(BitcrusherSettings::DEFAULT)
+    }
+
+    pub fn set_bitcrusher(&self, settings: BitcrusherSettings) {
+        if let Some(bitcrusher) = self.player.lock().unwrap().effects.get_mut::<Bitcrusher>() {
+            bitcrusher.settings = settings;
+        }
+    }
+
+    pub fn get_effects(&self) -> Vec<EffectInfo> {
+        self.player.lock().unwrap().effects.info()
+    }
+
+    pub fn set_effect_enabled(&self, index: usize, enabled: bool) {
+        self.player.lock().unwrap().effects.set_enabled(index, enabled);
+    }
+
+    pub fn move_effect_up(&self, index: usize) {
+        self.player.lock().unwrap().effects.move_up(index);
+    }
+
+    pub fn get_width(&self) -> f32 {
+        self.player.lock().unwrap().width
+    }
+
+    pub fn set_width(&self, width: f32) {
+        self.player.lock().unwrap().width = width.clamp(0.0, 1.0);
+    }
+
+    pub fn get_key_spread(&self) -> f32 {
+        self.player.lock().unwrap().key_spread
+    }
+
+    pub fn set_key_spread(&self, key_spread: f32) {
+        self.player.lock().unwrap().key_spread = key_spread.clamp(0.0, 1.0);
+    }
+
+    pub fn audition(&self, instrument: SynthInstrument) {
+        self.player.lock().unwrap().audition(instrument);
+    }
+
+    pub fn start_reference(&self, key: u8) {
+        self.player.lock().unwrap().start_reference(key.min(Self::NUM_KEYS as u8 - 1));
+    }
+
+    pub fn stop_reference(&self) {
+        self.player.lock().unwrap().stop_reference();
+    }
+
+    // Returns the frequency of the reference note, if it's playing.
+    pub fn get_reference_frequency(&self) -> Option<f32> {
+        self.player.lock().unwrap().reference_frequency()
+    }
+
+    pub fn get_transpose(&self) -> i32 {
+        self.player.lock().unwrap().transpose
+    }
+
+    // Held keys are released, since their note off would be transposed
+    // to a different key.
+    pub fn set_transpose(&self, transpose: i32) {
+        let mut player = self.player.lock().unwrap();
+        if player.transpose != transpose {
+            player.stop_all_keys();
+            player.transpose = transpose;
+        }
+    }
+
+    pub fn stop_all_keys(&self) {
+        self.player.lock().unwrap().stop_all_keys();
+    }
+
+    // Returns the number of active voices and the total number of voices.
+    pub fn get_voice_usage(&self) -> (usize, usize) {
+        let player = self.player.lock().unwrap();
+        (player.voices.iter().filter(|v| v.active).count(), player.num_voices)
+    }
+
+    pub fn get_voice_info(&self) -> Vec<VoiceInfo> {
+        let player = self.player.lock().unwrap();
+        player.voices.iter().map(|voice| VoiceInfo {
+            instrument: voice.instrument.name,
+            key: voice.key,
+            freq: voice.freq,
+            volume: voice.volume,
+            active: voice.active,
+            stopping: voice.is_stopping(),
+            sustained: voice.sustained,
+        }).collect()
+    }
+
+    pub fn copy_keys(&self, keys: &mut [SynthKeyState]) {
+        if keys.len() != Self::NUM_KEYS { return; }
+        let player = self.player.lock().unwrap();
+        keys.clone_from_slice(&player.keys);
+    }
+
+    pub fn copy_key_strikes(&self, strikes: &mut [Option<SynthKeyStrike>]) {
+        if strikes.len() != Self::NUM_KEYS { return; }
+        let player = self.player.lock().unwrap();
+        strikes.clone_from_slice(&player.strikes);
+    }
+
+    // Copies the level (0 to 1) of the voice playing each key relative
+    // to the volume of its strike, so the level also drops while a
+    // decaying note is held.
+    pub fn copy_key_volume_levels(&self, levels: &mut [f32]) {
+        if levels.len() != Self::NUM_KEYS { return; }
+        let player = self.player.lock().unwrap();
+        levels.fill(0.0);
+        for voice in player.voices.iter().filter(|v| v.active) {
+            if let Some(level) = levels.get_mut(voice.key as usize) {
+                *level = level.max(voice.volume_level());
+            }
+        }
+    }
+
+    // Copies the envelope level (0 to 1) of the voice playing each key.
+    pub fn copy_key_levels(&self, levels: &mut [f32]) {
+        if levels.len() != Self::NUM_KEYS { return; }
+        let player = self.player.lock().unwrap();
+        levels.fill(0.0);
+        for voice in player.voices.iter().filter(|v| v.active) {
+            if let Some(level) = levels.get_mut(voice.key as usize) {
+                *level = level.max(voice.envelope_level());
+            }
+        }
+    }
+
+    pub fn copy_output(&self, output: &mut Vec<i16>) {
+        let player = self.player.lock().unwrap();
+        output.clear();
+        output.extend_from_slice(&player.output[player.output_pos..]);
+        output.extend_from_slice(&player.output[..player.output_pos]);
+    }
+
+    pub fn get_patch_bank(&self) -> Arc<PatchBank> {
+        self.player.lock().unwrap().patch_bank.clone()
+    }
+
+    pub fn get_instrument(&self) -> SynthInstrument {
+        *self.player.lock().unwrap().key_instrument()
+    }
+
+    // Changes the settings of the instrument played by the keys, which
+    // are kept in their valid ranges.
+    pub fn update_instrument(&self, update: impl Fn(&mut SynthInstrument)) {
+        self.player.lock().unwrap().update_instrument(|instrument| {
+            update(instrument);
+            instrument.limit_settings();
+        });
+    }
+
+    pub fn set_instrument(&self, instrument: SynthInstrument) {
+        let mut player = self.player.lock().unwrap();
+        player.set_instrument(instrument);
+    }
+
+    pub fn edited_instrument(&self, instrument: SynthInstrument) -> SynthInstrument {
+        self.player.lock().unwrap().edited_instrument(instrument)
+    }
+
+    // Plays the instrument changed in the editor, and keeps the changes
+    // for when it's selected again (also by program changes).
+    pub fn set_edited_instrument(&self, instrument: SynthInstrument) {
+        let mut player = self.player.lock().unwrap();
+        player.edited_instruments.retain(|i| i.id() != instrument.id());
+        player.edited_instruments.push(instrument);
+        player.update_instrument(|i| *i = instrument);
+    }
+
+    // Returns the maximum number of voices the instrument can play.
+    pub fn get_voice_limit(&self, instrument: InstrumentId) -> usize {
+        self.player.lock().unwrap().voice_limit(instrument)
+    }
+
+    pub fn set_voice_limit(&self, instrument: InstrumentId, limit: usize) {
+        let mut player = self.player.lock().unwrap();
+        player.voice_limits.retain(|(id, _)| *id != instrument);
+        player.voice_limits.push((instrument, limit.clamp(1, Self::MAX_VOICES)));
+    }
+
+    pub fn discard_instrument_edits(&self, instrument: InstrumentId) {
+        self.player.lock().unwrap().edited_instruments.retain(|i| i.id() != instrument);
+    }
+
+    pub fn get_max_voices(&self) -> usize {
+        self.player.lock().unwrap().voices.len()
+    }
+
+    pub fn set_max_voices(&self, max_voices: usize) {
+        self.player.lock().unwrap().set_max_voices(max_voices);
+    }
+
+    pub fn set_tuning(&self, tuning: TuningTable) {
+        self.player.lock().unwrap().tuning = tuning;
+    }
+
+    // Plays a song in the synth, with the events timed by the audio
+    // output.  A song that was already playing is stopped.
+    pub fn play_song(&self, events: Vec<MidiFileEvent>) {
+        self.player.lock().unwrap().play_song(events);
+    }
+
+    pub fn stop_song(&self) {
+        self.player.lock().unwrap().stop_song();
+    }
+
+    pub fn is_song_playing(&self) -> bool {
+        self.player.lock().unwrap().song.is_some()
+    }
+
+    pub fn start_recording(&self) {
+        let mut player = self.player.lock().unwrap();
+        player.recording = Some(Recorder::start(player.num_channels, player.sample_rate as u32));
+    }
+
+    pub fn stop_recording(&self) -> Option<Recording> {
+        let recorder = self.player.lock().unwrap().recording.take()?;
+        Some(recorder.finish())
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.player.lock().unwrap().recording.is_some()
+    }
+
+    pub fn start_note_log(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        *self.note_log.lock().unwrap() = Some(NoteLogger::start(path)?);
+        Ok(())
+    }
+
+    // Stops logging notes and waits until the log file is written.
+    pub fn stop_note_log(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let note_log = self.note_log.lock().unwrap().take();
+        note_log.map_or(Ok(()), |note_log| note_log.finish())
+    }
+
+    pub fn is_note_logging(&self) -> bool {
+        self.note_log.lock().unwrap().is_some()
+    }
+
+    pub fn get_player(&self) -> Arc<Mutex<SynthPlayer>> {
+        self.player.clone()
+    }
+
+    fn schedule(&self, time: Instant, message: MidiMessage) {
+        self.player.lock().unwrap().schedule(time, message);
+    }
+
+    pub fn flush_scheduled(&self) {
+        self.player.lock().unwrap().flush_scheduled();
+    }
+
+    // Waits for the synth thread to finish after a `MidiMessage::Shutdown`.
+    pub fn join(&self) {
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            thread.join().unwrap_or(());
+        }
+    }
+
+    fn run(&self, midi_read: mpsc::Receiver<MidiEvent>, egui_ctx: egui::Context) {
+        let mut repaint = RepaintThrottle::new(egui_ctx.clone());
+
+        // sleep until a message arrives (messages that are already
+        // queued are returned immediately, so bursts are handled promptly)
+        while let Ok(event) = midi_read.recv() {
+            if let Some(note_log) = &*self.note_log.lock().unwrap() {
+                note_log.log(&event.message, event.time);
+            }
+            let channel_message = matches!(event.message, MidiMessage::NoteOn(..) | MidiMessage::NoteOff(..) |
+                                           MidiMessage::ControlChange(..) | MidiMessage::ProgramChange(..) | MidiMessage::PitchWheel(..));
+            if channel_message {
+                match event.time {
+                    // messages received from a device are played with
+                    // sample-accurate timing, keeping their order
+                    Some(time) => {
+                        self.schedule(time, event.message);
+                        egui_ctx.request_repaint_after(SCHEDULED_REPAINT_DELAY);
+                    }
+                    None => {
+                        self.player.lock().unwrap().apply_event(event.message);
+                        repaint.request(self.get_repaint_interval());
+                    }
+                }
+                continue;
+            }
+            match event.message {
+                MidiMessage::Shutdown => {
+                    return;
+                }
+                MidiMessage::PortConnected => {
+                    self.set_midi_connected(true);
+                    egui_ctx.request_repaint();
+                }
+                MidiMessage::ReaderStatus(status) => {
+                    self.player.lock().unwrap().reader_status = Some(status);
+                    egui_ctx.request_repaint();
+                }
+                MidiMessage::PortDisconnected => {
+                    // no note offs will arrive from the old port
+                    self.player.lock().unwrap().disconnect_keys();
+                    self.set_midi_connected(false);
+                    egui_ctx.request_repaint();
+                }
+                _ => {
+                    //println!("-> [{:016x}] {:?}", stamp, msg);
+                }
+            }
+        }
+    }
+
+    pub fn start(midi_read: mpsc::Receiver<MidiEvent>, egui_ctx: egui::Context,
+                 num_channels: usize, sample_rate: f32, block_size: usize) -> Self {
+        let clipped = Arc::new(AtomicBool::new(false));
+        let mut player = SynthPlayer::new(num_channels, sample_rate, block_size, clipped.clone());
+        player.patch_bank = Arc::new(PatchBank::load_default().unwrap_or_else(|e| {
+            println!("ERROR loading patch bank: {}", e);
+            PatchBank::empty()
+        }));
+        let synth = SynthKeyboard {
+            player: Arc::new(Mutex::new(player)),
+            clipped,
+            thread: Arc::new(Mutex::new(None)),
+            note_log: Arc::new(Mutex::new(None)),
         };
         let synth_clone = synth.clone();
-        thread::spawn(move || {
+        let thread = thread::spawn(move || {
             synth_clone.run(midi_read, egui_ctx);
         });
+        *synth.thread.lock().unwrap() = Some(thread);
         synth
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48000.0;
+
+    fn test_player(num_channels: usize) -> SynthPlayer {
+        SynthPlayer::new(num_channels, SAMPLE_RATE, 64, Arc::new(AtomicBool::new(false)))
+    }
+
+    fn render(player: &mut SynthPlayer, num_frames: usize) -> Vec<i16> {
+        let mut data = vec![0; num_frames * player.num_channels];
+        player.gen_samples(&mut data);
+        data
+    }
+
+    fn key_voice(player: &SynthPlayer, key: u8) -> Option<usize> {
+        match player.keys[key as usize] {
+            SynthKeyState::Playing(SynthVoiceIndex(voice_index)) => Some(voice_index),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn new_notes_use_free_voices() {
+        let mut player = test_player(1);
+        for key in 0..SynthPlayer::DEFAULT_VOICES as u8 {
+            player.play_key(40 + key, 100);
+        }
+        let mut voices = (0..SynthPlayer::DEFAULT_VOICES as u8).filter_map(|key| key_voice(&player, 40 + key)).collect::<Vec<_>>();
+        voices.sort();
+        voices.dedup();
+        assert_eq!(voices.len(), SynthPlayer::DEFAULT_VOICES);
+    }
+
+    #[test]
+    fn stealing_prefers_releasing_voices() {
+        let mut player = test_player(1);
+        for key in 0..SynthPlayer::DEFAULT_VOICES as u8 {
+            player.play_key(40 + key, 100);
+        }
+        render(&mut player, 256);
+        let released_voice = key_voice(&player, 43).unwrap();
+        player.stop_key(43);
+        player.play_key(60, 100);
+        assert_eq!(key_voice(&player, 60), Some(released_voice));
+        for key in (0..SynthPlayer::DEFAULT_VOICES as u8).map(|key| 40 + key).filter(|&key| key != 43) {
+            assert!(key_voice(&player, key).is_some(), "key {} lost its voice", key);
+        }
+    }
+
+    #[test]
+    fn stealing_prefers_oldest_voice_at_same_level() {
+        let mut player = test_player(1);
+        player.set_instrument(SynthInstrument::ORGAN);
+        for key in 0..SynthPlayer::DEFAULT_VOICES as u8 {
+            player.play_key(40 + key, 100);
+        }
+        render(&mut player, 4800);
+        let oldest_voice = key_voice(&player, 40).unwrap();
+        player.play_key(60, 100);
+        assert_eq!(key_voice(&player, 60), Some(oldest_voice));
+        assert!(matches!(player.keys[40], SynthKeyState::VoiceStolen));
+    }
+
+    #[test]
+    fn stale_scheduled_events_are_applied() {
+        let mut player = test_player(1);
+        let start = Instant::now();
+        player.schedule(start, MidiMessage::NoteOn(1, MidiKeyEvent { key: 40, pressure: 100 }));
+        player.schedule(start, MidiMessage::ControlChange(1, MidiControlEvent { control: CC_SUSTAIN as u8, value: 127 }));
+        assert!(key_voice(&player, 40).is_none());
+        player.schedule(start + MAX_SCHEDULE_DELAY * 2, MidiMessage::NoteOff(1, MidiKeyEvent { key: 40, pressure: 0 }));
+        assert!(key_voice(&player, 40).is_some());
+        assert_eq!(player.sustain, 1.0);
+        assert_eq!(player.scheduled.len(), 1);
+    }
+
+    #[test]
+    fn centered_pitch_wheel_does_not_bend() {
+        let mut player = test_player(1);
+        player.play_key(60, 100);
+        let voice = key_voice(&player, 60).unwrap();
+        player.apply_event(MidiMessage::PitchWheel(1, MidiPitchEvent { wheel: 0x3fff }));
+        assert!(player.voices[voice].pitch_bend > 0.0);
+        player.apply_event(MidiMessage::PitchWheel(1, MidiPitchEvent { wheel: 0x2000 }));
+        assert_eq!(player.pitch_bend, 0.0);
+        assert_eq!(player.voices[voice].pitch_bend, 0.0);
+        player.apply_event(MidiMessage::PitchWheel(1, MidiPitchEvent { wheel: 0 }));
+        assert_eq!(player.pitch_bend, -1.0);
+    }
+
+    #[test]
+    fn songs_bend_the_pitch_but_keep_the_instrument() {
+        let mut player = test_player(1);
+        player.patch_bank = Arc::new(PatchBank::load_default().unwrap());
+        let instrument = player.voices[0].instrument.name;
+        let event = |secs: f64, message: MidiMessage| MidiFileEvent { time: Duration::from_secs_f64(secs), message };
+        player.play_song(vec![
+            event(0.0, MidiMessage::ProgramChange(1, MidiProgramChangeEvent { number: 40 })),
+            event(0.0, MidiMessage::NoteOn(1, MidiKeyEvent { key: 40, pressure: 100 })),
+            event(0.01, MidiMessage::PitchWheel(1, MidiPitchEvent { wheel: 0 })),
+            event(0.5, MidiMessage::NoteOff(1, MidiKeyEvent { key: 40, pressure: 0 })),
+        ]);
+        render(&mut player, 4800);
+        assert_eq!(player.pitch_bend, -1.0);
+        assert!(player.voices.iter().all(|v| v.instrument.name == instrument));
+    }
+
+    #[test]
+    fn note_on_with_zero_velocity_releases_key() {
+        let mut player = test_player(1);
+        player.velocity_floor = 20;
+        player.apply_event(MidiMessage::NoteOn(1, MidiKeyEvent { key: 40, pressure: 100 }));
+        let voice_index = key_voice(&player, 40).unwrap();
+        player.apply_event(MidiMessage::NoteOn(1, MidiKeyEvent { key: 40, pressure: 0 }));
+        assert!(key_voice(&player, 40).is_none());
+        assert!(player.voices[voice_index].is_stopping());
+        player.apply_event(MidiMessage::NoteOn(1, MidiKeyEvent { key: 41, pressure: 0 }));
+        assert!(key_voice(&player, 41).is_none());
+    }
+
+    #[test]
+    fn volume_controller_pair_combines_to_14_bits() {
+        let mut player = test_player(1);
+        player.high_res_controllers = true;
+        player.control_change(CC_VOLUME as u8, 100);
+        player.control_change((CC_VOLUME + NUM_HIGH_RES_CONTROLLERS) as u8, 64);
+        assert_eq!(player.controllers[CC_VOLUME], (100 << 7) | 64);
+        assert!((player.controller_gain() - ((100 << 7) | 64) as f32 / CONTROLLER_MAX).abs() < 1e-6);
+
+        // a new high part resets the low part
+        player.control_change(CC_VOLUME as u8, 90);
+        assert_eq!(player.controllers[CC_VOLUME], 90 << 7);
+
+        // without high resolution controllers the low part is ignored
+        player.high_res_controllers = false;
+        player.control_change((CC_VOLUME + NUM_HIGH_RES_CONTROLLERS) as u8, 64);
+        assert_eq!(player.controllers[CC_VOLUME], 90 << 7);
+    }
+
+    #[test]
+    fn audition_does_not_take_held_voices() {
+        let mut player = test_player(1);
+        for key in 0..SynthPlayer::DEFAULT_VOICES as u8 - 1 {
+            player.play_key(40 + key, 100);
+        }
+        player.audition(SynthInstrument::BELL);
+        let audition_voice = player.audition.as_ref().map(|a| a.voice).unwrap();
+        assert!(player.keys.iter().all(|key| ! matches!(key, SynthKeyState::Playing(SynthVoiceIndex(v)) if *v == audition_voice)));
+
+        // with all voices busy, the audition doesn't play
+        player.play_key(60, 100);
+        player.audition(SynthInstrument::VIBRAPHONE);
+        assert!(player.audition.is_none());
+        for key in (0..SynthPlayer::DEFAULT_VOICES as u8 - 1).map(|key| 40 + key).chain([60]) {
+            assert!(key_voice(&player, key).is_some(), "key {} lost its voice", key);
+        }
+    }
+
+    #[test]
+    fn output_format_change_keeps_controller_state() {
+        let mut player = test_player(1);
+        player.set_economy(true);
+        player.set_pitch_wheel(16383);
+        player.filter_routing.control = None;
+        player.control_change(CC_MODULATION as u8, 127);
+        player.set_instrument(SynthInstrument::ORGAN);
+        player.update_instrument(|instrument| instrument.release = 1.5);
+        player.set_click_smoothing(0.005);
+        player.play_key(40, 100);
+        let played_voice = key_voice(&player, 40).unwrap();
+        player.audition(SynthInstrument::BELL);
+        player.set_output_format(2, 44100.0);
+        assert_eq!(player.key_instrument().name, SynthInstrument::ORGAN.name);
+        assert_eq!(player.key_instrument().release, 1.5);
+        assert_eq!(player.voices[played_voice].instrument.name, SynthInstrument::ORGAN.name);
+        assert_eq!(player.voices[played_voice].instrument.release, 1.5);
+        for voice in player.voices.iter() {
+            assert_eq!(voice.num_channels, 2);
+            assert_eq!(voice.sample_rate, 44100.0);
+            assert!(! voice.active);
+            assert_eq!(voice.max_overtones, ECONOMY_OVERTONES);
+            assert!(voice.pitch_bend > 0.0);
+            assert!(voice.vibrato_depth > 0.0);
+            assert_eq!(voice.click_smoothing, 0.005);
+        }
+        assert!(player.audition.is_none());
+        assert_eq!(player.num_voices, ECONOMY_VOICES);
+        assert!(player.fading_voices.is_empty());
+    }
+
+    #[test]
+    fn ringing_notes_are_released_by_the_pedal() {
+        let mut player = test_player(1);
+        player.disconnect_behavior = DisconnectBehavior::Ring;
+        player.play_key(40, 100);
+        let voice_index = key_voice(&player, 40).unwrap();
+        player.disconnect_keys();
+        assert!(key_voice(&player, 40).is_none());
+        assert!(player.voices[voice_index].active && ! player.voices[voice_index].is_stopping());
+
+        // pressing and releasing the pedal damps the ringing note
+        player.set_sustain(127);
+        player.set_sustain(0);
+        assert!(player.voices[voice_index].is_stopping());
+    }
+
+    #[test]
+    fn song_events_are_sample_accurate() {
+        let mut player = test_player(2);
+        let note = |secs: f64, key: u8| MidiFileEvent {
+            time: Duration::from_secs_f64(secs),
+            message: MidiMessage::NoteOn(1, MidiKeyEvent { key, pressure: 100 }),
+        };
+        player.play_song(vec![note(0.01, 40), note(0.0251, 45), note(0.5, 50)]);
+        let expected_frames = [(40, 480), (45, 1205), (50, 24000)];
+
+        // buffers that don't line up with the events or the block size
+        let num_frames = 30000;
+        let mut frame = 0;
+        while frame < num_frames {
+            let frames = 441.min(num_frames - frame);
+            render(&mut player, frames);
+            frame += frames;
+        }
+        for (key, event_frame) in expected_frames {
+            let voice = &player.voices[key_voice(&player, key).unwrap()];
+            assert_eq!(voice.age, num_frames - event_frame, "key {}", key);
+        }
+    }
+
+    #[test]
+    fn struck_notes_excite_notes_held_by_the_pedal() {
+        // returns the volume of a note held by the pedal after another
+        // note is struck an octave above (sharing its overtones)
+        let held_volume = |sympathetic_resonance: f32, velocity: u8| {
+            let mut player = test_player(1);
+            player.update_instrument(|instrument| instrument.sympathetic_resonance = sympathetic_resonance);
+            player.set_sustain(127);
+            player.play_key(36, 100);
+            let held_voice = key_voice(&player, 36).unwrap();
+            player.stop_key(36);
+            render(&mut player, 4800);
+            assert!(player.voices[held_voice].sustained);
+            player.play_key(48, velocity);
+            render(&mut player, 4800);
+            player.voices[held_voice].volume
+        };
+        let resonance = 0.05;
+        let added = held_volume(resonance, 127) - held_volume(0.0, 127);
+        assert!(added > 0.0 && added <= resonance, "added {}", added);
+
+        // softer strikes add less
+        let soft_added = held_volume(resonance, 32) - held_volume(0.0, 32);
+        assert!(soft_added > 0.0 && soft_added < 0.5 * added, "added {} by a soft strike", soft_added);
+    }
+
+    #[test]
+    fn velocity_trim_keeps_zero_velocity() {
+        let trim = VelocityTrim { gain: 1.5, offset: 10 };
+        assert_eq!(trim.apply(0), 0);
+        assert_eq!(trim.apply(20), 40);
+        assert_eq!(trim.apply(120), 127);
+        let trim = VelocityTrim { gain: 1.0, offset: -30 };
+        assert_eq!(trim.apply(20), 0);
+        assert_eq!(VelocityTrim::DEFAULT.apply(64), 64);
+    }
+
+    #[test]
+    fn audition_with_a_single_voice_keeps_key_instrument() {
+        let mut player = test_player(1);
+        player.set_instrument(SynthInstrument::ORGAN);
+        player.set_max_voices(1);
+        player.audition(SynthInstrument::BELL);
+        assert_eq!(player.audition.as_ref().map(|a| a.voice), Some(0));
+        assert_eq!(player.key_instrument().name, SynthInstrument::ORGAN.name);
+
+        // stealing the audition voice gives it back the key instrument
+        player.play_key(60, 100);
+        assert!(player.audition.is_none());
+        assert_eq!(player.voices[0].instrument.name, SynthInstrument::ORGAN.name);
+
+        player.set_max_voices(4);
+        assert!(player.voices.iter().all(|v| v.instrument.name == SynthInstrument::ORGAN.name));
+    }
+
+    #[test]
+    fn removed_voices_fade_out() {
+        let mut player = test_player(1);
+        for key in 0..SynthPlayer::DEFAULT_VOICES as u8 {
+            player.play_key(40 + key, 100);
+        }
+        player.set_max_voices(2);
+        assert_eq!(player.fading_voices.len(), SynthPlayer::DEFAULT_VOICES - 2);
+        assert!(player.fading_voices.iter().all(|v| v.is_stopping()));
+        render(&mut player, (2.0 * STEAL_FADE_TIME * SAMPLE_RATE) as usize);
+        assert!(player.fading_voices.is_empty());
+    }
+
+    #[test]
+    fn stealing_takes_the_quietest_voice() {
+        let mut player = test_player(1);
+        player.set_instrument(SynthInstrument::ORGAN);
+        let quietest_key = 43;
+        for key in 0..SynthPlayer::DEFAULT_VOICES as u8 {
+            let velocity = if 40 + key == quietest_key { 20 } else { 60 + 5 * key };
+            player.play_key(40 + key, velocity);
+        }
+        render(&mut player, 4800);
+        let quietest_voice = key_voice(&player, quietest_key).unwrap();
+        player.play_key(60, 100);
+        assert_eq!(key_voice(&player, 60), Some(quietest_voice));
+        assert!(matches!(player.keys[quietest_key as usize], SynthKeyState::VoiceStolen));
+        for key in (0..SynthPlayer::DEFAULT_VOICES as u8).map(|key| 40 + key).filter(|&key| key != quietest_key) {
+            assert!(key_voice(&player, key).is_some(), "key {} lost its voice", key);
+        }
+
+        // a copy of the stolen voice fades out
+        assert_eq!(player.fading_voices.len(), 1);
+        assert_eq!(player.fading_voices[0].key, quietest_key);
+        assert!(player.fading_voices[0].is_stopping());
+    }
+
+    #[test]
+    fn program_change_plays_the_edited_instrument() {
+        let mut player = test_player(1);
+        player.patch_bank = Arc::new(PatchBank::load_default().unwrap());
+        let patch = player.patch_bank.instrument(0).unwrap();
+        player.edited_instruments.push(SynthInstrument { release: 2.5, ..patch });
+        player.apply_event(MidiMessage::ProgramChange(1, MidiProgramChangeEvent { number: 0 }));
+        assert_eq!(player.key_instrument().name, patch.name);
+        assert_eq!(player.key_instrument().release, 2.5);
+
+        // a patch with the name of a preset doesn't get the preset's edits
+        let patch = player.patch_bank.patches().iter().find(|p| p.name == SynthInstrument::VIBRAPHONE.name).copied().unwrap();
+        player.edited_instruments.push(SynthInstrument { release: 3.5, ..SynthInstrument::VIBRAPHONE });
+        player.apply_event(MidiMessage::ProgramChange(1, MidiProgramChangeEvent { number: patch.program.unwrap() }));
+        assert_eq!(player.key_instrument().id(), patch.id());
+        assert_eq!(player.key_instrument().release, patch.release);
+    }
+
+    #[test]
+    fn mod_wheel_routed_to_the_filter_adds_no_vibrato() {
+        let mut player = test_player(1);
+        assert_eq!(player.filter_routing.control, Some(CC_MODULATION as u8));
+        player.control_change(CC_MODULATION as u8, 0);
+        let closed_cutoff = player.instrument.cutoff;
+        player.control_change(CC_MODULATION as u8, 127);
+        assert!(player.instrument.cutoff > closed_cutoff);
+        assert_eq!(player.mod_depth, 0.0);
+        assert!(player.voices.iter().all(|v| v.vibrato_depth == 0.0));
+
+        // routed elsewhere, the wheel sets the vibrato depth again
+        player.filter_routing.control = Some(74);
+        player.update_vibrato();
+        assert_eq!(player.mod_depth, 1.0);
+        assert!(player.voices.iter().all(|v| v.vibrato_depth == MAX_VIBRATO_DEPTH));
+    }
+
+    #[test]
+    fn lone_modulation_low_part_adds_no_vibrato() {
+        let mut player = test_player(1);
+        player.filter_routing.control = None;
+        player.control_change((CC_MODULATION + NUM_HIGH_RES_CONTROLLERS) as u8, 127);
+        assert_eq!(player.mod_depth, 0.0);
+        assert_eq!(player.controllers[CC_MODULATION], 0);
+
+        // with high resolution controllers it only sets the low part
+        player.high_res_controllers = true;
+        player.control_change((CC_MODULATION + NUM_HIGH_RES_CONTROLLERS) as u8, 127);
+        assert_eq!(player.controllers[CC_MODULATION], 127);
+        assert!(player.mod_depth < 0.01);
+    }
+
+    #[test]
+    fn instrument_updates_reach_playing_voices() {
+        let mut player = test_player(1);
+        player.play_key(60, 100);
+        player.update_instrument(|instrument| instrument.release = 2.0);
+        assert_eq!(player.key_instrument().release, 2.0);
+        assert_eq!(player.voices[key_voice(&player, 60).unwrap()].instrument.release, 2.0);
+
+        let mut instrument = SynthInstrument::PIANO;
+        instrument.resonance = 0.0;
+        instrument.limit_settings();
+        assert_eq!(instrument.resonance, SynthInstrument::MIN_RESONANCE);
+    }
+
+    #[test]
+    fn audition_voice_is_not_bent_or_edited() {
+        let mut player = test_player(1);
+        player.filter_routing.control = None;
+        player.start_reference(69);
+        let reference_voice = player.audition.as_ref().map(|a| a.voice).unwrap();
+        let reference_frequency = player.reference_frequency().unwrap();
+        player.set_pitch_wheel(16383);
+        player.control_change(CC_MODULATION as u8, 127);
+        player.update_instrument(|instrument| instrument.cutoff = 500.0);
+        render(&mut player, 480);
+        let voice = &player.voices[reference_voice];
+        assert_eq!(voice.pitch_bend, 0.0);
+        assert_eq!(voice.vibrato_depth, 0.0);
+        assert_eq!(voice.instrument.cutoff, SynthInstrument::REFERENCE_TONE.cutoff);
+        assert_eq!(player.reference_frequency(), Some(reference_frequency));
+        assert!(player.voices.iter().enumerate().filter(|(i, _)| *i != reference_voice).all(|(_, v)| v.pitch_bend > 0.0 && v.instrument.cutoff == 500.0));
+
+        // when the reference ends, its voice plays the keys again
+        player.stop_reference();
+        render(&mut player, 4800);
+        render(&mut player, 4800);
+        assert!(player.audition.is_none());
+        let voice = &player.voices[reference_voice];
+        assert!(voice.pitch_bend > 0.0);
+        assert_eq!(voice.vibrato_depth, MAX_VIBRATO_DEPTH);
+        assert_eq!(voice.instrument.cutoff, 500.0);
+    }
+
+    #[test]
+    fn submixes_are_reused_without_allocating() {
+        let mut player = test_player(2);
+        let buffers = player.submixes.iter().map(|m| m.buffer.as_ptr()).collect::<Vec<_>>();
+        let in_use = |player: &SynthPlayer| player.submixes.iter().filter(|m| m.id.is_some()).count();
+        player.set_instrument(SynthInstrument::ORGAN);
+        player.play_key(40, 100);
+        player.set_instrument(SynthInstrument::PIANO);
+        player.play_key(60, 100);
+        render(&mut player, 480);
+        assert_eq!(in_use(&player), 2);
+        assert!(player.submixes.iter().map(|m| m.buffer.as_ptr()).eq(buffers.iter().copied()));
+
+        // the submixes are freed when their instruments stop playing
+        player.stop_key(40);
+        player.stop_key(60);
+        render(&mut player, 48000);
+        assert_eq!(in_use(&player), 0);
+
+        player.set_output_format(1, SAMPLE_RATE);
+        assert!(player.submixes.iter().all(|m| m.buffer.len() == 64));
+    }
+
+    #[test]
+    fn mute_and_solo_apply_to_notes_of_other_instruments() {
+        let mut player = test_player(1);
+        let audible = |player: &mut SynthPlayer| render(player, 480).iter().any(|&spl| spl != 0);
+        player.set_instrument(SynthInstrument::ORGAN);
+        player.play_key(60, 100);
+
+        // the instrument played by the keys can't be muted
+        player.muted_instruments.push(SynthInstrument::ORGAN.id());
+        assert!(audible(&mut player));
+
+        // but its notes are muted after another instrument is selected
+        player.set_instrument(SynthInstrument::PIANO);
+        assert!(! audible(&mut player));
+        player.muted_instruments.clear();
+        assert!(audible(&mut player));
+        player.solo_instrument = Some(SynthInstrument::BELL.id());
+        assert!(! audible(&mut player));
+        player.solo_instrument = Some(SynthInstrument::ORGAN.id());
+        assert!(audible(&mut player));
+    }
+
+    #[test]
+    fn voice_limit_steals_from_the_same_instrument() {
+        let mut player = test_player(1);
+        player.voice_limits.push((SynthInstrument::PIANO.id(), 2));
+        player.set_instrument(SynthInstrument::ORGAN);
+        player.play_key(40, 100);
+        player.play_key(41, 100);
+        player.set_instrument(SynthInstrument::PIANO);
+        for key in [60, 62, 64] {
+            player.play_key(key, 100);
+            render(&mut player, 480);
+        }
+
+        // the oldest piano note is stolen, while the organ keeps playing
+        assert!(matches!(player.keys[60], SynthKeyState::VoiceStolen));
+        for key in [62, 64] {
+            assert_eq!(player.voices[key_voice(&player, key).unwrap()].instrument.name, SynthInstrument::PIANO.name);
+        }
+        for key in [40, 41] {
+            assert_eq!(player.voices[key_voice(&player, key).unwrap()].instrument.name, SynthInstrument::ORGAN.name);
+        }
+        let playing = |name: &str| player.voices.iter().filter(|v| v.active && ! v.is_stopping() && v.instrument.name == name).count();
+        assert_eq!(playing(SynthInstrument::PIANO.name), 2);
+        assert_eq!(playing(SynthInstrument::ORGAN.name), 2);
+    }
+
+    #[test]
+    fn layered_strike_lets_the_previous_note_ring() {
+        let mut player = test_player(1);
+        player.repeated_note = RepeatedNote::Layer;
+        player.play_key(60, 100);
+        let first_voice = key_voice(&player, 60).unwrap();
+        render(&mut player, 4800);
+        player.play_key(60, 100);
+        let second_voice = key_voice(&player, 60).unwrap();
+        assert_ne!(first_voice, second_voice);
+        render(&mut player, 4800);
+        let first = &player.voices[first_voice];
+        assert!(first.active && ! first.is_stopping() && first.sustained);
+
+        // the pedal still damps the ringing note
+        player.set_sustain(127);
+        player.set_sustain(0);
+        assert!(player.voices[first_voice].is_stopping());
+        assert!(! player.voices[second_voice].is_stopping());
+    }
+
+    #[test]
+    fn layered_strike_lets_the_pedaled_note_ring() {
+        let mut player = test_player(1);
+        player.repeated_note = RepeatedNote::Layer;
+        player.set_sustain(127);
+        player.play_key(60, 100);
+        let first_voice = key_voice(&player, 60).unwrap();
+        player.stop_key(60);
+        render(&mut player, 4800);
+        player.play_key(60, 100);
+        let second_voice = key_voice(&player, 60).unwrap();
+        assert_ne!(first_voice, second_voice);
+        render(&mut player, 4800);
+        let first = &player.voices[first_voice];
+        assert!(first.active && ! first.is_stopping() && first.sustained);
+
+        // releasing the pedal damps the ringing note but not the held key
+        player.set_sustain(0);
+        assert!(player.voices[first_voice].is_stopping());
+        assert!(! player.voices[second_voice].is_stopping());
+    }
+}
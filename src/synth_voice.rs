@@ -1,19 +1,120 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use super::compressor::CompressorSettings;
+
+// Peak level of a single voice at full volume (-12 dBFS, where 1.0 is
+// full scale), which leaves some headroom for chords.
+const VOICE_PEAK_LEVEL: f32 = 0.25;
+
+// The decay of an instrument is the level kept every `DECAY_TIME`
+// seconds (1024 frames at 48 kHz).
+const DECAY_TIME: f32 = 1024.0 / 48000.0;
+
+// Time to ramp to the new volume when a playing voice is struck again.
+const RETRIGGER_RAMP_TIME: f32 = 0.005;
+
+// Time for the pitch to follow a change of the pitch wheel.
+const PITCH_BEND_SMOOTHING_TIME: f32 = 0.01;
+
+// Time (in seconds) for the analog drift to wander to a new detune.
+const DRIFT_TIME: f32 = 2.0;
+
+// State of the (xorshift) random generator for the start phases and
+// the analog drift.
+static RANDOM_STATE: AtomicU32 = AtomicU32::new(0x2545_f491);
+
+// Time for a held voice to build up a sympathetic resonance, and the
+// largest frequency ratio between two overtones considered in tune
+// (about 17 cents).
+const RESONANCE_RAMP_TIME: f32 = 0.05;
+const RESONANCE_TOLERANCE: f32 = 0.01;
+
+// Length of the noise burst at the start of a note, and the smoothing
+// coefficient of the low-pass filter applied to it.
+const TRANSIENT_TIME: f32 = 0.008;
+const TRANSIENT_FILTER: f32 = 0.3;
+
+fn xorshift(mut x: u32) -> u32 {
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    x
+}
+
+// Returns a random number between 0 and 1.
+fn random() -> f32 {
+    let x = xorshift(RANDOM_STATE.load(Ordering::Relaxed));
+    RANDOM_STATE.store(x, Ordering::Relaxed);
+    (x >> 8) as f32 / (1 << 24) as f32
+}
+
 #[derive(Clone, Copy)]
 pub struct SynthInstrumentOvertone {
-    frequency: f32,
-    loudness: f32,
+    pub frequency: f32,     // relative to the note frequency
+    pub loudness: f32,
+}
+
+// Identifies an instrument: the built-in presets by name, and the
+// patches of the bank by program number, since their names can be the
+// same as the name of a preset.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InstrumentId {
+    Preset(&'static str),
+    Patch(u8),
 }
 
 #[derive(Clone, Copy)]
 pub struct SynthInstrument {
+    pub name: &'static str,
+    pub program: Option<u8>,    // GM program (0-127) of a patch from the bank, None for the presets
     pub overtones: [SynthInstrumentOvertone; SynthInstrument::NUM_OVERTONES],
-    pub decay: f32,
+    pub decay: f32,         // level kept every DECAY_TIME (about 21 ms)
+    pub decay_key_tracking: f32,    // how much faster the decay is per octave above middle C
+    pub inharmonicity: f32,     // stretches the upper overtones like stiff piano strings (0 keeps exact ratios)
+    pub attack: f32,        // seconds to rise to full level when the note starts
+    pub decay_time: f32,    // seconds to fall from full level to the sustain level after the attack
+    pub sustain_level: f32, // level held after the decay (0 to 1), still fading with `decay`
+    pub release: f32,       // seconds to fade out after the key is released
+    pub gain: f32,          // relative to VOICE_PEAK_LEVEL
+    pub infinite_sustain: bool,
+    pub random_phase: bool,     // start the overtones at random phases
+    pub analog_drift: f32,      // maximum random detune in cents
+    pub transient_amount: f32,  // level of the noise burst at note start, relative to the tone
+    pub cutoff: f32,            // low-pass filter cutoff in Hz
+    pub resonance: f32,         // low-pass filter Q, boosting the frequencies around the cutoff
+    pub lfo_rate: f32,          // vibrato frequency in Hz (the depth is set by the modulation wheel)
+    pub sympathetic_resonance: f32,     // level added to held notes by related struck notes, relative to the struck note (0 disables it)
+    pub pan: f32,               // from -1 (left) to 1 (right)
+    pub compressor: CompressorSettings,     // applied to the submix of the instrument
 }
 
 impl SynthInstrument {
-    const NUM_OVERTONES: usize = 5;
+    pub const NUM_OVERTONES: usize = 5;
+    pub const MAX_CUTOFF: f32 = 20000.0;    // at this cutoff the filter is bypassed
+    pub const DEFAULT_RESONANCE: f32 = std::f32::consts::FRAC_1_SQRT_2;     // no boost at the cutoff
+    pub const MIN_RESONANCE: f32 = 0.5;
+    pub const MAX_RESONANCE: f32 = 10.0;
     pub const PIANO: Self = SynthInstrument {
+        name: "Piano",
+        program: None,
         decay: 0.95,
+        decay_key_tracking: 0.25,
+        inharmonicity: 0.0004,
+        attack: 0.002,
+        decay_time: 0.3,
+        sustain_level: 0.7,
+        release: 0.30,
+        gain: 1.0,
+        infinite_sustain: false,
+        random_phase: false,
+        analog_drift: 0.0,
+        transient_amount: 0.0,
+        cutoff: SynthInstrument::MAX_CUTOFF,
+        resonance: SynthInstrument::DEFAULT_RESONANCE,
+        lfo_rate: 5.5,
+        sympathetic_resonance: 0.0,
+        pan: 0.0,
+        compressor: CompressorSettings::OFF,
         overtones: [
             SynthInstrumentOvertone { frequency: 1.00, loudness: 1.0 },
             SynthInstrumentOvertone { frequency: 2.00, loudness: 0.5 },
@@ -23,7 +124,26 @@ impl SynthInstrument {
         ]
     };
     pub const VIBRAPHONE: Self = SynthInstrument {
+        name: "Vibraphone",
+        program: None,
         decay: 0.90,
+        decay_key_tracking: 0.0,
+        inharmonicity: 0.0,
+        attack: 0.002,
+        decay_time: 0.5,
+        sustain_level: 0.8,
+        release: 0.50,
+        gain: 1.0,
+        infinite_sustain: false,
+        random_phase: false,
+        analog_drift: 0.0,
+        transient_amount: 0.0,
+        cutoff: SynthInstrument::MAX_CUTOFF,
+        resonance: SynthInstrument::DEFAULT_RESONANCE,
+        lfo_rate: 5.5,
+        sympathetic_resonance: 0.0,
+        pan: 0.0,
+        compressor: CompressorSettings::OFF,
         overtones: [
             SynthInstrumentOvertone { frequency: 1.00, loudness: 0.8 },
             SynthInstrumentOvertone { frequency: 2.00, loudness: 0.0 },
@@ -33,7 +153,26 @@ impl SynthInstrument {
         ]
     };
     pub const BELL: Self = SynthInstrument {
+        name: "Bell",
+        program: None,
         decay: 0.95,
+        decay_key_tracking: 0.0,
+        inharmonicity: 0.0,
+        attack: 0.001,
+        decay_time: 0.1,
+        sustain_level: 0.9,
+        release: 0.80,
+        gain: 1.0,
+        infinite_sustain: false,
+        random_phase: false,
+        analog_drift: 0.0,
+        transient_amount: 0.0,
+        cutoff: SynthInstrument::MAX_CUTOFF,
+        resonance: SynthInstrument::DEFAULT_RESONANCE,
+        lfo_rate: 5.5,
+        sympathetic_resonance: 0.0,
+        pan: 0.0,
+        compressor: CompressorSettings::OFF,
         overtones: [
             SynthInstrumentOvertone { frequency: 1.0, loudness: 1.0 },
             SynthInstrumentOvertone { frequency: 2.2, loudness: 0.6 },
@@ -42,6 +181,95 @@ impl SynthInstrument {
             SynthInstrumentOvertone { frequency: 5.5, loudness: 0.3 },
         ]
     };
+    pub const ORGAN: Self = SynthInstrument {
+        name: "Organ",
+        program: None,
+        decay: 1.0,
+        decay_key_tracking: 0.0,
+        inharmonicity: 0.0,
+        attack: 0.01,
+        decay_time: 0.0,
+        sustain_level: 1.0,
+        release: 0.05,
+        gain: 1.0,
+        infinite_sustain: true,     // hold the level for as long as the key is held
+        random_phase: false,
+        analog_drift: 0.0,
+        transient_amount: 0.0,
+        cutoff: SynthInstrument::MAX_CUTOFF,
+        resonance: SynthInstrument::DEFAULT_RESONANCE,
+        lfo_rate: 5.5,
+        sympathetic_resonance: 0.0,
+        pan: 0.0,
+        compressor: CompressorSettings::OFF,
+        overtones: [
+            SynthInstrumentOvertone { frequency: 1.00, loudness: 1.0 },
+            SynthInstrumentOvertone { frequency: 2.00, loudness: 0.6 },
+            SynthInstrumentOvertone { frequency: 3.00, loudness: 0.4 },
+            SynthInstrumentOvertone { frequency: 4.00, loudness: 0.3 },
+            SynthInstrumentOvertone { frequency: 5.00, loudness: 0.2 },
+        ]
+    };
+    // A pure sine held while the key is down, to tune other instruments.
+    pub const REFERENCE_TONE: Self = SynthInstrument {
+        name: "Reference",
+        program: None,
+        decay: 1.0,
+        decay_key_tracking: 0.0,
+        inharmonicity: 0.0,
+        attack: 0.01,
+        decay_time: 0.0,
+        sustain_level: 1.0,
+        release: 0.05,
+        gain: 1.0,
+        infinite_sustain: true,
+        random_phase: false,
+        analog_drift: 0.0,
+        transient_amount: 0.0,
+        cutoff: SynthInstrument::MAX_CUTOFF,
+        resonance: SynthInstrument::DEFAULT_RESONANCE,
+        lfo_rate: 5.5,
+        sympathetic_resonance: 0.0,
+        pan: 0.0,
+        compressor: CompressorSettings::OFF,
+        overtones: [
+            SynthInstrumentOvertone { frequency: 1.00, loudness: 1.0 },
+            SynthInstrumentOvertone { frequency: 2.00, loudness: 0.0 },
+            SynthInstrumentOvertone { frequency: 3.00, loudness: 0.0 },
+            SynthInstrumentOvertone { frequency: 4.00, loudness: 0.0 },
+            SynthInstrumentOvertone { frequency: 5.00, loudness: 0.0 },
+        ]
+    };
+
+    // Voices with the same instrument id share a submix.
+    pub fn id(&self) -> InstrumentId {
+        match self.program {
+            Some(program) => InstrumentId::Patch(program),
+            None => InstrumentId::Preset(self.name),
+        }
+    }
+
+    // Keeps the settings that can be changed in the app in their valid ranges.
+    pub fn limit_settings(&mut self) {
+        self.attack = self.attack.max(0.0);
+        self.decay_time = self.decay_time.max(0.0);
+        self.sustain_level = self.sustain_level.clamp(0.0, 1.0);
+        self.cutoff = self.cutoff.clamp(20.0, Self::MAX_CUTOFF);
+        self.resonance = self.resonance.clamp(Self::MIN_RESONANCE, Self::MAX_RESONANCE);
+        self.lfo_rate = self.lfo_rate.clamp(0.1, 20.0);
+        self.pan = self.pan.clamp(-1.0, 1.0);
+    }
+}
+
+// Phase of the ADSR envelope of a voice.  In the release phase the
+// envelope falls from its current level to zero over the release time;
+// it's the only fade applied to a stopped voice.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EnvelopePhase {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
 }
 
 #[derive(Clone, Copy)]
@@ -49,14 +277,37 @@ pub struct SynthVoice {
     pub sample_rate: f32,
     pub num_channels: usize,
     pub active: bool,
-    pub stopping: bool,
+    pub envelope: EnvelopePhase,
+    pub envelope_gain: f32,     // level of the attack/decay/sustain envelope, from 0 to 1
+    pub sustained: bool,    // the key was released, but the sustain pedal holds the note
+    pub release_step: f32,      // fall of the envelope every frame in the release phase
+    pub release_level: f32,     // envelope level when the release started
+    pub ramp_target: f32,
+    pub ramp_delta: f32,
+    pub start_count: u64,   // when the voice was started, used to find the oldest voice
+    pub age: usize,         // frames since the note was struck
+    pub click_smoothing: f32,   // minimum attack and release time in seconds
+    pub max_overtones: usize,   // overtones rendered, the others are skipped to save CPU
+    pub pan: f32,           // added to the pan of the instrument, from -1 (left) to 1 (right)
     pub key: u8,
     pub freq: f32,
     pub volume: f32,
-    pub tick: f32,
+    pub strike_volume: f32,     // volume given by the velocity of the last strike
     pub instrument: SynthInstrument,
     pub log_decay: f32,
     overtones: [(f32, f32); SynthInstrument::NUM_OVERTONES],
+    phases: [f32; SynthInstrument::NUM_OVERTONES],     // in cycles
+    pub pitch_bend: f32,    // detune set by the pitch wheel, in cents
+    bend: f32,              // current pitch wheel detune, gliding to `pitch_bend`
+    pub vibrato_depth: f32, // vibrato set by the modulation wheel, in cents
+    lfo_phase: f32,         // in cycles
+    drift: f32,             // current detune in cents
+    drift_target: f32,
+    transient_pos: usize,   // samples since the start of the transient
+    transient_gain: f32,
+    transient_noise: u32,   // noise generator state
+    transient_filtered: f32,
+    filter_state: [f32; 2],
 }
 
 impl SynthVoice {
@@ -65,45 +316,223 @@ impl SynthVoice {
             num_channels,
             sample_rate,
             active: false,
-            stopping: false,
+            envelope: EnvelopePhase::Sustain,
+            envelope_gain: 0.0,
+            sustained: false,
+            release_step: 0.0,
+            release_level: 0.0,
+            ramp_target: 0.0,
+            ramp_delta: 0.0,
+            start_count: 0,
+            age: 0,
+            click_smoothing: 0.0,
+            max_overtones: SynthInstrument::NUM_OVERTONES,
+            pan: 0.0,
             key: 0,
             freq: 0.0,
             volume: 0.0,
-            tick: 0.0,
+            strike_volume: 0.0,
             log_decay: 0.0,
             instrument: SynthInstrument::PIANO,
             overtones: [(0.0, 0.0); SynthInstrument::NUM_OVERTONES],
+            phases: [0.0; SynthInstrument::NUM_OVERTONES],
+            pitch_bend: 0.0,
+            bend: 0.0,
+            vibrato_depth: 0.0,
+            lfo_phase: 0.0,
+            drift: 0.0,
+            drift_target: 0.0,
+            transient_pos: usize::MAX,
+            transient_gain: 0.0,
+            transient_noise: 1,
+            transient_filtered: 0.0,
+            filter_state: [0.0; 2],
         }
     }
 
-    fn get_midi_note_frequency(note: i32) -> f32 {
+    // Changes the output format.  The voice is silenced, since its ramps
+    // and filter state were computed for the old format, but it keeps
+    // its instrument and settings.
+    pub fn set_output_format(&mut self, num_channels: usize, sample_rate: f32) {
+        self.num_channels = num_channels;
+        self.sample_rate = sample_rate;
+        self.active = false;
+        self.sustained = false;
+        self.release_step = 0.0;
+        self.ramp_delta = 0.0;
+        self.transient_pos = usize::MAX;
+        self.filter_state = [0.0; 2];
+    }
+
+    fn get_midi_note_frequency(note: i32, cents: f32) -> f32 {
         // We use standard A440 with A4 = general midi note 69, so the
-        // formula for the note frequency is:
+        // formula for the note frequency (with the key's tuning offset
+        // in cents) is:
         //
-        //    f_note = 440 * 2^((note - 69) / 12)
+        //    f_note = 440 * 2^((note - 69 + cents/100) / 12)
         //
-        440.0 * 2.0_f32.powf((note - 69) as f32 / 12.0)
+        440.0 * 2.0_f32.powf(((note - 69) as f32 + cents / 100.0) / 12.0)
     }
 
-    pub fn start(&mut self, key: u8, pressure: u8, volume: f32) {
+    pub fn start(&mut self, key: u8, pressure: u8, cents: f32) {
         self.key = key;
         self.active = true;
-        self.stopping = false;
-        self.tick = 0.0;
-        self.volume = pressure as f32 / 127.0 * volume;
-        self.freq = Self::get_midi_note_frequency(key as i32);
+        self.envelope = EnvelopePhase::Attack;
+        self.envelope_gain = 0.0;
+        self.sustained = false;
+        self.age = 0;
+        for phase in self.phases.iter_mut() {
+            *phase = if self.instrument.random_phase { random() } else { 0.0 };
+        }
+        self.drift = (random() * 2.0 - 1.0) * self.instrument.analog_drift;
+        self.drift_target = self.drift;
+        self.bend = self.pitch_bend;
+        self.lfo_phase = 0.0;
+        self.volume = pressure as f32 / 127.0;
+        self.strike_volume = self.volume;
+        self.ramp_delta = 0.0;
+        self.transient_pos = 0;
+        self.transient_gain = self.instrument.transient_amount * VOICE_PEAK_LEVEL * self.volume;
+        if self.click_smoothing > 0.0 {
+            // ramp up from silence instead of starting at full volume
+            self.ramp_target = self.volume;
+            self.ramp_delta = self.volume / (self.click_smoothing * self.sample_rate).max(1.0);
+            self.volume = 0.0;
+        }
+        self.transient_noise = (random() * u32::MAX as f32) as u32 | 1;
+        self.transient_filtered = 0.0;
+        self.filter_state = [0.0; 2];
+        self.freq = Self::get_midi_note_frequency(key as i32, cents);
         self.update_instrument();
     }
 
+    pub fn retrigger(&mut self, pressure: u8) {
+        // keep the phase of the oscillators and quickly ramp to the new
+        // volume, so the new strike doesn't produce a click; the attack
+        // starts from the current envelope level
+        self.envelope = EnvelopePhase::Attack;
+        self.age = 0;
+        self.ramp_target = pressure as f32 / 127.0;
+        self.strike_volume = self.ramp_target;
+        self.ramp_delta = (self.ramp_target - self.volume) / (RETRIGGER_RAMP_TIME * self.sample_rate);
+    }
+
+    // Returns how much the overtones of this voice match the overtones
+    // of another voice, from 0 (no common overtones) to 1.
+    pub fn harmonic_coupling(&self, other: &SynthVoice) -> f32 {
+        let mut coupling = 0.0;
+        for (freq, loudness) in self.overtones.iter() {
+            for (other_freq, other_loudness) in other.overtones.iter() {
+                if *other_freq > 0.0 && (freq / other_freq - 1.0).abs() < RESONANCE_TOLERANCE {
+                    coupling += (loudness * other_loudness).abs();
+                }
+            }
+        }
+        let gain = self.instrument.gain * other.instrument.gain;
+        if gain > 0.0 { (coupling / gain).min(1.0) } else { 0.0 }
+    }
+
+    // Slowly raises the volume of a held voice excited by another note.
+    pub fn resonate(&mut self, amount: f32) {
+        if ! self.active || self.is_stopping() || amount <= 0.0 { return; }
+        let target = (self.volume + amount).min(1.0);
+        if target > self.ramp_target || self.ramp_delta == 0.0 {
+            self.ramp_target = target;
+            self.ramp_delta = (self.ramp_target - self.volume) / (RESONANCE_RAMP_TIME * self.sample_rate);
+        }
+    }
+
+    // Returns true if the voice is in the release phase (its key was
+    // released, or it was stopped to make room for another note).
+    pub fn is_stopping(&self) -> bool {
+        self.envelope == EnvelopePhase::Release
+    }
+
+    // Fades out quickly, over the given time, from the current level.
+    // Used for voices that are stolen to play another note.
+    pub fn fade_out(&mut self, time: f32) {
+        self.stop();
+        self.release_step = self.envelope_gain / (time * self.sample_rate).max(1.0);
+    }
+
+    // Returns the current output level of the voice, including the
+    // volume of the strike.
+    pub fn output_level(&self) -> f32 {
+        if self.active { self.volume * self.envelope_gain } else { 0.0 }
+    }
+
+    // Returns the coefficients (b0, b1, b2, a1, a2, normalized by a0) of
+    // the biquad low-pass filter for the cutoff and resonance of the
+    // instrument.  The cutoff is kept below the Nyquist frequency and
+    // the Q within the resonance range, where the filter is always stable.
+    fn filter_coefficients(&self) -> [f32; 5] {
+        let cutoff = self.instrument.cutoff.clamp(1.0, 0.45 * self.sample_rate);
+        let q = self.instrument.resonance.clamp(SynthInstrument::MIN_RESONANCE, SynthInstrument::MAX_RESONANCE);
+        let w0 = std::f32::consts::TAU * cutoff / self.sample_rate;
+        let (sin_w0, cos_w0) = w0.sin_cos();
+        let alpha = sin_w0 / (2.0 * q);
+        let a0 = 1.0 + alpha;
+        let b1 = (1.0 - cos_w0) / a0;
+        [b1 / 2.0, b1, b1 / 2.0, -2.0 * cos_w0 / a0, (1.0 - alpha) / a0]
+    }
+
     pub fn stop(&mut self) {
-        self.stopping = true;
+        // the envelope falls linearly from its current level over the release time
+        self.envelope = EnvelopePhase::Release;
+        self.ramp_delta = 0.0;
+        self.release_level = self.envelope_gain;
+        self.release_step = self.envelope_gain / (self.release_time() * self.sample_rate).max(1.0);
+    }
+
+    fn release_time(&self) -> f32 {
+        self.instrument.release.max(self.click_smoothing)
+    }
+
+    // Makes the release of a stopping voice longer (or shorter) by the
+    // given factor, starting from its current level.
+    pub fn set_release_scale(&mut self, scale: f32) {
+        if ! self.is_stopping() { return; }
+        self.release_step = self.envelope_gain / (self.release_time() * scale * self.sample_rate).max(1.0);
     }
 
     fn update_instrument(&mut self) {
-        self.log_decay = self.instrument.decay.ln();
+        // with key tracking, lower notes decay slower and higher notes faster
+        let octaves_from_middle_c = (self.key as f32 - 60.0) / 12.0;
+        self.log_decay = self.instrument.decay.ln() * (self.instrument.decay_key_tracking * octaves_from_middle_c).exp2();
+
+        // normalize the overtone loudness so the voice never peaks above the instrument gain
+        let total_loudness = self.instrument.overtones.iter().map(|o| o.loudness.abs()).sum::<f32>();
+        let scale = if total_loudness > 0.0 { self.instrument.gain / total_loudness } else { 0.0 };
+        // the partials of a stiff string are at n*f*sqrt(1 + B*n^2); they
+        // are scaled so the fundamental stays at the frequency of the note
+        let inharmonicity = self.instrument.inharmonicity.max(0.0);
         for (i, overtone) in self.overtones.iter_mut().enumerate() {
-            overtone.0 = self.instrument.overtones[i].frequency * self.freq;
-            overtone.1 = self.instrument.overtones[i].loudness;
+            let ratio = self.instrument.overtones[i].frequency;
+            let stretch = ((1.0 + inharmonicity * ratio * ratio) / (1.0 + inharmonicity)).sqrt();
+            overtone.0 = ratio * stretch * self.freq;
+            overtone.1 = self.instrument.overtones[i].loudness * scale;
+        }
+    }
+
+    // Returns the envelope level, from 1 while the key is held down to
+    // 0 at the end of the release.
+    pub fn envelope_level(&self) -> f32 {
+        if ! self.active {
+            0.0
+        } else if self.is_stopping() && self.release_level > 0.0 {
+            (self.envelope_gain / self.release_level).clamp(0.0, 1.0)
+        } else {
+            1.0
+        }
+    }
+
+    // Returns the current volume relative to the volume of the strike,
+    // from 1 when the note starts to 0 when it's silent.
+    pub fn volume_level(&self) -> f32 {
+        if ! self.active || self.strike_volume <= 0.0 {
+            0.0
+        } else {
+            (self.volume * self.envelope_gain / self.strike_volume).clamp(0.0, 1.0)
         }
     }
 
@@ -112,32 +541,424 @@ impl SynthVoice {
         self.update_instrument();
     }
 
-    pub fn gen_samples(&mut self, data: &mut [i16]) {
+    // Slowly moves the detune towards a random target, picking a new
+    // target when it's reached.
+    fn update_drift(&mut self, num_frames: usize) {
+        let depth = self.instrument.analog_drift;
+        if depth <= 0.0 {
+            self.drift = 0.0;
+            return;
+        }
+        let max_step = 2.0 * depth * num_frames as f32 / (DRIFT_TIME * self.sample_rate);
+        let delta = self.drift_target - self.drift;
+        if delta.abs() <= max_step {
+            self.drift = self.drift_target;
+            self.drift_target = (random() * 2.0 - 1.0) * depth;
+        } else {
+            self.drift += max_step.copysign(delta);
+        }
+    }
+
+    // Adds the voice samples to the (unclamped) mix buffer.
+    pub fn gen_samples(&mut self, data: &mut [f32]) {
         if self.num_channels == 0 { return; }
 
-        let mut t = self.tick;
+        self.age = self.age.saturating_add(data.len() / self.num_channels);
+        self.update_drift(data.len() / self.num_channels);
+        // the pitch glides from the current to the new bend over the buffer
+        let num_frames = data.len() / self.num_channels;
+        let mut phase_scale = ((self.drift + self.bend) / 1200.0).exp2() / self.sample_rate;
+        let glide = (num_frames as f32 / (PITCH_BEND_SMOOTHING_TIME * self.sample_rate)).min(1.0);
+        self.bend += (self.pitch_bend - self.bend) * glide;
+        let end_phase_scale = ((self.drift + self.bend) / 1200.0).exp2() / self.sample_rate;
+        let phase_scale_step = (end_phase_scale - phase_scale) / num_frames.max(1) as f32;
+
+        // the vibrato is only a few cents, so the pitch ratio is close
+        // enough to linear in the detune
+        let vibrato_ratio = self.vibrato_depth * std::f32::consts::LN_2 / 1200.0;
+        let lfo_step = self.instrument.lfo_rate / self.sample_rate;
+
         let mut volume = self.volume;
-        let stopping = self.stopping;
-        let vol_delta = if stopping { -volume / data.len() as f32 } else { 0.0 };
-        let overtones = &self.overtones;
+        let stopping = self.is_stopping();
+        let release_step = self.release_step;
+        let mut ramp_delta = self.ramp_delta;
+        let mut ramp_target = self.ramp_target;
+        // the decay is applied to every frame, so the decay time doesn't
+        // depend on the buffer size or the sample rate
+        let decay = if stopping || self.instrument.infinite_sustain { 1.0 } else { (self.log_decay / (DECAY_TIME * self.sample_rate)).exp() };
+        let transient_len = (TRANSIENT_TIME * self.sample_rate) as usize;
+        let filter = (self.instrument.cutoff < SynthInstrument::MAX_CUTOFF).then(|| self.filter_coefficients());
+        // the pan only attenuates the opposite channel, so the center
+        // keeps the full level in both channels
+        let pan = (self.instrument.pan + self.pan).clamp(-1.0, 1.0);
+        let (left_gain, right_gain) = if self.num_channels >= 2 { ((1.0 - pan).min(1.0), (1.0 + pan).min(1.0)) } else { (1.0, 1.0) };
+        let attack_step = 1.0 / (self.instrument.attack * self.sample_rate).max(1.0);
+        let sustain_level = self.instrument.sustain_level.clamp(0.0, 1.0);
+        let decay_step = (1.0 - sustain_level) / (self.instrument.decay_time * self.sample_rate).max(1.0);
+        let mut envelope = self.envelope;
+        let mut envelope_gain = self.envelope_gain;
+        let overtones = &self.overtones[..self.max_overtones.min(SynthInstrument::NUM_OVERTONES)];
+        let phases = &mut self.phases;
         for spl in data.chunks_exact_mut(self.num_channels) {
+            match envelope {
+                EnvelopePhase::Attack => {
+                    envelope_gain += attack_step;
+                    if envelope_gain >= 1.0 {
+                        envelope_gain = 1.0;
+                        envelope = EnvelopePhase::Decay;
+                    }
+                }
+                EnvelopePhase::Decay => {
+                    envelope_gain -= decay_step;
+                    if envelope_gain <= sustain_level {
+                        envelope_gain = sustain_level;
+                        envelope = EnvelopePhase::Sustain;
+                    }
+                }
+                EnvelopePhase::Release => {
+                    envelope_gain = (envelope_gain - release_step).max(0.0);
+                }
+                EnvelopePhase::Sustain => {}
+            }
+            let gain = VOICE_PEAK_LEVEL * volume * envelope_gain;
             let mut val = 0.0;
-            for (freq, mult) in overtones {
-                val += (t * std::f32::consts::TAU / self.sample_rate * freq).sin() * mult * 3000.0 * volume;
+            let mut frame_phase_scale = phase_scale;
+            if vibrato_ratio > 0.0 {
+                frame_phase_scale *= 1.0 + vibrato_ratio * (self.lfo_phase * std::f32::consts::TAU).sin();
+                self.lfo_phase = (self.lfo_phase + lfo_step).fract();
             }
-            let ival = val.clamp(i16::MIN as f32, i16::MAX as f32).round() as i16;
-            for s in spl.iter_mut().take(self.num_channels) {
-                *s = (*s).saturating_add(ival);
+            for ((freq, mult), phase) in overtones.iter().zip(phases.iter_mut()) {
+                val += (*phase * std::f32::consts::TAU).sin() * mult * gain;
+                *phase = (*phase + freq * frame_phase_scale).fract();
+            }
+            phase_scale += phase_scale_step;
+            if self.transient_pos < transient_len && self.transient_gain > 0.0 {
+                // low-passed noise, fading out linearly
+                self.transient_noise = xorshift(self.transient_noise);
+                let noise = (self.transient_noise >> 8) as f32 / (1 << 23) as f32 - 1.0;
+                self.transient_filtered += (noise - self.transient_filtered) * TRANSIENT_FILTER;
+                let env = 1.0 - self.transient_pos as f32 / transient_len as f32;
+                val += self.transient_filtered * env * self.transient_gain;
+                self.transient_pos += 1;
+            }
+            if let Some([b0, b1, b2, a1, a2]) = filter {
+                // transposed direct form II
+                let out = b0 * val + self.filter_state[0];
+                self.filter_state[0] = b1 * val - a1 * out + self.filter_state[1];
+                self.filter_state[1] = b2 * val - a2 * out;
+                val = out;
+            }
+            for (channel, s) in spl.iter_mut().enumerate() {
+                *s += match channel {
+                    0 => val * left_gain,
+                    1 => val * right_gain,
+                    _ => val,
+                };
+            }
+            volume *= decay;
+            ramp_target *= decay;
+            if ramp_delta != 0.0 {
+                volume += ramp_delta;
+                if (ramp_delta > 0.0 && volume >= ramp_target) || (ramp_delta < 0.0 && volume <= ramp_target) {
+                    volume = ramp_target;
+                    ramp_delta = 0.0;
+                }
             }
-            t += 1.0;
-            volume += vol_delta;
         }
-        self.tick = t;
-        if stopping {
+        self.volume = volume;
+        self.ramp_target = ramp_target;
+        self.ramp_delta = ramp_delta;
+        self.envelope = envelope;
+        self.envelope_gain = envelope_gain;
+        if stopping && envelope_gain <= 0.0 {
             self.active = false;
-        } else {
-            //self.volume *= self.instrument.decay;
-            self.volume *= (data.len() as f32 / 2048.0 * self.log_decay).exp();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48000.0;
+    const PRESETS: [SynthInstrument; 4] = [
+        SynthInstrument::PIANO,
+        SynthInstrument::VIBRAPHONE,
+        SynthInstrument::BELL,
+        SynthInstrument::ORGAN,
+    ];
+
+    // Renders `num_frames` frames of a note in buffers of `buffer_len`
+    // frames.
+    fn render(voice: &mut SynthVoice, num_frames: usize, buffer_len: usize) -> Vec<f32> {
+        let mut out = Vec::with_capacity(num_frames * voice.num_channels);
+        while out.len() < num_frames * voice.num_channels {
+            let frames = buffer_len.min(num_frames - out.len() / voice.num_channels);
+            let mut buffer = vec![0.0; frames * voice.num_channels];
+            voice.gen_samples(&mut buffer);
+            out.extend_from_slice(&buffer);
+        }
+        out
+    }
+
+    fn start_voice(instrument: SynthInstrument, num_channels: usize, key: u8, velocity: u8) -> SynthVoice {
+        let mut voice = SynthVoice::new(num_channels, SAMPLE_RATE);
+        voice.set_instrument(instrument);
+        voice.start(key, velocity, 0.0);
+        voice
+    }
+
+    // Returns the magnitude spectrum of the samples with a Hann window,
+    // from 0 Hz to the Nyquist frequency.  A sine of amplitude 1 peaks at
+    // about 0.25.  The number of samples must be a power of two.
+    fn spectrum(samples: &[f32]) -> Vec<f64> {
+        use std::f64::consts::TAU;
+        let len = samples.len();
+        assert!(len.is_power_of_two());
+        let bits = len.trailing_zeros();
+        let mut data = vec![(0.0, 0.0); len];
+        for (i, &spl) in samples.iter().enumerate() {
+            let window = 0.5 - 0.5 * (TAU * i as f64 / len as f64).cos();
+            data[i.reverse_bits() >> (usize::BITS - bits)] = (spl as f64 * window, 0.0);
+        }
+        let mut size = 2;
+        while size <= len {
+            let half = size / 2;
+            for start in (0..len).step_by(size) {
+                for k in 0..half {
+                    let (wr, wi) = ((TAU * k as f64 / size as f64).cos(), -(TAU * k as f64 / size as f64).sin());
+                    let (ar, ai) = data[start + k];
+                    let (br, bi) = data[start + k + half];
+                    let (tr, ti) = (br * wr - bi * wi, br * wi + bi * wr);
+                    data[start + k] = (ar + tr, ai + ti);
+                    data[start + k + half] = (ar - tr, ai - ti);
+                }
+            }
+            size *= 2;
+        }
+        data[..len / 2].iter().map(|(re, im)| (re * re + im * im).sqrt() / len as f64).collect()
+    }
+
+    // Returns the frequency (interpolated between bins) and magnitude of
+    // the highest bin of the spectrum within `width` (as a fraction of
+    // the frequency) of `freq`.
+    fn peak_near(spectrum: &[f64], freq: f64, width: f64) -> (f64, f64) {
+        let bin_width = SAMPLE_RATE as f64 / (2 * spectrum.len()) as f64;
+        let low = ((freq * (1.0 - width) / bin_width) as usize).max(1);
+        let high = ((freq * (1.0 + width) / bin_width) as usize).min(spectrum.len() - 2);
+        let bin = (low..=high).max_by(|&a, &b| spectrum[a].total_cmp(&spectrum[b])).unwrap();
+        let (a, b, c) = (spectrum[bin - 1], spectrum[bin], spectrum[bin + 1]);
+        let offset = if a + c < 2.0 * b { 0.5 * (a - c) / (a - 2.0 * b + c) } else { 0.0 };
+        ((bin as f64 + offset) * bin_width, b)
+    }
+
+    // Expected peaks of a sustained middle C (261.63 Hz) on each preset:
+    // the frequency in Hz and the loudness relative to the fundamental.
+    // The piano partials are stretched by its inharmonicity.
+    const EXPECTED_PEAKS: [(SynthInstrument, [(f64, f64); SynthInstrument::NUM_OVERTONES]); 5] = [
+        (SynthInstrument::PIANO, [(261.63, 1.0), (523.56, 0.5), (786.13, 0.8), (1049.64, 0.1), (1314.39, 0.3)]),
+        (SynthInstrument::VIBRAPHONE, [(261.63, 1.0), (523.25, 0.0), (784.88, 0.0), (1046.50, 1.0), (1308.13, 0.0)]),
+        (SynthInstrument::BELL, [(261.63, 1.0), (575.58, 0.6), (863.36, 0.9), (1151.15, 0.1), (1438.94, 0.3)]),
+        (SynthInstrument::ORGAN, [(261.63, 1.0), (523.25, 0.6), (784.88, 0.4), (1046.50, 0.3), (1308.13, 0.2)]),
+        (SynthInstrument::REFERENCE_TONE, [(261.63, 1.0), (523.25, 0.0), (784.88, 0.0), (1046.50, 0.0), (1308.13, 0.0)]),
+    ];
+
+    #[test]
+    fn preset_spectral_peaks_match_overtones() {
+        // relative loudness tolerance, frequency tolerance (in Hz) and how
+        // far (as a fraction of the frequency) a peak is searched
+        const LOUDNESS_TOLERANCE: f64 = 0.05;
+        const FREQ_TOLERANCE: f64 = 0.5;
+        const PEAK_WIDTH: f64 = 0.02;
+        const FFT_LEN: usize = 1 << 14;
+        for (instrument, peaks) in EXPECTED_PEAKS {
+            let name = instrument.name;
+            let mut voice = start_voice(instrument, 1, 60, 100);
+            // skip the attack and decay of the envelope
+            let skip = SAMPLE_RATE as usize / 2;
+            let samples = render(&mut voice, skip + FFT_LEN, 512);
+            let spectrum = spectrum(&samples[skip..]);
+            let (_, fundamental) = peak_near(&spectrum, peaks[0].0, PEAK_WIDTH);
+            assert!(fundamental > 0.0, "{}: no fundamental", name);
+            for (freq, loudness) in peaks {
+                let (peak_freq, amplitude) = peak_near(&spectrum, freq, PEAK_WIDTH);
+                let relative = amplitude / fundamental;
+                assert!((relative - loudness).abs() < LOUDNESS_TOLERANCE,
+                        "{}: overtone at {} Hz has relative loudness {:.3}, expected {}", name, freq, relative, loudness);
+                if loudness > 0.0 {
+                    assert!((peak_freq - freq).abs() < FREQ_TOLERANCE, "{}: peak at {:.2} Hz, expected {} Hz", name, peak_freq, freq);
+                }
+            }
+
+            // there are no other peaks
+            for bin in 1..spectrum.len() - 1 {
+                let is_peak = spectrum[bin] > spectrum[bin - 1] && spectrum[bin] >= spectrum[bin + 1];
+                if is_peak && spectrum[bin] > LOUDNESS_TOLERANCE * fundamental {
+                    let freq = bin as f64 * SAMPLE_RATE as f64 / FFT_LEN as f64;
+                    assert!(peaks.iter().any(|&(f, l)| l > 0.0 && (freq - f).abs() < f * PEAK_WIDTH),
+                            "{}: unexpected peak at {:.1} Hz", name, freq);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn max_velocity_note_leaves_headroom() {
+        let max_peak = 10.0_f32.powf(-6.0 / 20.0);
+        for instrument in PRESETS {
+            for key in [21, 60, 108] {
+                let mut voice = start_voice(instrument, 1, key, 127);
+                let samples = render(&mut voice, SAMPLE_RATE as usize, 512);
+                let peak = samples.iter().fold(0.0_f32, |peak, s| peak.max(s.abs()));
+                assert!(peak < max_peak, "{} key {} peaks at {:.1} dBFS", instrument.name, key, 20.0 * peak.log10());
+            }
+        }
+    }
+
+    #[test]
+    fn stereo_voice_matches_mono_voice() {
+        let num_frames = SAMPLE_RATE as usize / 2;
+        let mut mono_voice = start_voice(SynthInstrument::PIANO, 1, 60, 100);
+        let mut stereo_voice = start_voice(SynthInstrument::PIANO, 2, 60, 100);
+        let mono = render(&mut mono_voice, num_frames, 512);
+        let stereo = render(&mut stereo_voice, num_frames, 512);
+        assert_eq!(stereo.len(), 2 * mono.len());
+        for (frame, (mono, stereo)) in mono.iter().zip(stereo.chunks_exact(2)).enumerate() {
+            assert_eq!(stereo[0], stereo[1], "channels differ at frame {}", frame);
+            assert!((stereo[0] - mono).abs() < 1e-6, "stereo differs from mono at frame {}", frame);
+        }
+        assert!((stereo_voice.volume - mono_voice.volume).abs() < 1e-6);
+    }
+
+    #[test]
+    fn piano_partials_are_stretched() {
+        // n*f*sqrt(1 + B*n^2), scaled so the fundamental stays at f
+        let voice = start_voice(SynthInstrument::PIANO, 1, 60, 100);
+        let expected = [261.63, 523.56, 786.13, 1049.64, 1314.39];
+        for ((freq, _), expected) in voice.overtones.iter().zip(expected) {
+            assert!((freq - expected).abs() < 0.02, "partial at {} Hz, expected {} Hz", freq, expected);
+        }
+
+        // without inharmonicity the partials are exact multiples
+        let voice = start_voice(SynthInstrument { inharmonicity: 0.0, ..SynthInstrument::PIANO }, 1, 60, 100);
+        for (n, (freq, _)) in voice.overtones.iter().enumerate() {
+            assert!((freq - (n + 1) as f32 * voice.freq).abs() < 0.01, "partial at {} Hz", freq);
+        }
+    }
+
+    #[test]
+    fn decay_does_not_depend_on_buffer_length() {
+        let num_frames = SAMPLE_RATE as usize;
+        let mut short_voice = start_voice(SynthInstrument::PIANO, 1, 60, 100);
+        let mut long_voice = start_voice(SynthInstrument::PIANO, 1, 60, 100);
+        let short = render(&mut short_voice, num_frames, 64);
+        let long = render(&mut long_voice, num_frames, 3000);
+        assert!((short_voice.volume - long_voice.volume).abs() < 1e-4);
+        for (frame, (a, b)) in short.iter().zip(long.iter()).enumerate().step_by(1000) {
+            assert!((a - b).abs() < 1e-4, "envelopes differ at frame {}", frame);
+        }
+    }
+
+    #[test]
+    fn decay_does_not_depend_on_sample_rate() {
+        let mut voice_48k = start_voice(SynthInstrument::PIANO, 1, 60, 100);
+        let mut voice_96k = SynthVoice::new(1, 2.0 * SAMPLE_RATE);
+        voice_96k.set_instrument(SynthInstrument::PIANO);
+        voice_96k.start(60, 100, 0.0);
+        render(&mut voice_48k, SAMPLE_RATE as usize, 512);
+        render(&mut voice_96k, 2 * SAMPLE_RATE as usize, 512);
+        assert!(voice_48k.volume < 0.5 * 100.0 / 127.0);
+        assert!((voice_48k.volume - voice_96k.volume).abs() < 1e-3 * voice_48k.volume.max(1e-3),
+                "{} at 48 kHz, {} at 96 kHz", voice_48k.volume, voice_96k.volume);
+    }
+
+    #[test]
+    fn envelope_stages_follow_their_times() {
+        let instrument = SynthInstrument {
+            attack: 0.01,
+            decay_time: 0.02,
+            sustain_level: 0.5,
+            release: 0.05,
+            infinite_sustain: true,
+            ..SynthInstrument::REFERENCE_TONE
+        };
+        let mut voice = start_voice(instrument, 1, 60, 127);
+        let volume = voice.volume;
+
+        // attack: from 0 to 1 in 10 ms
+        render(&mut voice, 240, 64);
+        assert_eq!(voice.envelope, EnvelopePhase::Attack);
+        assert!((voice.envelope_gain - 0.5).abs() < 0.01, "{} halfway through the attack", voice.envelope_gain);
+        render(&mut voice, 240, 64);
+        assert_eq!(voice.envelope, EnvelopePhase::Decay);
+
+        // decay: from 1 to the sustain level in 20 ms
+        render(&mut voice, 480, 64);
+        assert!((voice.envelope_gain - 0.75).abs() < 0.01, "{} halfway through the decay", voice.envelope_gain);
+        render(&mut voice, 484, 64);     // a few frames for rounding errors
+        assert_eq!(voice.envelope, EnvelopePhase::Sustain);
+        assert_eq!(voice.envelope_gain, 0.5);
+
+        // sustain: the level holds while the key is down
+        render(&mut voice, 4800, 64);
+        assert_eq!(voice.envelope, EnvelopePhase::Sustain);
+        assert_eq!(voice.envelope_gain, 0.5);
+
+        // release: from the sustain level to 0 in 50 ms, with no other fade
+        voice.stop();
+        render(&mut voice, 1200, 64);
+        assert_eq!(voice.envelope, EnvelopePhase::Release);
+        assert!((voice.envelope_gain - 0.25).abs() < 0.01, "{} halfway through the release", voice.envelope_gain);
+        assert_eq!(voice.volume, volume);
+        render(&mut voice, 1200, 64);
+        assert!(! voice.active);
+        assert_eq!(voice.envelope_gain, 0.0);
+    }
+
+    #[test]
+    fn filter_sweep_at_max_resonance_stays_bounded() {
+        let instrument = SynthInstrument {
+            infinite_sustain: true,
+            resonance: SynthInstrument::MAX_RESONANCE,
+            overtones: [
+                SynthInstrumentOvertone { frequency: 1.0, loudness: 1.0 },
+                SynthInstrumentOvertone { frequency: 2.0, loudness: 0.5 },
+                SynthInstrumentOvertone { frequency: 3.0, loudness: 0.33 },
+                SynthInstrumentOvertone { frequency: 4.0, loudness: 0.25 },
+                SynthInstrumentOvertone { frequency: 5.0, loudness: 0.2 },
+            ],
+            ..SynthInstrument::REFERENCE_TONE
+        };
+        let mut voice = start_voice(instrument, 1, 36, 127);
+
+        // sweep the cutoff from 20 Hz to just below 20 kHz (where the
+        // filter is bypassed) and back, changing it every 64 frames
+        let steps = 300;
+        let mut peak = 0.0f32;
+        for i in (0..steps).chain((0..steps).rev()) {
+            voice.instrument.cutoff = 20.0 * (999.9f32).powf(i as f32 / (steps - 1) as f32);
+            let out = render(&mut voice, 64, 64);
+            assert!(out.iter().all(|spl| spl.is_finite()));
+            peak = out.iter().fold(peak, |peak, spl| peak.max(spl.abs()));
+        }
+        assert!(peak < SynthInstrument::MAX_RESONANCE * VOICE_PEAK_LEVEL, "peak {}", peak);
+    }
+
+    #[test]
+    fn infinite_sustain_holds_a_constant_level() {
+        // without the flag this decay would halve the level in about 140 ms
+        let instrument = SynthInstrument { decay: 0.9, infinite_sustain: true, ..SynthInstrument::REFERENCE_TONE };
+        let mut voice = start_voice(instrument, 1, 60, 100);
+        render(&mut voice, 4800, 256);      // past the attack and decay
+        let level = voice.output_level();
+        assert!(level > 0.0);
+
+        // the peak of every 100 ms (many periods of the note) stays the same
+        let peak = |samples: &[f32]| samples.iter().fold(0.0f32, |peak, spl| peak.max(spl.abs()));
+        let first_peak = peak(&render(&mut voice, 4800, 256));
+        for _ in 0..50 {
+            assert!((peak(&render(&mut voice, 4800, 256)) - first_peak).abs() < 1e-3 * first_peak);
+        }
+        assert_eq!(voice.output_level(), level);
+    }
+}
@@ -0,0 +1,48 @@
+use std::result::Result;
+use std::error::Error;
+
+use super::synth::SynthPlayer;
+
+// Offset in cents for each key, applied on top of equal temperament.
+pub type TuningTable = [f32; SynthPlayer::NUM_KEYS];
+
+pub const EQUAL_TEMPERAMENT: TuningTable = [0.0; SynthPlayer::NUM_KEYS];
+
+// Approximation of the Railsback curve: the bass is tuned flat and the
+// treble sharp, increasingly so towards the extremes of the keyboard.
+pub fn stretch_tuning() -> TuningTable {
+    let mut table = EQUAL_TEMPERAMENT;
+    for (key, cents) in table.iter_mut().enumerate() {
+        let distance = (key as f32 - 60.0) / 12.0;
+        *cents = 0.8 * distance.powi(3) + distance;
+    }
+    table
+}
+
+// Reads a tuning file.  Each non-empty line has a MIDI key number and
+// an offset in cents, separated by whitespace; everything after a `#`
+// is ignored.  Keys not listed in the file keep a zero offset.  Example:
+//
+//    # key  cents
+//    21     -12.5
+//    84     +8.0
+//
+pub fn load_tuning_file(path: &str) -> Result<TuningTable, Box<dyn Error>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut table = EQUAL_TEMPERAMENT;
+    for (line_num, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() { continue; }
+        let mut fields = line.split_whitespace();
+        let (Some(key), Some(cents), None) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(format!("line {}: expected key and cents", line_num + 1).into());
+        };
+        let key = key.parse::<usize>().map_err(|e| format!("line {}: invalid key: {}", line_num + 1, e))?;
+        let cents = cents.parse::<f32>().map_err(|e| format!("line {}: invalid cents: {}", line_num + 1, e))?;
+        let Some(entry) = table.get_mut(key) else {
+            return Err(format!("line {}: key {} is out of range", line_num + 1, key).into());
+        };
+        *entry = cents;
+    }
+    Ok(table)
+}
@@ -0,0 +1,214 @@
+use std::result::Result;
+use std::error::Error;
+use std::io::Write;
+use std::sync::mpsc;
+use std::thread;
+
+// Number of samples in each block sent to the recording thread.
+const RECORDING_BLOCK_LEN: usize = 4096;
+
+// Number of blocks that can wait for the recording thread (about 4
+// seconds of stereo at 48 kHz).
+const RECORDING_QUEUE_LEN: usize = 96;
+
+// Largest number of samples that fit in a WAV file, whose sizes are
+// 32-bit and include the 36 bytes of header after the size field.
+const MAX_WAV_SAMPLES: usize = ((u32::MAX - 36) / 2) as usize;
+
+// Audio captured from the synth output, as interleaved 16-bit samples.
+pub struct Recording {
+    pub samples: Vec<i16>,
+    pub num_channels: usize,
+    pub sample_rate: u32,
+    pub truncated: bool,    // the recording was stopped at the WAV size limit
+    pub lost_samples: bool, // the recording thread fell behind and some audio was dropped
+}
+
+impl Recording {
+    // Scales the samples so the peak is at the given level (in dBFS).
+    pub fn normalize(&mut self, peak_db: f32) {
+        let peak = self.samples.iter().map(|s| s.unsigned_abs()).max().unwrap_or(0);
+        if peak == 0 { return; }
+        let gain = 10.0_f32.powf(peak_db / 20.0) * i16::MAX as f32 / peak as f32;
+        for spl in self.samples.iter_mut() {
+            *spl = (*spl as f32 * gain).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+    }
+
+    // Writes the recording as a 16-bit PCM WAV file.
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let num_channels = self.num_channels as u16;
+        let block_align = num_channels * 2;
+        let data_len = u32::try_from(self.samples.len() * 2).map_err(|_| "recording too long for a WAV file")?;
+        let riff_len = data_len.checked_add(36).ok_or("recording too long for a WAV file")?;
+
+        let mut data = Vec::with_capacity(44 + data_len as usize);
+        data.extend_from_slice(b"RIFF");
+        data.extend_from_slice(&riff_len.to_le_bytes());
+        data.extend_from_slice(b"WAVE");
+        data.extend_from_slice(b"fmt ");
+        data.extend_from_slice(&16u32.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());      // PCM
+        data.extend_from_slice(&num_channels.to_le_bytes());
+        data.extend_from_slice(&self.sample_rate.to_le_bytes());
+        data.extend_from_slice(&(self.sample_rate * block_align as u32).to_le_bytes());
+        data.extend_from_slice(&block_align.to_le_bytes());
+        data.extend_from_slice(&16u16.to_le_bytes());     // bits per sample
+        data.extend_from_slice(b"data");
+        data.extend_from_slice(&data_len.to_le_bytes());
+        for spl in self.samples.iter() {
+            data.extend_from_slice(&spl.to_le_bytes());
+        }
+
+        let mut file = std::fs::File::create(path)?;
+        file.write_all(&data)?;
+        Ok(())
+    }
+}
+
+struct RecordingBlock {
+    samples: [i16; RECORDING_BLOCK_LEN],
+    len: usize,
+}
+
+// Records the synth output.  The samples are collected by their own
+// thread: the audio callback only copies them into blocks sent over a
+// bounded channel, whose space is allocated when the recording starts,
+// so recording never allocates in the audio callback.  The recording
+// stops at the size limit of a WAV file.
+pub struct Recorder {
+    sender: mpsc::SyncSender<RecordingBlock>,
+    collector: thread::JoinHandle<(Vec<i16>, bool)>,
+    block_len: usize,       // samples sent in each block, a whole number of frames
+    num_channels: usize,
+    sample_rate: u32,
+    lost_samples: bool,
+}
+
+impl Recorder {
+    pub fn start(num_channels: usize, sample_rate: u32) -> Self {
+        Self::with_limit(num_channels, sample_rate, MAX_WAV_SAMPLES)
+    }
+
+    fn with_limit(num_channels: usize, sample_rate: u32, max_samples: usize) -> Self {
+        let frame_len = num_channels.max(1);
+        let max_samples = max_samples - max_samples % frame_len;
+        let (sender, receiver) = mpsc::sync_channel::<RecordingBlock>(RECORDING_QUEUE_LEN);
+        let collector = thread::spawn(move || {
+            let mut samples = Vec::new();
+            for block in receiver.iter() {
+                let len = block.len.min(max_samples - samples.len());
+                samples.extend_from_slice(&block.samples[..len]);
+                if len < block.len {
+                    // dropping the receiver tells the audio callback to stop sending
+                    return (samples, true);
+                }
+            }
+            (samples, false)
+        });
+        Recorder {
+            sender,
+            collector,
+            block_len: RECORDING_BLOCK_LEN - RECORDING_BLOCK_LEN % frame_len,
+            num_channels,
+            sample_rate,
+            lost_samples: false,
+        }
+    }
+
+    // Records interleaved samples; called from the audio callback.
+    pub fn write(&mut self, data: &[i16]) {
+        for chunk in data.chunks(self.block_len) {
+            let mut block = RecordingBlock { samples: [0; RECORDING_BLOCK_LEN], len: chunk.len() };
+            block.samples[..chunk.len()].copy_from_slice(chunk);
+            match self.sender.try_send(block) {
+                Ok(()) => {}
+                Err(mpsc::TrySendError::Full(_)) => { self.lost_samples = true; }
+                Err(mpsc::TrySendError::Disconnected(_)) => { return; }
+            }
+        }
+    }
+
+    // Stops the recording and returns everything recorded.
+    pub fn finish(self) -> Recording {
+        drop(self.sender);
+        let (samples, truncated) = self.collector.join().unwrap_or_default();
+        Recording {
+            samples,
+            num_channels: self.num_channels,
+            sample_rate: self.sample_rate,
+            truncated,
+            lost_samples: self.lost_samples,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recording(samples: Vec<i16>) -> Recording {
+        Recording { samples, num_channels: 2, sample_rate: 48000, truncated: false, lost_samples: false }
+    }
+
+    #[test]
+    fn header_describes_the_samples() {
+        let path = std::env::temp_dir().join(format!("key-synth-test-{}.wav", std::process::id()));
+        let path = path.to_str().unwrap();
+        recording(vec![1, -1, 0x1234, -0x1234]).save(path).unwrap();
+        let data = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let u16_at = |pos: usize| u16::from_le_bytes([data[pos], data[pos + 1]]);
+        let u32_at = |pos: usize| u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]);
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32_at(4), 36 + 8);
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(16), 16);
+        assert_eq!(u16_at(20), 1);              // PCM
+        assert_eq!(u16_at(22), 2);              // channels
+        assert_eq!(u32_at(24), 48000);          // sample rate
+        assert_eq!(u32_at(28), 48000 * 4);      // bytes per second
+        assert_eq!(u16_at(32), 4);              // block align
+        assert_eq!(u16_at(34), 16);             // bits per sample
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(u32_at(40), 8);
+        assert_eq!(&data[44..], &[1, 0, 0xff, 0xff, 0x34, 0x12, 0xcc, 0xed]);
+    }
+
+    #[test]
+    fn normalize_scales_the_peak() {
+        let mut rec = recording(vec![100, -200, 50, 0]);
+        rec.normalize(0.0);
+        assert_eq!(rec.samples, [16384, -32767, 8192, 0]);
+
+        // -6 dBFS is about half of full scale
+        let mut rec = recording(vec![100, -200, 50, 0]);
+        rec.normalize(-6.0);
+        assert_eq!(rec.samples[1], (-32767.0 * 10.0_f32.powf(-6.0 / 20.0)).round() as i16);
+
+        // silence is left alone
+        let mut rec = recording(vec![0; 4]);
+        rec.normalize(0.0);
+        assert_eq!(rec.samples, [0; 4]);
+    }
+
+    #[test]
+    fn recorder_collects_blocks_and_stops_at_the_limit() {
+        let mut recorder = Recorder::start(2, 48000);
+        let data = (0..3 * RECORDING_BLOCK_LEN as i16 / 2).collect::<Vec<_>>();
+        recorder.write(&data);
+        let rec = recorder.finish();
+        assert_eq!(rec.samples, data);
+        assert!(! rec.truncated);
+
+        // the limit is rounded down to whole frames
+        let mut recorder = Recorder::with_limit(2, 48000, 11);
+        recorder.write(&data[..8]);
+        recorder.write(&data[8..16]);
+        let rec = recorder.finish();
+        assert_eq!(rec.samples, &data[..10]);
+        assert!(rec.truncated);
+    }
+}